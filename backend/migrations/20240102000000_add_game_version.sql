@@ -0,0 +1,2 @@
+-- Bumped on every state change; writers compare-and-swap on it to detect concurrent updates.
+ALTER TABLE games ADD COLUMN version BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1,22 @@
+CREATE TABLE users (
+    id            UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    username      TEXT NOT NULL,
+    password_hash TEXT NOT NULL,
+    created_at    TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+-- Usernames are unique regardless of case.
+CREATE UNIQUE INDEX users_username_idx ON users (LOWER(username));
+
+-- Only a SHA-256 hash of each session token is stored.
+CREATE TABLE sessions (
+    token_hash  TEXT PRIMARY KEY,
+    user_id     UUID NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    created_at  TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    expires_at  TIMESTAMPTZ NOT NULL
+);
+CREATE INDEX sessions_user_id_idx ON sessions (user_id);
+
+-- Seats taken by signed-in players; guests are identified by the seat secret alone.
+ALTER TABLE games
+    ADD COLUMN white_user_id UUID REFERENCES users (id),
+    ADD COLUMN black_user_id UUID REFERENCES users (id);
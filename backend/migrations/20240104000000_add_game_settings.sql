@@ -0,0 +1,17 @@
+CREATE TYPE variant AS ENUM ('standard');
+CREATE TYPE time_control AS ENUM ('bullet','blitz','rapid','classical','correspondence');
+
+-- `time_control` is derived from the clock when the game is created; no clock
+-- means correspondence.
+ALTER TABLE games
+    ADD COLUMN variant              variant NOT NULL DEFAULT 'standard',
+    ADD COLUMN time_control         time_control NOT NULL DEFAULT 'correspondence',
+    ADD COLUMN clock_initial_secs   INTEGER,
+    ADD COLUMN clock_increment_secs INTEGER;
+
+-- A player's history is read newest first, from whichever seat they had.
+CREATE INDEX games_white_user_id_idx ON games (white_user_id, created_at DESC)
+    WHERE white_user_id IS NOT NULL;
+CREATE INDEX games_black_user_id_idx ON games (black_user_id, created_at DESC)
+    WHERE black_user_id IS NOT NULL;
+CREATE INDEX games_created_at_idx ON games (created_at DESC);
@@ -0,0 +1,22 @@
+-- Glicko-2 state per player, variant and time control. Rows are created with
+-- the defaults when a player finishes their first rated game in a category.
+CREATE TABLE ratings (
+    user_id      UUID NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    variant      variant NOT NULL,
+    time_control time_control NOT NULL,
+    rating       DOUBLE PRECISION NOT NULL,
+    deviation    DOUBLE PRECISION NOT NULL,
+    volatility   DOUBLE PRECISION NOT NULL,
+    games        INTEGER NOT NULL DEFAULT 0,
+    updated_at   TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_id, variant, time_control)
+);
+
+-- The rating columns are rounded and filled in, in the same transaction that
+-- finishes a rated game.
+ALTER TABLE games
+    ADD COLUMN rated               BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN white_rating_before INTEGER,
+    ADD COLUMN white_rating_after  INTEGER,
+    ADD COLUMN black_rating_before INTEGER,
+    ADD COLUMN black_rating_after  INTEGER;
@@ -0,0 +1,3 @@
+-- Set when a game starts from a custom position; moves are replayed from it.
+-- NULL means the standard starting position.
+ALTER TABLE games ADD COLUMN initial_fen TEXT;
@@ -0,0 +1,5 @@
+-- Rematches form a chain: each game points back to the one it followed, and
+-- forward to its rematch. A game can have only one rematch.
+ALTER TABLE games
+    ADD COLUMN rematch_of UUID REFERENCES games (id),
+    ADD COLUMN rematch_id UUID REFERENCES games (id);
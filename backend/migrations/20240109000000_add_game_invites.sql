@@ -0,0 +1,9 @@
+-- Private games are left out of the public listing, and their open seat can
+-- only be taken with the invite token, which may expire or be single-use.
+-- The seat can also be kept for one player.
+ALTER TABLE games
+    ADD COLUMN private BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN invite_token UUID,
+    ADD COLUMN invite_expires_at TIMESTAMPTZ,
+    ADD COLUMN invite_single_use BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN invited_user_id UUID REFERENCES users (id);
@@ -0,0 +1,17 @@
+-- SQLite schema. UUIDs and timestamps are generated by the server and stored as
+-- text, and moves are kept as a JSON array.
+CREATE TABLE games (
+    id           TEXT PRIMARY KEY,
+    white_secret TEXT NOT NULL,
+    black_secret TEXT,
+    fen          TEXT NOT NULL DEFAULT 'rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1',
+    moves        TEXT NOT NULL DEFAULT '[]',
+    status       TEXT NOT NULL DEFAULT 'waiting'
+                 CHECK (status IN ('waiting','active','checkmate','stalemate','draw','resigned')),
+    result       TEXT,
+    version      INTEGER NOT NULL DEFAULT 0,
+    created_at   TEXT NOT NULL,
+    updated_at   TEXT NOT NULL
+);
+
+CREATE INDEX games_created_at_idx ON games (created_at);
@@ -0,0 +1,20 @@
+CREATE TABLE users (
+    id            TEXT PRIMARY KEY,
+    -- Usernames are unique regardless of case.
+    username      TEXT NOT NULL UNIQUE COLLATE NOCASE,
+    password_hash TEXT NOT NULL,
+    created_at    TEXT NOT NULL
+);
+
+-- Only a SHA-256 hash of each session token is stored.
+CREATE TABLE sessions (
+    token_hash  TEXT PRIMARY KEY,
+    user_id     TEXT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    created_at  TEXT NOT NULL,
+    expires_at  TEXT NOT NULL
+);
+CREATE INDEX sessions_user_id_idx ON sessions (user_id);
+
+-- Seats taken by signed-in players; guests are identified by the seat secret alone.
+ALTER TABLE games ADD COLUMN white_user_id TEXT REFERENCES users (id);
+ALTER TABLE games ADD COLUMN black_user_id TEXT REFERENCES users (id);
@@ -0,0 +1,14 @@
+-- `time_control` is derived from the clock when the game is created; no clock
+-- means correspondence.
+ALTER TABLE games ADD COLUMN variant TEXT NOT NULL DEFAULT 'standard'
+    CHECK (variant IN ('standard'));
+ALTER TABLE games ADD COLUMN time_control TEXT NOT NULL DEFAULT 'correspondence'
+    CHECK (time_control IN ('bullet','blitz','rapid','classical','correspondence'));
+ALTER TABLE games ADD COLUMN clock_initial_secs INTEGER;
+ALTER TABLE games ADD COLUMN clock_increment_secs INTEGER;
+
+-- A player's history is read newest first, from whichever seat they had.
+CREATE INDEX games_white_user_id_idx ON games (white_user_id, created_at)
+    WHERE white_user_id IS NOT NULL;
+CREATE INDEX games_black_user_id_idx ON games (black_user_id, created_at)
+    WHERE black_user_id IS NOT NULL;
@@ -0,0 +1,21 @@
+-- Glicko-2 state per player, variant and time control. Rows are created with
+-- the defaults when a player finishes their first rated game in a category.
+CREATE TABLE ratings (
+    user_id      TEXT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    variant      TEXT NOT NULL,
+    time_control TEXT NOT NULL,
+    rating       REAL NOT NULL,
+    deviation    REAL NOT NULL,
+    volatility   REAL NOT NULL,
+    games        INTEGER NOT NULL DEFAULT 0,
+    updated_at   TEXT NOT NULL,
+    PRIMARY KEY (user_id, variant, time_control)
+);
+
+-- The rating columns are rounded and filled in, in the same transaction that
+-- finishes a rated game.
+ALTER TABLE games ADD COLUMN rated INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE games ADD COLUMN white_rating_before INTEGER;
+ALTER TABLE games ADD COLUMN white_rating_after INTEGER;
+ALTER TABLE games ADD COLUMN black_rating_before INTEGER;
+ALTER TABLE games ADD COLUMN black_rating_after INTEGER;
@@ -0,0 +1,16 @@
+-- One point per player per rated game, written with the rating update.
+CREATE TABLE rating_history (
+    user_id      TEXT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    game_id      TEXT NOT NULL REFERENCES games (id) ON DELETE CASCADE,
+    variant      TEXT NOT NULL,
+    time_control TEXT NOT NULL,
+    rating       REAL NOT NULL,
+    deviation    REAL NOT NULL,
+    recorded_at  TEXT NOT NULL,
+    PRIMARY KEY (user_id, game_id)
+);
+CREATE INDEX rating_history_user_id_idx
+    ON rating_history (user_id, variant, time_control, recorded_at);
+
+-- Leaderboards read the top of one category.
+CREATE INDEX ratings_leaderboard_idx ON ratings (variant, time_control, rating DESC);
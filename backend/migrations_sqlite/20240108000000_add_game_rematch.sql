@@ -0,0 +1,4 @@
+-- Rematches form a chain: each game points back to the one it followed, and
+-- forward to its rematch. A game can have only one rematch.
+ALTER TABLE games ADD COLUMN rematch_of TEXT REFERENCES games (id);
+ALTER TABLE games ADD COLUMN rematch_id TEXT REFERENCES games (id);
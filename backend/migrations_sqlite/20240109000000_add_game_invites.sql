@@ -0,0 +1,8 @@
+-- Private games are left out of the public listing, and their open seat can
+-- only be taken with the invite token, which may expire or be single-use.
+-- The seat can also be kept for one player.
+ALTER TABLE games ADD COLUMN private INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE games ADD COLUMN invite_token TEXT;
+ALTER TABLE games ADD COLUMN invite_expires_at TEXT;
+ALTER TABLE games ADD COLUMN invite_single_use INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE games ADD COLUMN invited_user_id TEXT REFERENCES users (id);
@@ -0,0 +1,168 @@
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use axum::extract::{FromRequestParts, OptionalFromRequestParts};
+use axum::http::header::AUTHORIZATION;
+use axum::http::request::Parts;
+use axum::http::HeaderMap;
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+use sha2::{Digest, Sha256};
+use std::sync::LazyLock;
+use uuid::Uuid;
+
+use crate::config::AuthConfig;
+use crate::db::models::UserRow;
+use crate::error::{AppError, ErrorCode};
+use crate::game::Credential;
+use crate::state::AppState;
+
+pub const SESSION_COOKIE: &str = "session";
+
+/// Verified against when a login names an unknown user, so that case takes
+/// as long as a wrong password.
+static DUMMY_HASH: LazyLock<String> =
+    LazyLock::new(|| hash_blocking("not a real password").unwrap_or_default());
+
+fn hash_blocking(password: &str) -> Result<String, AppError> {
+    let salt = SaltString::encode_b64(Uuid::new_v4().as_bytes())
+        .map_err(|e| AppError::Internal(format!("Failed to make salt: {e}")))?;
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| AppError::Internal(format!("Failed to hash password: {e}")))
+}
+
+fn verify_blocking(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+/// Hashes a password with argon2 off the async runtime.
+pub async fn hash_password(password: String) -> Result<String, AppError> {
+    tokio::task::spawn_blocking(move || hash_blocking(&password))
+        .await
+        .map_err(|e| AppError::Internal(format!("Password hashing failed: {e}")))?
+}
+
+/// Checks a password against a stored hash, or against a dummy one when the
+/// user doesn't exist.
+pub async fn verify_password(password: String, hash: Option<String>) -> Result<bool, AppError> {
+    tokio::task::spawn_blocking(move || match hash {
+        Some(hash) => verify_blocking(&password, &hash),
+        None => {
+            verify_blocking(&password, &DUMMY_HASH);
+            false
+        }
+    })
+    .await
+    .map_err(|e| AppError::Internal(format!("Password check failed: {e}")))
+}
+
+/// A fresh random session token (244 random bits, hex encoded).
+pub fn new_session_token() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+/// Sessions are stored by hash, so a leaked database doesn't leak live tokens.
+pub fn token_hash(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// The credentials of an `Authorization: <scheme> <credentials>` header.
+fn authorization<'a>(headers: &'a HeaderMap, scheme: &str) -> Option<&'a str> {
+    let (name, credentials) = headers.get(AUTHORIZATION)?.to_str().ok()?.split_once(' ')?;
+    Some(credentials.trim()).filter(|c| name.eq_ignore_ascii_case(scheme) && !c.is_empty())
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    authorization(headers, "Bearer")
+}
+
+/// The session token of a request, from `Authorization: Bearer` or the session cookie.
+pub fn session_token(headers: &HeaderMap) -> Option<String> {
+    bearer_token(headers).map(str::to_string).or_else(|| {
+        CookieJar::from_headers(headers)
+            .get(SESSION_COOKIE)
+            .map(|c| c.value().to_string())
+    })
+}
+
+pub fn session_cookie(token: String, auth: &AuthConfig) -> Cookie<'static> {
+    Cookie::build((SESSION_COOKIE, token))
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .secure(auth.secure_cookies)
+        .max_age(time::Duration::days(auth.session_ttl_days.into()))
+        .build()
+}
+
+pub fn removal_cookie() -> Cookie<'static> {
+    Cookie::build(SESSION_COOKIE).path("/").build()
+}
+
+/// The signed-in user. Requests without a session are rejected; use
+/// `Option<CurrentUser>` where guests are welcome too.
+pub struct CurrentUser(pub UserRow);
+
+impl FromRequestParts<AppState> for CurrentUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, AppError> {
+        <Self as OptionalFromRequestParts<AppState>>::from_request_parts(parts, state)
+            .await?
+            .ok_or_else(|| AppError::Unauthorized("Sign in required".to_string()))
+    }
+}
+
+impl OptionalFromRequestParts<AppState> for CurrentUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Option<Self>, AppError> {
+        let bearer = bearer_token(&parts.headers).is_some();
+        let Some(token) = session_token(&parts.headers) else {
+            return Ok(None);
+        };
+        match state.repo.get_session_user(&token_hash(&token)).await? {
+            Some(user) => Ok(Some(CurrentUser(user))),
+            // A stale cookie just means the browser is a guest again, but a
+            // bad bearer token is a client bug worth reporting
+            None if !bearer => Ok(None),
+            None => Err(AppError::rejected(
+                ErrorCode::InvalidSession,
+                "Session expired or invalid",
+            )),
+        }
+    }
+}
+
+/// Who a REST request acts for in a game: the seat secret from
+/// `Authorization: Seat <secret>`, or else the signed-in user.
+pub struct SeatCredential(pub Credential);
+
+impl FromRequestParts<AppState> for SeatCredential {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, AppError> {
+        if let Some(secret) = authorization(&parts.headers, "Seat") {
+            let secret = secret
+                .parse()
+                .map_err(|_| AppError::rejected(ErrorCode::InvalidSecret, "Invalid secret"))?;
+            return Ok(SeatCredential(Credential::Secret(secret)));
+        }
+        let user =
+            <CurrentUser as OptionalFromRequestParts<AppState>>::from_request_parts(parts, state)
+                .await?;
+        match user {
+            Some(CurrentUser(user)) => Ok(SeatCredential(Credential::User(user.id))),
+            None => Err(AppError::Unauthorized(
+                "Send the seat secret as `Authorization: Seat <secret>` or sign in".to_string(),
+            )),
+        }
+    }
+}
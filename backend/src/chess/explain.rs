@@ -0,0 +1,233 @@
+use serde::Serialize;
+use shakmaty::{attacks, uci::UciMove, CastlingSide, Chess, Piece, Position, Rank, Role, Square};
+
+/// Why a well-formed move can't be played in a position.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IllegalMoveReason {
+    NoPiece,
+    WrongColor,
+    /// The piece doesn't move that way, or something is in its path.
+    CannotMoveThatWay,
+    /// The piece is pinned to its king.
+    Pinned,
+    /// The move leaves (or puts) the mover's king in check.
+    LeavesKingInCheck,
+    /// The king is in check or would pass through or land on an attacked square.
+    CastlingThroughCheck,
+    CastlingBlocked,
+    CastlingRightsLost,
+    MissingPromotion,
+}
+
+impl IllegalMoveReason {
+    pub fn message(self) -> &'static str {
+        match self {
+            IllegalMoveReason::NoPiece => "there is no piece on that square",
+            IllegalMoveReason::WrongColor => "that piece belongs to the opponent",
+            IllegalMoveReason::CannotMoveThatWay => "that piece can't move that way",
+            IllegalMoveReason::Pinned => "that piece is pinned to the king",
+            IllegalMoveReason::LeavesKingInCheck => "the move leaves the king in check",
+            IllegalMoveReason::CastlingThroughCheck => {
+                "the king can't castle out of or through check"
+            }
+            IllegalMoveReason::CastlingBlocked => "there are pieces between the king and the rook",
+            IllegalMoveReason::CastlingRightsLost => "castling rights on that side are lost",
+            IllegalMoveReason::MissingPromotion => {
+                "a pawn reaching the last rank must name its promotion piece"
+            }
+        }
+    }
+}
+
+/// Works out why `uci` was rejected by `UciMove::to_move`, reading the move the
+/// same way it does (king two squares or onto its own rook is castling).
+pub fn explain_illegal(pos: &Chess, uci: &UciMove) -> IllegalMoveReason {
+    let UciMove::Normal {
+        from,
+        to,
+        promotion,
+    } = *uci
+    else {
+        return IllegalMoveReason::CannotMoveThatWay;
+    };
+    let turn = pos.turn();
+    let Some(piece) = pos.board().piece_at(from) else {
+        return IllegalMoveReason::NoPiece;
+    };
+    if piece.color != turn {
+        return IllegalMoveReason::WrongColor;
+    }
+
+    if piece.role == Role::King && promotion.is_none() {
+        let onto_rook = (pos.castles().castling_rights() & pos.us()).contains(to);
+        let castles = onto_rook
+            || (from == turn.fold_wb(Square::E1, Square::E8)
+                && to.rank() == turn.backrank()
+                && from.distance(to) == 2);
+        if castles {
+            return explain_castling(pos, CastlingSide::from_king_side(from.file() < to.file()));
+        }
+    }
+
+    if !reaches(pos, piece, from, to) {
+        return IllegalMoveReason::CannotMoveThatWay;
+    }
+    let last_rank = to.rank() == turn.other().backrank();
+    match promotion {
+        None if piece.role == Role::Pawn && last_rank => {
+            return IllegalMoveReason::MissingPromotion;
+        }
+        Some(role)
+            if piece.role != Role::Pawn
+                || !last_rank
+                || matches!(role, Role::Pawn | Role::King) =>
+        {
+            return IllegalMoveReason::CannotMoveThatWay;
+        }
+        _ => {}
+    }
+
+    explain_king_exposure(pos, piece, from, to)
+}
+
+/// Whether `piece` could go from `from` to `to` if its own king's safety didn't matter.
+fn reaches(pos: &Chess, piece: Piece, from: Square, to: Square) -> bool {
+    let occupied = pos.board().occupied();
+    if pos.us().contains(to) {
+        return false;
+    }
+    if piece.role != Role::Pawn {
+        return attacks::attacks(from, piece, occupied).contains(to);
+    }
+
+    if from.file() != to.file() {
+        return attacks::pawn_attacks(piece.color, from).contains(to)
+            && (pos.them().contains(to) || pos.maybe_ep_square() == Some(to));
+    }
+    let step = piece.color.fold_wb(8, -8);
+    let Some(one) = from.offset(step) else {
+        return false;
+    };
+    if occupied.contains(one) {
+        return false;
+    }
+    one == to
+        || (from.rank() == piece.color.relative_rank(Rank::Second)
+            && from.offset(2 * step) == Some(to)
+            && !occupied.contains(to))
+}
+
+fn explain_king_exposure(pos: &Chess, piece: Piece, from: Square, to: Square) -> IllegalMoveReason {
+    let Some(king) = pos.our(Role::King).first() else {
+        return IllegalMoveReason::CannotMoveThatWay;
+    };
+    let them = pos.turn().other();
+
+    let mut board = pos.board().clone();
+    board.discard_piece_at(from);
+    if piece.role == Role::Pawn && from.file() != to.file() && !pos.board().occupied().contains(to)
+    {
+        board.discard_piece_at(Square::from_coords(to.file(), from.rank()));
+    }
+    board.set_piece_at(to, piece);
+    let king_after = if piece.role == Role::King { to } else { king };
+    let attackers = board.attacks_to(king_after, them, board.occupied());
+
+    if piece.role != Role::King {
+        // Lines to the king that only this piece was blocking
+        let exposed = pos
+            .board()
+            .attacks_to(king, them, pos.board().occupied().without(from))
+            .without(pos.checkers());
+        if (attackers & exposed).any() {
+            return IllegalMoveReason::Pinned;
+        }
+    }
+    IllegalMoveReason::LeavesKingInCheck
+}
+
+fn explain_castling(pos: &Chess, side: CastlingSide) -> IllegalMoveReason {
+    let turn = pos.turn();
+    if !pos.castles().has(turn, side) {
+        return IllegalMoveReason::CastlingRightsLost;
+    }
+    if (pos.castles().path(turn, side) & pos.board().occupied()).any() {
+        return IllegalMoveReason::CastlingBlocked;
+    }
+    IllegalMoveReason::CastlingThroughCheck
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chess::{parse_fen, START_FEN};
+
+    /// Explains `uci` in `fen`, checking first that it really is illegal there.
+    fn reason(fen: &str, uci: &str) -> IllegalMoveReason {
+        let pos = parse_fen(fen).unwrap();
+        let uci: UciMove = uci.parse().unwrap();
+        assert!(uci.to_move(&pos).is_err(), "{uci} is legal in {fen}");
+        explain_illegal(&pos, &uci)
+    }
+
+    #[test]
+    fn empty_square() {
+        assert_eq!(reason(START_FEN, "e3e4"), IllegalMoveReason::NoPiece);
+    }
+
+    #[test]
+    fn opponents_piece() {
+        assert_eq!(reason(START_FEN, "e7e5"), IllegalMoveReason::WrongColor);
+    }
+
+    #[test]
+    fn piece_cannot_move_that_way() {
+        assert_eq!(
+            reason(START_FEN, "e2e5"),
+            IllegalMoveReason::CannotMoveThatWay
+        );
+        assert_eq!(
+            reason(START_FEN, "c1e3"),
+            IllegalMoveReason::CannotMoveThatWay
+        );
+    }
+
+    #[test]
+    fn pinned_piece() {
+        let fen = "4k3/4r3/8/8/8/8/4N3/4K3 w - - 0 1";
+        assert_eq!(reason(fen, "e2c3"), IllegalMoveReason::Pinned);
+    }
+
+    #[test]
+    fn move_ignoring_check() {
+        let fen = "4k3/8/8/8/8/8/P7/r3K3 w - - 0 1";
+        assert_eq!(reason(fen, "a2a3"), IllegalMoveReason::LeavesKingInCheck);
+    }
+
+    #[test]
+    fn castling_through_check() {
+        let fen = "4kr2/8/8/8/8/8/8/4K2R w K - 0 1";
+        assert_eq!(reason(fen, "e1g1"), IllegalMoveReason::CastlingThroughCheck);
+    }
+
+    #[test]
+    fn castling_blocked() {
+        assert_eq!(
+            reason(START_FEN, "e1g1"),
+            IllegalMoveReason::CastlingBlocked
+        );
+    }
+
+    #[test]
+    fn castling_without_rights() {
+        let fen = "4k3/8/8/8/8/8/8/4K2R w - - 0 1";
+        assert_eq!(reason(fen, "e1g1"), IllegalMoveReason::CastlingRightsLost);
+    }
+
+    #[test]
+    fn promotion_without_piece() {
+        let fen = "4k3/P7/8/8/8/8/8/4K3 w - - 0 1";
+        assert_eq!(reason(fen, "a7a8"), IllegalMoveReason::MissingPromotion);
+    }
+}
@@ -1,28 +1,51 @@
-use shakmaty::{fen::Fen, san::San, uci::UciMove, CastlingMode, Chess, Color, EnPassantMode, Outcome, Position};
+mod explain;
 
-use crate::error::AppError;
+use serde_json::json;
+use shakmaty::{
+    fen::Fen,
+    san::{San, SanPlus},
+    uci::UciMove,
+    CastlingMode, Chess, Color, EnPassantMode, Outcome, Position,
+};
+
+use crate::error::{AppError, ErrorCode};
+use explain::explain_illegal;
+
+pub const START_FEN: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
 
 pub fn parse_fen(fen: &str) -> Result<Chess, AppError> {
-    let fen: Fen = fen
+    let invalid = |message: String| {
+        AppError::rejected(ErrorCode::InvalidFen, message).with_details(json!({ "fen": fen }))
+    };
+    let parsed: Fen = fen
         .parse()
-        .map_err(|e| AppError::BadRequest(format!("Invalid FEN: {e}")))?;
-    let chess: Chess = fen
+        .map_err(|e| invalid(format!("Invalid FEN: {e}")))?;
+    let chess: Chess = parsed
         .into_position(CastlingMode::Standard)
-        .map_err(|e| AppError::BadRequest(format!("Invalid position: {e}")))?;
+        .map_err(|e| invalid(format!("Invalid position: {e}")))?;
     Ok(chess)
 }
 
-pub fn apply_uci_move(fen: &str, uci_move: &str) -> Result<(Chess, String), AppError> {
-    let pos = parse_fen(fen)?;
-    let uci: UciMove = uci_move
-        .parse()
-        .map_err(|e| AppError::BadRequest(format!("Invalid UCI move: {e}")))?;
-    let mv = uci
-        .to_move(&pos)
-        .map_err(|e| AppError::BadRequest(format!("Illegal move: {e}")))?;
+pub fn apply_uci_move(pos: &Chess, uci_move: &str) -> Result<(Chess, String), AppError> {
+    let uci: UciMove = uci_move.parse().map_err(|e| {
+        AppError::rejected(ErrorCode::InvalidMove, format!("Invalid UCI move: {e}"))
+            .with_details(json!({ "move": uci_move }))
+    })?;
+    let mv = uci.to_move(pos).map_err(|_| {
+        let reason = explain_illegal(pos, &uci);
+        AppError::rejected(
+            ErrorCode::IllegalMove,
+            format!("Illegal move: {}", reason.message()),
+        )
+        .with_details(json!({
+            "move": uci_move,
+            "turn": pos.turn().to_string(),
+            "reason": reason,
+        }))
+    })?;
 
     // Get SAN notation before applying
-    let san = San::from_move(&pos, &mv);
+    let san = San::from_move(pos, &mv);
     let san_str = san.to_string();
 
     let mut new_pos = pos.clone();
@@ -40,6 +63,23 @@ pub fn apply_uci_move(fen: &str, uci_move: &str) -> Result<(Chess, String), AppE
     Ok((new_pos, san_str))
 }
 
+/// Replays SAN moves from `initial_fen`, or the standard starting position.
+pub fn replay_san(initial_fen: Option<&str>, moves: &[String]) -> Result<Chess, AppError> {
+    let mut pos = match initial_fen {
+        Some(fen) => parse_fen(fen)?,
+        None => Chess::default(),
+    };
+    for san in moves {
+        let mv = san
+            .parse::<SanPlus>()
+            .ok()
+            .and_then(|s| s.san.to_move(&pos).ok())
+            .ok_or_else(|| AppError::Internal(format!("Corrupt move history at {san}")))?;
+        pos.play_unchecked(&mv);
+    }
+    Ok(pos)
+}
+
 pub fn legal_moves_uci(pos: &Chess) -> Vec<String> {
     let moves = pos.legal_moves();
     moves
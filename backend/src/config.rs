@@ -0,0 +1,706 @@
+use axum::http::HeaderValue;
+use clap::{Parser, ValueEnum};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Settings are read from the TOML file, then environment variables, then
+/// command-line flags; later sources win.
+#[derive(Parser)]
+pub struct Args {
+    /// TOML config file
+    #[arg(long, env = "CONFIG_FILE")]
+    pub config: Option<PathBuf>,
+    /// Address to listen on
+    #[arg(long)]
+    pub listen: Option<SocketAddr>,
+    /// Where games are stored
+    #[arg(long, value_enum)]
+    pub storage: Option<Storage>,
+    /// How game changes reach other instances
+    #[arg(long, value_enum)]
+    pub pubsub: Option<PubSubKind>,
+    /// Maximum database connections
+    #[arg(long)]
+    pub max_connections: Option<u32>,
+    /// Origin allowed to call the API; repeat for several, or pass `*` for any
+    #[arg(long = "cors-origin")]
+    pub cors_origins: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Storage {
+    /// Postgres or SQLite, depending on the scheme of the database URL
+    #[value(alias = "postgres")]
+    #[serde(alias = "postgres")]
+    Database,
+    /// Keep games in memory, for demos and tests; nothing survives a restart
+    Memory,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum PubSubKind {
+    /// Postgres `LISTEN/NOTIFY`; needs a Postgres database
+    Postgres,
+    /// Don't share changes; for a single instance
+    Local,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: SocketAddr,
+    pub storage: Storage,
+    /// Bearer token for the admin API; the API is disabled when unset.
+    pub admin_token: Option<String>,
+    pub database: DatabaseConfig,
+    pub rooms: RoomConfig,
+    pub cors: CorsConfig,
+    pub features: FeatureConfig,
+    pub shutdown: ShutdownConfig,
+    pub telemetry: TelemetryConfig,
+    pub auth: AuthConfig,
+    pub ratings: RatingsConfig,
+    pub matchmaking: MatchmakingConfig,
+    pub challenges: ChallengeConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// `postgres://…` or `sqlite:…`; the scheme picks the backend.
+    pub url: Option<String>,
+    pub max_connections: u32,
+    pub acquire_timeout_secs: u64,
+    /// Delays between attempts to connect at startup.
+    pub connect_retry_delays_ms: Vec<u64>,
+}
+
+/// Sizing of the per-game actors and their queues.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoomConfig {
+    /// Commands a game's actor can have queued.
+    pub mailbox_size: usize,
+    /// Events a socket may fall behind before it is resynced with a snapshot.
+    pub broadcast_capacity: usize,
+    /// Recent events kept for clients resuming with `?since=N`.
+    pub history_size: usize,
+    /// Private messages (acks, errors, draw offers) queued per socket.
+    pub outbound_queue_size: usize,
+    /// Delays between attempts to write a game state change.
+    pub persist_retry_delays_ms: Vec<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Origins allowed to call the API; `["*"]` allows any.
+    pub allowed_origins: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeatureConfig {
+    pub admin_api: bool,
+    /// Serve Prometheus metrics on `/metrics`.
+    pub metrics: bool,
+    /// Defaults to `postgres` with a Postgres database and `local` otherwise.
+    pub pubsub: Option<PubSubKind>,
+    /// Serve the lobby on `/ws/lobby`. Seeks, challenges and the queue live
+    /// in one process, so only one instance sharing a database may serve it.
+    pub lobby: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// How long to wait for sockets to close and writes to finish before exiting.
+    pub grace_secs: u64,
+    /// Delay suggested to clients before they reconnect.
+    pub reconnect_after_ms: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelemetryConfig {
+    /// OTLP/gRPC collector to export spans to, e.g. `http://localhost:4317`.
+    /// Spans are only logged when unset.
+    pub otlp_endpoint: Option<String>,
+    pub service_name: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// How long a sign-in lasts.
+    pub session_ttl_days: u32,
+    /// Mark the session cookie `Secure`; turn on when served over HTTPS.
+    pub secure_cookies: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RatingsConfig {
+    /// Players without a rated game in a category for this long drop off its leaderboard.
+    pub leaderboard_inactive_days: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MatchmakingConfig {
+    /// How often waiting players are paired.
+    pub interval_ms: u64,
+    /// Rating difference a player accepts right after joining the queue.
+    pub initial_window: u32,
+    /// How much that difference grows for every second of waiting.
+    pub window_growth_per_sec: u32,
+    /// The difference never grows past this.
+    pub max_window: u32,
+    /// Players aren't paired with any of their last this many queue opponents.
+    pub recent_opponents: usize,
+    /// Queue opponents are forgotten this long after their game started.
+    pub recent_opponent_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChallengeConfig {
+    /// Challenges nobody answered are withdrawn after this long.
+    pub expire_after_secs: u32,
+    /// How many unanswered challenges a player can have out at a time.
+    pub max_pending: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: SocketAddr::from(([0, 0, 0, 0], 3000)),
+            storage: Storage::Database,
+            admin_token: None,
+            database: DatabaseConfig::default(),
+            rooms: RoomConfig::default(),
+            cors: CorsConfig::default(),
+            features: FeatureConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            telemetry: TelemetryConfig::default(),
+            auth: AuthConfig::default(),
+            ratings: RatingsConfig::default(),
+            matchmaking: MatchmakingConfig::default(),
+            challenges: ChallengeConfig::default(),
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            max_connections: 5,
+            acquire_timeout_secs: 15,
+            connect_retry_delays_ms: vec![500, 1000, 2000],
+        }
+    }
+}
+
+impl Default for RoomConfig {
+    fn default() -> Self {
+        Self {
+            mailbox_size: 64,
+            broadcast_capacity: 64,
+            history_size: 128,
+            outbound_queue_size: 32,
+            persist_retry_delays_ms: vec![500, 1000, 2000],
+        }
+    }
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: vec!["*".to_string()],
+        }
+    }
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: None,
+            service_name: "chess-backend".to_string(),
+        }
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            session_ttl_days: 30,
+            secure_cookies: false,
+        }
+    }
+}
+
+impl Default for RatingsConfig {
+    fn default() -> Self {
+        Self {
+            leaderboard_inactive_days: 30,
+        }
+    }
+}
+
+impl Default for MatchmakingConfig {
+    fn default() -> Self {
+        Self {
+            interval_ms: 1000,
+            initial_window: 100,
+            window_growth_per_sec: 10,
+            max_window: 500,
+            recent_opponents: 3,
+            recent_opponent_secs: 3600,
+        }
+    }
+}
+
+impl Default for ChallengeConfig {
+    fn default() -> Self {
+        Self {
+            expire_after_secs: 120,
+            max_pending: 5,
+        }
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            grace_secs: 10,
+            reconnect_after_ms: 1000,
+        }
+    }
+}
+
+impl Default for FeatureConfig {
+    fn default() -> Self {
+        Self {
+            admin_api: true,
+            metrics: true,
+            pubsub: None,
+            lobby: true,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("cannot read {path}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("invalid config file {path}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+
+    #[error("invalid value {value:?} for {name}")]
+    Env { name: &'static str, value: String },
+
+    #[error("{0}")]
+    Invalid(String),
+}
+
+impl Config {
+    /// Builds the configuration from every source and checks it.
+    pub fn load(args: &Args) -> Result<Self, ConfigError> {
+        let mut config = match &args.config {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        config.apply_env(&EnvVars(&|name| std::env::var(name).ok()))?;
+        config.apply_args(args);
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &PathBuf) -> Result<Self, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.clone(),
+            source,
+        })?;
+        toml::from_str(&text).map_err(|source| ConfigError::Parse {
+            path: path.clone(),
+            source,
+        })
+    }
+
+    fn apply_env(&mut self, env: &EnvVars) -> Result<(), ConfigError> {
+        if let Some(listen) = env.parse("LISTEN_ADDR")? {
+            self.listen = listen;
+        }
+        if let Some(url) = env.get("DATABASE_URL") {
+            self.database.url = Some(url);
+        }
+        if let Some(n) = env.parse("DB_MAX_CONNECTIONS")? {
+            self.database.max_connections = n;
+        }
+        if let Some(secs) = env.parse("DB_ACQUIRE_TIMEOUT_SECS")? {
+            self.database.acquire_timeout_secs = secs;
+        }
+        if let Some(token) = env.get("ADMIN_TOKEN") {
+            self.admin_token = Some(token);
+        }
+        if let Some(origins) = env.get("CORS_ORIGINS") {
+            self.cors.allowed_origins = origins
+                .split(',')
+                .map(|o| o.trim().to_string())
+                .filter(|o| !o.is_empty())
+                .collect();
+        }
+        if let Some(endpoint) = env.get("OTEL_EXPORTER_OTLP_ENDPOINT") {
+            self.telemetry.otlp_endpoint = Some(endpoint);
+        }
+        if let Some(name) = env.get("OTEL_SERVICE_NAME") {
+            self.telemetry.service_name = name;
+        }
+        if let Some(value) = env.get("PUBSUB") {
+            let kind = PubSubKind::from_str(&value, true).map_err(|_| ConfigError::Env {
+                name: "PUBSUB",
+                value,
+            })?;
+            self.features.pubsub = Some(kind);
+        }
+        if let Some(lobby) = env.parse("LOBBY")? {
+            self.features.lobby = lobby;
+        }
+        Ok(())
+    }
+
+    fn apply_args(&mut self, args: &Args) {
+        if let Some(listen) = args.listen {
+            self.listen = listen;
+        }
+        if let Some(storage) = args.storage {
+            self.storage = storage;
+        }
+        if let Some(pubsub) = args.pubsub {
+            self.features.pubsub = Some(pubsub);
+        }
+        if let Some(n) = args.max_connections {
+            self.database.max_connections = n;
+        }
+        if !args.cors_origins.is_empty() {
+            self.cors.allowed_origins = args.cors_origins.clone();
+        }
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |msg: &str| Err(ConfigError::Invalid(msg.to_string()));
+
+        if self.storage == Storage::Database {
+            match self.database.url.as_deref() {
+                None => {
+                    return invalid("a database URL is required (DATABASE_URL or database.url)")
+                }
+                Some(url) if self.database_kind().is_none() => {
+                    return Err(ConfigError::Invalid(format!(
+                        "unsupported database URL {:?}; use postgres://, postgresql:// or sqlite:",
+                        redact(url)
+                    )));
+                }
+                Some(_) => {}
+            }
+        }
+        if self.features.pubsub == Some(PubSubKind::Postgres)
+            && self.database_kind() != Some(DatabaseKind::Postgres)
+        {
+            return invalid("pubsub = \"postgres\" needs a Postgres database");
+        }
+
+        if self.database.max_connections == 0 {
+            return invalid("database.max_connections must be at least 1");
+        }
+        if self.database.acquire_timeout_secs == 0 {
+            return invalid("database.acquire_timeout_secs must be at least 1");
+        }
+
+        if let Some(endpoint) = &self.telemetry.otlp_endpoint {
+            if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+                return Err(ConfigError::Invalid(format!(
+                    "invalid OTLP endpoint {endpoint:?}; expected e.g. http://localhost:4317"
+                )));
+            }
+        }
+
+        if self.shutdown.grace_secs == 0 {
+            return invalid("shutdown.grace_secs must be at least 1");
+        }
+        if self.auth.session_ttl_days == 0 {
+            return invalid("auth.session_ttl_days must be at least 1");
+        }
+        if self.ratings.leaderboard_inactive_days == 0 {
+            return invalid("ratings.leaderboard_inactive_days must be at least 1");
+        }
+        if self.matchmaking.interval_ms == 0 {
+            return invalid("matchmaking.interval_ms must be at least 1");
+        }
+        if self.matchmaking.initial_window > self.matchmaking.max_window {
+            return invalid("matchmaking.initial_window must not exceed matchmaking.max_window");
+        }
+        if self.challenges.expire_after_secs == 0 {
+            return invalid("challenges.expire_after_secs must be at least 1");
+        }
+
+        let rooms = &self.rooms;
+        for (name, size) in [
+            ("rooms.mailbox_size", rooms.mailbox_size),
+            ("rooms.broadcast_capacity", rooms.broadcast_capacity),
+            ("rooms.history_size", rooms.history_size),
+            ("rooms.outbound_queue_size", rooms.outbound_queue_size),
+        ] {
+            if size == 0 {
+                return Err(ConfigError::Invalid(format!("{name} must be at least 1")));
+            }
+        }
+        for (name, delays) in [
+            (
+                "database.connect_retry_delays_ms",
+                &self.database.connect_retry_delays_ms,
+            ),
+            (
+                "rooms.persist_retry_delays_ms",
+                &rooms.persist_retry_delays_ms,
+            ),
+        ] {
+            if delays.iter().any(|&d| d > 60_000) {
+                return Err(ConfigError::Invalid(format!(
+                    "{name} entries must be at most 60000"
+                )));
+            }
+        }
+
+        let origins = &self.cors.allowed_origins;
+        if origins.is_empty() {
+            return invalid("cors.allowed_origins must not be empty; use [\"*\"] to allow any");
+        }
+        if origins.len() > 1 && origins.iter().any(|o| o == "*") {
+            return invalid("cors.allowed_origins can't mix \"*\" with specific origins");
+        }
+        for origin in origins.iter().filter(|o| *o != "*") {
+            if !origin.starts_with("http://") && !origin.starts_with("https://")
+                || HeaderValue::from_str(origin).is_err()
+            {
+                return Err(ConfigError::Invalid(format!(
+                    "invalid CORS origin {origin:?}; expected e.g. https://chess.example.com"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn database_kind(&self) -> Option<DatabaseKind> {
+        if self.storage == Storage::Memory {
+            return None;
+        }
+        let url = self.database.url.as_deref()?;
+        if url.starts_with("sqlite:") {
+            Some(DatabaseKind::Sqlite)
+        } else if url.starts_with("postgres:") || url.starts_with("postgresql:") {
+            Some(DatabaseKind::Postgres)
+        } else {
+            None
+        }
+    }
+
+    pub fn pubsub(&self) -> PubSubKind {
+        self.features.pubsub.unwrap_or(match self.database_kind() {
+            Some(DatabaseKind::Postgres) => PubSubKind::Postgres,
+            _ => PubSubKind::Local,
+        })
+    }
+
+    /// The admin token, if the admin API is enabled.
+    pub fn admin_token(&self) -> Option<String> {
+        self.admin_token
+            .clone()
+            .filter(|t| self.features.admin_api && !t.is_empty())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DatabaseKind {
+    Postgres,
+    Sqlite,
+}
+
+/// Looks up environment variables; tests pass their own instead of the process's.
+struct EnvVars<'a>(&'a dyn Fn(&str) -> Option<String>);
+
+impl EnvVars<'_> {
+    fn get(&self, name: &str) -> Option<String> {
+        (self.0)(name).filter(|v| !v.is_empty())
+    }
+
+    fn parse<T: FromStr>(&self, name: &'static str) -> Result<Option<T>, ConfigError> {
+        self.get(name)
+            .map(|value| value.parse().map_err(|_| ConfigError::Env { name, value }))
+            .transpose()
+    }
+}
+
+/// Hides the password in a database URL before it goes into an error message.
+fn redact(url: &str) -> String {
+    match (url.find("://"), url.rfind('@')) {
+        (Some(scheme_end), Some(at)) if at > scheme_end => {
+            format!("{}://***{}", &url[..scheme_end], &url[at..])
+        }
+        _ => url.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds the configuration the way `load` does, from a TOML file's text,
+    /// environment variables and command-line flags.
+    fn build(toml: &str, vars: &[(&str, &str)], flags: &[&str]) -> Result<Config, ConfigError> {
+        let mut config: Config = toml::from_str(toml).unwrap();
+        config.apply_env(&EnvVars(&|name| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.to_string())
+        }))?;
+        let args = Args::try_parse_from(std::iter::once("backend").chain(flags.iter().copied()));
+        config.apply_args(&args.unwrap());
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn rejection(toml: &str) -> String {
+        match build(&format!("storage = \"memory\"\n{toml}"), &[], &[]) {
+            Err(ConfigError::Invalid(msg)) => msg,
+            other => panic!("{toml:?} was not rejected: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn flags_override_env_which_overrides_the_file() {
+        let toml = r#"
+            listen = "127.0.0.1:4000"
+            storage = "memory"
+            [database]
+            max_connections = 7
+            acquire_timeout_secs = 3
+            [rooms]
+            mailbox_size = 11
+        "#;
+        let vars = [
+            ("LISTEN_ADDR", "127.0.0.1:5000"),
+            ("DB_MAX_CONNECTIONS", "8"),
+            ("DB_ACQUIRE_TIMEOUT_SECS", ""),
+        ];
+        let config = build(toml, &vars, &["--max-connections", "9"]).unwrap();
+        assert_eq!(config.listen, "127.0.0.1:5000".parse().unwrap());
+        assert_eq!(config.database.max_connections, 9);
+        // Empty variables count as unset
+        assert_eq!(config.database.acquire_timeout_secs, 3);
+        assert_eq!(config.rooms.mailbox_size, 11);
+
+        let config = build(toml, &vars, &["--listen", "127.0.0.1:6000"]).unwrap();
+        assert_eq!(config.listen, "127.0.0.1:6000".parse().unwrap());
+        assert_eq!(config.database.max_connections, 8);
+    }
+
+    #[test]
+    fn bad_env_values_are_rejected() {
+        for (name, value) in [
+            ("LISTEN_ADDR", "nowhere"),
+            ("PUBSUB", "redis"),
+            ("LOBBY", "maybe"),
+        ] {
+            match build("storage = \"memory\"", &[(name, value)], &[]) {
+                Err(ConfigError::Env { name: got, .. }) => assert_eq!(got, name),
+                other => panic!("{name}={value} was not rejected: {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn zero_sizes_are_rejected() {
+        for (section, key) in [
+            ("database", "max_connections"),
+            ("database", "acquire_timeout_secs"),
+            ("shutdown", "grace_secs"),
+            ("auth", "session_ttl_days"),
+            ("ratings", "leaderboard_inactive_days"),
+            ("matchmaking", "interval_ms"),
+            ("challenges", "expire_after_secs"),
+            ("rooms", "mailbox_size"),
+            ("rooms", "broadcast_capacity"),
+            ("rooms", "history_size"),
+            ("rooms", "outbound_queue_size"),
+        ] {
+            let msg = rejection(&format!("[{section}]\n{key} = 0"));
+            assert_eq!(msg, format!("{section}.{key} must be at least 1"));
+        }
+    }
+
+    #[test]
+    fn other_bad_values_are_rejected() {
+        for (toml, expected) in [
+            (
+                "[matchmaking]\ninitial_window = 600",
+                "initial_window must not exceed",
+            ),
+            (
+                "[database]\nconnect_retry_delays_ms = [60001]",
+                "at most 60000",
+            ),
+            (
+                "[rooms]\npersist_retry_delays_ms = [60001]",
+                "at most 60000",
+            ),
+            ("[cors]\nallowed_origins = []", "must not be empty"),
+            (
+                "[cors]\nallowed_origins = [\"*\", \"https://a.example\"]",
+                "can't mix",
+            ),
+            (
+                "[cors]\nallowed_origins = [\"a.example\"]",
+                "invalid CORS origin",
+            ),
+            (
+                "[telemetry]\notlp_endpoint = \"localhost:4317\"",
+                "invalid OTLP endpoint",
+            ),
+            (
+                "[features]\npubsub = \"postgres\"",
+                "needs a Postgres database",
+            ),
+        ] {
+            let msg = rejection(toml);
+            assert!(msg.contains(expected), "{toml:?}: {msg}");
+        }
+    }
+
+    #[test]
+    fn database_storage_needs_a_supported_url() {
+        let Err(ConfigError::Invalid(msg)) = build("", &[], &[]) else {
+            panic!("a missing database URL was accepted");
+        };
+        assert!(msg.contains("database URL is required"), "{msg}");
+
+        let vars = [("DATABASE_URL", "mysql://user:secret@db/chess")];
+        let Err(ConfigError::Invalid(msg)) = build("", &vars, &[]) else {
+            panic!("a MySQL URL was accepted");
+        };
+        assert!(msg.contains("unsupported database URL"), "{msg}");
+        assert!(!msg.contains("secret"), "{msg}");
+    }
+}
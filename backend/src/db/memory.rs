@@ -0,0 +1,423 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+use super::models::{
+    GameCounts, GameFilter, GameRow, GameStatus, LeaderboardRow, NewGame, PlayerResult,
+    RatingHistoryRow, RatingRow, TimeControl, UserRow, Variant,
+};
+use super::repository::GameRepository;
+use crate::error::AppError;
+use crate::rating;
+
+struct Session {
+    user_id: Uuid,
+    expires_at: DateTime<Utc>,
+}
+
+/// Keeps games in process memory; everything is lost on exit. Meant for demos
+/// and tests that shouldn't need a database.
+#[derive(Default)]
+pub struct MemoryGameRepository {
+    games: Mutex<HashMap<Uuid, GameRow>>,
+    users: Mutex<HashMap<Uuid, UserRow>>,
+    sessions: Mutex<HashMap<String, Session>>,
+    ratings: Mutex<HashMap<(Uuid, Variant, TimeControl), RatingRow>>,
+    rating_history: Mutex<HashMap<Uuid, Vec<RatingHistoryRow>>>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl MemoryGameRepository {
+    fn games(&self) -> std::sync::MutexGuard<'_, HashMap<Uuid, GameRow>> {
+        lock(&self.games)
+    }
+
+    fn users(&self) -> std::sync::MutexGuard<'_, HashMap<Uuid, UserRow>> {
+        lock(&self.users)
+    }
+
+    fn sessions(&self) -> std::sync::MutexGuard<'_, HashMap<String, Session>> {
+        lock(&self.sessions)
+    }
+
+    fn ratings(
+        &self,
+    ) -> std::sync::MutexGuard<'_, HashMap<(Uuid, Variant, TimeControl), RatingRow>> {
+        lock(&self.ratings)
+    }
+
+    fn rating_history(&self) -> std::sync::MutexGuard<'_, HashMap<Uuid, Vec<RatingHistoryRow>>> {
+        lock(&self.rating_history)
+    }
+
+    /// Applies a finished rated game to both players' ratings and records the
+    /// change on the game. Called with the games lock held.
+    fn rate_game(&self, game: &mut GameRow) {
+        let (Some(white_id), Some(black_id), Some(score)) =
+            (game.white_user_id, game.black_user_id, game.white_score())
+        else {
+            return;
+        };
+        let (variant, time_control) = (game.variant, game.time_control);
+        let mut ratings = self.ratings();
+        let current = |id| {
+            ratings
+                .get(&(id, variant, time_control))
+                .map(RatingRow::glicko)
+                .unwrap_or_default()
+        };
+        let (white_before, black_before) = (current(white_id), current(black_id));
+        let (white_after, black_after) = rating::rate_game(white_before, black_before, score);
+
+        let now = Utc::now();
+        let mut history = self.rating_history();
+        for (user_id, after) in [(white_id, white_after), (black_id, black_after)] {
+            history.entry(user_id).or_default().push(RatingHistoryRow {
+                game_id: game.id,
+                variant,
+                time_control,
+                rating: after.rating,
+                deviation: after.deviation,
+                recorded_at: now,
+            });
+            let row = ratings
+                .entry((user_id, variant, time_control))
+                .or_insert_with(|| RatingRow {
+                    user_id,
+                    variant,
+                    time_control,
+                    rating: after.rating,
+                    deviation: after.deviation,
+                    volatility: after.volatility,
+                    games: 0,
+                    updated_at: now,
+                });
+            row.rating = after.rating;
+            row.deviation = after.deviation;
+            row.volatility = after.volatility;
+            row.games += 1;
+            row.updated_at = now;
+        }
+        game.white_rating_before = Some(white_before.rounded());
+        game.white_rating_after = Some(white_after.rounded());
+        game.black_rating_before = Some(black_before.rounded());
+        game.black_rating_after = Some(black_after.rounded());
+    }
+}
+
+/// The row a new game starts as.
+fn new_row(new_game: &NewGame) -> GameRow {
+    let now = Utc::now();
+    GameRow {
+        id: Uuid::new_v4(),
+        white_secret: Uuid::new_v4(),
+        black_secret: new_game.seat_black.then(Uuid::new_v4),
+        fen: new_game.start_fen().to_string(),
+        moves: vec![],
+        status: if new_game.seat_black {
+            GameStatus::Active
+        } else {
+            GameStatus::Waiting
+        },
+        result: None,
+        version: 0,
+        created_at: now,
+        updated_at: now,
+        white_user_id: new_game.white_user,
+        black_user_id: new_game.black_user.filter(|_| new_game.seat_black),
+        variant: new_game.variant,
+        time_control: new_game.time_control(),
+        clock_initial_secs: new_game.clock.map(|c| c.initial_secs as i32),
+        clock_increment_secs: new_game.clock.map(|c| c.increment_secs as i32),
+        rated: new_game.rated,
+        white_rating_before: None,
+        white_rating_after: None,
+        black_rating_before: None,
+        black_rating_after: None,
+        initial_fen: new_game.initial_fen.clone(),
+        rematch_of: new_game.rematch_of,
+        rematch_id: None,
+        private: new_game.private,
+        invite_token: (new_game.private && !new_game.seat_black).then(Uuid::new_v4),
+        invite_expires_at: new_game.invite_expires_at,
+        invite_single_use: new_game.invite_single_use,
+        invited_user_id: new_game.invited_user,
+    }
+}
+
+#[async_trait]
+impl GameRepository for MemoryGameRepository {
+    async fn ping(&self) -> Result<(), AppError> {
+        Ok(())
+    }
+
+    async fn create_game(&self, new_game: &NewGame) -> Result<GameRow, AppError> {
+        let game = new_row(new_game);
+        self.games().insert(game.id, game.clone());
+        Ok(game)
+    }
+
+    async fn get_game(&self, id: Uuid) -> Result<Option<GameRow>, AppError> {
+        Ok(self.games().get(&id).cloned())
+    }
+
+    async fn delete_unplayed_game(&self, id: Uuid) -> Result<bool, AppError> {
+        let mut games = self.games();
+        if games.get(&id).is_some_and(|g| g.version == 0) {
+            games.remove(&id);
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    async fn list_games(&self) -> Result<Vec<GameRow>, AppError> {
+        let mut games: Vec<GameRow> = self
+            .games()
+            .values()
+            .filter(|g| !g.private)
+            .cloned()
+            .collect();
+        games.sort_by_key(|g| Reverse(g.created_at));
+        games.truncate(50);
+        Ok(games)
+    }
+
+    async fn join_game(
+        &self,
+        id: Uuid,
+        black_user: Option<Uuid>,
+    ) -> Result<Option<GameRow>, AppError> {
+        let mut games = self.games();
+        let Some(game) = games.get_mut(&id).filter(|g| g.black_secret.is_none()) else {
+            return Ok(None);
+        };
+        game.black_secret = Some(Uuid::new_v4());
+        game.black_user_id = black_user;
+        if game.invite_single_use {
+            game.invite_token = None;
+        }
+        game.status = GameStatus::Active;
+        game.version += 1;
+        game.updated_at = Utc::now();
+        Ok(Some(game.clone()))
+    }
+
+    async fn create_rematch(
+        &self,
+        id: Uuid,
+        expected_version: i64,
+        rematch: &NewGame,
+    ) -> Result<Option<(GameRow, GameRow)>, AppError> {
+        let mut games = self.games();
+        let Some(game) = games
+            .get_mut(&id)
+            .filter(|g| g.version == expected_version && g.rematch_id.is_none())
+        else {
+            return Ok(None);
+        };
+        let created = new_row(rematch);
+        game.rematch_id = Some(created.id);
+        game.version += 1;
+        game.updated_at = Utc::now();
+        let game = game.clone();
+        games.insert(created.id, created.clone());
+        Ok(Some((game, created)))
+    }
+
+    async fn update_game_state(
+        &self,
+        id: Uuid,
+        expected_version: i64,
+        fen: &str,
+        moves: &[String],
+        status: GameStatus,
+        result: Option<&str>,
+    ) -> Result<Option<GameRow>, AppError> {
+        let mut games = self.games();
+        let Some(game) = games.get_mut(&id).filter(|g| g.version == expected_version) else {
+            return Ok(None);
+        };
+        game.fen = fen.to_string();
+        game.moves = moves.to_vec();
+        game.status = status;
+        game.result = result.map(str::to_string);
+        game.version += 1;
+        game.updated_at = Utc::now();
+        if game.awaits_rating() {
+            self.rate_game(game);
+        }
+        Ok(Some(game.clone()))
+    }
+
+    async fn create_user(
+        &self,
+        username: &str,
+        password_hash: &str,
+    ) -> Result<Option<UserRow>, AppError> {
+        let mut users = self.users();
+        if users
+            .values()
+            .any(|u| u.username.eq_ignore_ascii_case(username))
+        {
+            return Ok(None);
+        }
+        let user = UserRow {
+            id: Uuid::new_v4(),
+            username: username.to_string(),
+            password_hash: password_hash.to_string(),
+            created_at: Utc::now(),
+        };
+        users.insert(user.id, user.clone());
+        Ok(Some(user))
+    }
+
+    async fn get_user_by_name(&self, username: &str) -> Result<Option<UserRow>, AppError> {
+        Ok(self
+            .users()
+            .values()
+            .find(|u| u.username.eq_ignore_ascii_case(username))
+            .cloned())
+    }
+
+    async fn create_session(
+        &self,
+        token_hash: &str,
+        user_id: Uuid,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), AppError> {
+        let now = Utc::now();
+        let mut sessions = self.sessions();
+        sessions.retain(|_, s| s.expires_at > now);
+        sessions.insert(
+            token_hash.to_string(),
+            Session {
+                user_id,
+                expires_at,
+            },
+        );
+        Ok(())
+    }
+
+    async fn get_session_user(&self, token_hash: &str) -> Result<Option<UserRow>, AppError> {
+        let user_id = match self.sessions().get(token_hash) {
+            Some(session) if session.expires_at > Utc::now() => session.user_id,
+            _ => return Ok(None),
+        };
+        Ok(self.users().get(&user_id).cloned())
+    }
+
+    async fn delete_session(&self, token_hash: &str) -> Result<(), AppError> {
+        self.sessions().remove(token_hash);
+        Ok(())
+    }
+
+    async fn list_user_games(
+        &self,
+        user_id: Uuid,
+        filter: &GameFilter,
+        limit: u32,
+        offset: u32,
+    ) -> Result<Vec<GameRow>, AppError> {
+        let mut games: Vec<GameRow> = self
+            .games()
+            .values()
+            .filter(|g| filter.matches(g, user_id))
+            .cloned()
+            .collect();
+        games.sort_by_key(|g| Reverse(g.created_at));
+        Ok(games
+            .into_iter()
+            .skip(offset as usize)
+            .take(limit as usize)
+            .collect())
+    }
+
+    async fn count_user_games(&self, user_id: Uuid) -> Result<GameCounts, AppError> {
+        let mut counts = GameCounts::default();
+        for game in self.games().values() {
+            if game.private {
+                continue;
+            }
+            let Some(seat) = game.seat_of_user(user_id) else {
+                continue;
+            };
+            counts.total += 1;
+            match game.result_for(seat) {
+                Some(PlayerResult::Win) => counts.wins += 1,
+                Some(PlayerResult::Loss) => counts.losses += 1,
+                Some(PlayerResult::Draw) => counts.draws += 1,
+                None if matches!(game.status, GameStatus::Waiting | GameStatus::Active) => {
+                    counts.ongoing += 1
+                }
+                None => {}
+            }
+        }
+        Ok(counts)
+    }
+
+    async fn get_user_ratings(&self, user_id: Uuid) -> Result<Vec<RatingRow>, AppError> {
+        let mut ratings: Vec<RatingRow> = self
+            .ratings()
+            .values()
+            .filter(|r| r.user_id == user_id)
+            .cloned()
+            .collect();
+        ratings.sort_by_key(|r| (r.variant.to_string(), r.time_control.to_string()));
+        Ok(ratings)
+    }
+
+    async fn get_rating_history(&self, user_id: Uuid) -> Result<Vec<RatingHistoryRow>, AppError> {
+        let mut history = self
+            .rating_history()
+            .get(&user_id)
+            .cloned()
+            .unwrap_or_default();
+        // Stable, so each category stays in the order it was recorded
+        history.sort_by_key(|r| (r.variant.to_string(), r.time_control.to_string()));
+        Ok(history)
+    }
+
+    async fn leaderboard(
+        &self,
+        variant: Variant,
+        time_control: TimeControl,
+        max_deviation: f64,
+        active_since: DateTime<Utc>,
+        limit: u32,
+    ) -> Result<Vec<LeaderboardRow>, AppError> {
+        let mut ratings: Vec<RatingRow> = self
+            .ratings()
+            .values()
+            .filter(|r| {
+                r.variant == variant
+                    && r.time_control == time_control
+                    && r.deviation <= max_deviation
+                    && r.updated_at >= active_since
+            })
+            .cloned()
+            .collect();
+        ratings.sort_by(|a, b| b.rating.total_cmp(&a.rating));
+        ratings.truncate(limit as usize);
+
+        let users = self.users();
+        Ok(ratings
+            .into_iter()
+            .filter_map(|r| {
+                let user = users.get(&r.user_id)?;
+                Some(LeaderboardRow {
+                    username: user.username.clone(),
+                    rating: r.rating,
+                    deviation: r.deviation,
+                    games: r.games,
+                    updated_at: r.updated_at,
+                })
+            })
+            .collect())
+    }
+}
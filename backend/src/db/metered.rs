@@ -0,0 +1,175 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Instant;
+use uuid::Uuid;
+
+use super::models::{
+    GameCounts, GameFilter, GameRow, GameStatus, LeaderboardRow, NewGame, RatingHistoryRow,
+    RatingRow, TimeControl, UserRow, Variant,
+};
+use super::repository::GameRepository;
+use crate::error::AppError;
+use crate::telemetry;
+
+/// Records the latency of every call to the wrapped repository.
+pub struct MeteredRepository {
+    inner: Arc<dyn GameRepository>,
+}
+
+impl MeteredRepository {
+    pub fn new(inner: Arc<dyn GameRepository>) -> Self {
+        Self { inner }
+    }
+}
+
+async fn timed<T>(
+    operation: &'static str,
+    query: impl Future<Output = Result<T, AppError>>,
+) -> Result<T, AppError> {
+    let started = Instant::now();
+    let result = query.await;
+    telemetry::record_db_query(operation, started.elapsed(), result.is_ok());
+    result
+}
+
+#[async_trait]
+impl GameRepository for MeteredRepository {
+    async fn ping(&self) -> Result<(), AppError> {
+        timed("ping", self.inner.ping()).await
+    }
+
+    async fn create_game(&self, game: &NewGame) -> Result<GameRow, AppError> {
+        timed("create_game", self.inner.create_game(game)).await
+    }
+
+    async fn get_game(&self, id: Uuid) -> Result<Option<GameRow>, AppError> {
+        timed("get_game", self.inner.get_game(id)).await
+    }
+
+    async fn delete_unplayed_game(&self, id: Uuid) -> Result<bool, AppError> {
+        timed("delete_unplayed_game", self.inner.delete_unplayed_game(id)).await
+    }
+
+    async fn list_games(&self) -> Result<Vec<GameRow>, AppError> {
+        timed("list_games", self.inner.list_games()).await
+    }
+
+    async fn join_game(
+        &self,
+        id: Uuid,
+        black_user: Option<Uuid>,
+    ) -> Result<Option<GameRow>, AppError> {
+        timed("join_game", self.inner.join_game(id, black_user)).await
+    }
+
+    async fn create_rematch(
+        &self,
+        id: Uuid,
+        expected_version: i64,
+        rematch: &NewGame,
+    ) -> Result<Option<(GameRow, GameRow)>, AppError> {
+        timed(
+            "create_rematch",
+            self.inner.create_rematch(id, expected_version, rematch),
+        )
+        .await
+    }
+
+    async fn update_game_state(
+        &self,
+        id: Uuid,
+        expected_version: i64,
+        fen: &str,
+        moves: &[String],
+        status: GameStatus,
+        result: Option<&str>,
+    ) -> Result<Option<GameRow>, AppError> {
+        timed(
+            "update_game_state",
+            self.inner
+                .update_game_state(id, expected_version, fen, moves, status, result),
+        )
+        .await
+    }
+
+    async fn create_user(
+        &self,
+        username: &str,
+        password_hash: &str,
+    ) -> Result<Option<UserRow>, AppError> {
+        timed(
+            "create_user",
+            self.inner.create_user(username, password_hash),
+        )
+        .await
+    }
+
+    async fn get_user_by_name(&self, username: &str) -> Result<Option<UserRow>, AppError> {
+        timed("get_user_by_name", self.inner.get_user_by_name(username)).await
+    }
+
+    async fn create_session(
+        &self,
+        token_hash: &str,
+        user_id: Uuid,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), AppError> {
+        timed(
+            "create_session",
+            self.inner.create_session(token_hash, user_id, expires_at),
+        )
+        .await
+    }
+
+    async fn get_session_user(&self, token_hash: &str) -> Result<Option<UserRow>, AppError> {
+        timed("get_session_user", self.inner.get_session_user(token_hash)).await
+    }
+
+    async fn delete_session(&self, token_hash: &str) -> Result<(), AppError> {
+        timed("delete_session", self.inner.delete_session(token_hash)).await
+    }
+
+    async fn list_user_games(
+        &self,
+        user_id: Uuid,
+        filter: &GameFilter,
+        limit: u32,
+        offset: u32,
+    ) -> Result<Vec<GameRow>, AppError> {
+        timed(
+            "list_user_games",
+            self.inner.list_user_games(user_id, filter, limit, offset),
+        )
+        .await
+    }
+
+    async fn count_user_games(&self, user_id: Uuid) -> Result<GameCounts, AppError> {
+        timed("count_user_games", self.inner.count_user_games(user_id)).await
+    }
+
+    async fn get_user_ratings(&self, user_id: Uuid) -> Result<Vec<RatingRow>, AppError> {
+        timed("get_user_ratings", self.inner.get_user_ratings(user_id)).await
+    }
+
+    async fn get_rating_history(&self, user_id: Uuid) -> Result<Vec<RatingHistoryRow>, AppError> {
+        timed("get_rating_history", self.inner.get_rating_history(user_id)).await
+    }
+
+    async fn leaderboard(
+        &self,
+        variant: Variant,
+        time_control: TimeControl,
+        max_deviation: f64,
+        active_since: DateTime<Utc>,
+        limit: u32,
+    ) -> Result<Vec<LeaderboardRow>, AppError> {
+        timed(
+            "leaderboard",
+            self.inner
+                .leaderboard(variant, time_control, max_deviation, active_since, limit),
+        )
+        .await
+    }
+}
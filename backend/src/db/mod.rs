@@ -1,3 +1,7 @@
+pub mod memory;
+pub mod metered;
 pub mod models;
 pub mod pool;
 pub mod queries;
+pub mod repository;
+pub mod sqlite;
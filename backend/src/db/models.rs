@@ -1,8 +1,13 @@
 use chrono::{DateTime, Utc};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use shakmaty::Color;
 use sqlx::Type;
+use subtle::{Choice, ConstantTimeEq};
 use uuid::Uuid;
 
+use crate::chess;
+use crate::rating::Rating;
+
 #[derive(Debug, Type, Serialize, Clone, PartialEq)]
 #[sqlx(type_name = "game_status", rename_all = "snake_case")]
 #[serde(rename_all = "snake_case")]
@@ -28,7 +33,211 @@ impl std::fmt::Display for GameStatus {
     }
 }
 
-#[derive(Debug, sqlx::FromRow)]
+impl std::str::FromStr for GameStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "waiting" => Ok(GameStatus::Waiting),
+            "active" => Ok(GameStatus::Active),
+            "checkmate" => Ok(GameStatus::Checkmate),
+            "stalemate" => Ok(GameStatus::Stalemate),
+            "draw" => Ok(GameStatus::Draw),
+            "resigned" => Ok(GameStatus::Resigned),
+            other => Err(format!("Unknown game status: {other}")),
+        }
+    }
+}
+
+/// The rules a game is played under.
+#[derive(Debug, Type, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[sqlx(type_name = "variant", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum Variant {
+    #[default]
+    Standard,
+}
+
+impl std::fmt::Display for Variant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Variant::Standard => write!(f, "standard"),
+        }
+    }
+}
+
+impl std::str::FromStr for Variant {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "standard" => Ok(Variant::Standard),
+            other => Err(format!("Unknown variant: {other}")),
+        }
+    }
+}
+
+/// How fast a game is, judged from its clock.
+#[derive(Debug, Type, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[sqlx(type_name = "time_control", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum TimeControl {
+    Bullet,
+    Blitz,
+    Rapid,
+    Classical,
+    /// No clock.
+    Correspondence,
+}
+
+impl TimeControl {
+    /// Categorizes by the expected length of a 40-move game, so increments count.
+    pub fn of(clock: Option<Clock>) -> Self {
+        let Some(clock) = clock else {
+            return TimeControl::Correspondence;
+        };
+        match clock.initial_secs + 40 * clock.increment_secs {
+            0..180 => TimeControl::Bullet,
+            180..480 => TimeControl::Blitz,
+            480..1500 => TimeControl::Rapid,
+            _ => TimeControl::Classical,
+        }
+    }
+}
+
+impl std::fmt::Display for TimeControl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TimeControl::Bullet => write!(f, "bullet"),
+            TimeControl::Blitz => write!(f, "blitz"),
+            TimeControl::Rapid => write!(f, "rapid"),
+            TimeControl::Classical => write!(f, "classical"),
+            TimeControl::Correspondence => write!(f, "correspondence"),
+        }
+    }
+}
+
+impl std::str::FromStr for TimeControl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bullet" => Ok(TimeControl::Bullet),
+            "blitz" => Ok(TimeControl::Blitz),
+            "rapid" => Ok(TimeControl::Rapid),
+            "classical" => Ok(TimeControl::Classical),
+            "correspondence" => Ok(TimeControl::Correspondence),
+            other => Err(format!("Unknown time control: {other}")),
+        }
+    }
+}
+
+/// Starting time per player and the time added after each move.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct Clock {
+    pub initial_secs: u32,
+    pub increment_secs: u32,
+}
+
+/// What a new game is set up with.
+#[derive(Debug, Clone, Default)]
+pub struct NewGame {
+    /// Links the white seat to an account.
+    pub white_user: Option<Uuid>,
+    pub variant: Variant,
+    pub clock: Option<Clock>,
+    /// Whether the result counts towards the players' ratings.
+    pub rated: bool,
+    /// Seats black as well, so the game starts active.
+    pub seat_black: bool,
+    /// Links the black seat to an account; only used with `seat_black`.
+    pub black_user: Option<Uuid>,
+    /// A normalized FEN to start from instead of the standard position.
+    pub initial_fen: Option<String>,
+    /// The game this one is a rematch of.
+    pub rematch_of: Option<Uuid>,
+    /// Hides the game from listings and gives it an invite token; only
+    /// holders of the token can take the open seat.
+    pub private: bool,
+    /// When the invite token stops working; never if unset.
+    pub invite_expires_at: Option<DateTime<Utc>>,
+    /// Revokes the invite token once someone has joined with it.
+    pub invite_single_use: bool,
+    /// Keeps the open seat for one account.
+    pub invited_user: Option<Uuid>,
+}
+
+impl NewGame {
+    pub fn time_control(&self) -> TimeControl {
+        TimeControl::of(self.clock)
+    }
+
+    /// The position the game starts in.
+    pub fn start_fen(&self) -> &str {
+        self.initial_fen.as_deref().unwrap_or(chess::START_FEN)
+    }
+}
+
+/// How a game ended for one of its players.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PlayerResult {
+    Win,
+    Loss,
+    Draw,
+}
+
+impl PlayerResult {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PlayerResult::Win => "win",
+            PlayerResult::Loss => "loss",
+            PlayerResult::Draw => "draw",
+        }
+    }
+}
+
+/// Narrows a player's game history; unset fields match everything.
+#[derive(Debug, Clone, Default)]
+pub struct GameFilter {
+    pub result: Option<PlayerResult>,
+    /// The color the player had.
+    pub color: Option<Color>,
+    pub variant: Option<Variant>,
+    pub time_control: Option<TimeControl>,
+}
+
+impl GameFilter {
+    /// Whether `game` is one of `user_id`'s public games and passes the
+    /// filter.
+    pub fn matches(&self, game: &GameRow, user_id: Uuid) -> bool {
+        let Some(seat) = game.seat_of_user(user_id) else {
+            return false;
+        };
+        !game.private
+            && self.color.is_none_or(|color| color == seat)
+            && self
+                .result
+                .is_none_or(|result| game.result_for(seat) == Some(result))
+            && self.variant.is_none_or(|variant| variant == game.variant)
+            && self
+                .time_control
+                .is_none_or(|time_control| time_control == game.time_control)
+    }
+}
+
+/// A player's games by outcome.
+#[derive(Debug, Serialize, Clone, Default, PartialEq, Eq, sqlx::FromRow)]
+pub struct GameCounts {
+    pub total: i64,
+    pub wins: i64,
+    pub losses: i64,
+    pub draws: i64,
+    /// Games still waiting for an opponent or being played.
+    pub ongoing: i64,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
 pub struct GameRow {
     pub id: Uuid,
     pub white_secret: Uuid,
@@ -37,31 +246,241 @@ pub struct GameRow {
     pub moves: Vec<String>,
     pub status: GameStatus,
     pub result: Option<String>,
+    pub version: i64,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    pub white_user_id: Option<Uuid>,
+    pub black_user_id: Option<Uuid>,
+    pub variant: Variant,
+    pub time_control: TimeControl,
+    pub clock_initial_secs: Option<i32>,
+    pub clock_increment_secs: Option<i32>,
+    pub rated: bool,
+    /// Set, rounded, once a rated game has been applied to the players' ratings.
+    pub white_rating_before: Option<i32>,
+    pub white_rating_after: Option<i32>,
+    pub black_rating_before: Option<i32>,
+    pub black_rating_after: Option<i32>,
+    /// The position the game started from; `None` for the standard one.
+    pub initial_fen: Option<String>,
+    /// The game this one is a rematch of.
+    pub rematch_of: Option<Uuid>,
+    /// The rematch that followed this game.
+    pub rematch_id: Option<Uuid>,
+    pub private: bool,
+    /// Needed to join a private game; cleared once a single-use invite is used.
+    pub invite_token: Option<Uuid>,
+    pub invite_expires_at: Option<DateTime<Utc>>,
+    pub invite_single_use: bool,
+    /// The only account that may take the open seat.
+    pub invited_user_id: Option<Uuid>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Clone)]
 pub struct GameResponse {
     pub id: Uuid,
     pub fen: String,
     pub moves: Vec<String>,
     pub status: String,
     pub result: Option<String>,
+    pub version: i64,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
     pub has_black: bool,
+    pub white_user_id: Option<Uuid>,
+    pub black_user_id: Option<Uuid>,
+    pub variant: Variant,
+    pub time_control: TimeControl,
+    pub clock: Option<Clock>,
+    pub rated: bool,
+    /// How the game moved each player's rating; set once a rated game is over.
+    pub ratings: Option<RatingChanges>,
+    /// Absent when the game started from the standard position.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub initial_fen: Option<String>,
+    pub rematch_of: Option<Uuid>,
+    pub rematch_id: Option<Uuid>,
+    pub private: bool,
+    /// The account the open seat is kept for.
+    pub invited_user_id: Option<Uuid>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+pub struct RatingChange {
+    pub before: i32,
+    pub after: i32,
+    pub change: i32,
+}
+
+impl RatingChange {
+    fn new(before: i32, after: i32) -> Self {
+        RatingChange {
+            before,
+            after,
+            change: after - before,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+pub struct RatingChanges {
+    pub white: RatingChange,
+    pub black: RatingChange,
+}
+
+#[derive(Debug, Serialize, Clone)]
 pub struct GameWithSecret {
     #[serde(flatten)]
     pub game: GameResponse,
     pub secret: Uuid,
     pub color: String,
+    /// Only handed to the creator of a private game.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub invite: Option<Invite>,
+}
+
+/// The token that lets another player into a private game.
+#[derive(Debug, Serialize, Clone)]
+pub struct Invite {
+    pub token: Uuid,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub single_use: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MoveResponse {
+    #[serde(flatten)]
+    pub game: GameResponse,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    pub ply: u32,
+    #[serde(rename = "move")]
+    pub mv: String,
+    pub san: String,
+    /// True when the request repeated a move that had already been applied.
+    pub duplicate: bool,
 }
 
 impl GameRow {
+    /// Returns the seat held by the player presenting `secret`, if any.
+    /// Compares in constant time, so response timing doesn't reveal how much
+    /// of a guessed secret was right.
+    pub fn seat_of(&self, secret: Uuid) -> Option<Color> {
+        let guess = secret.as_bytes();
+        let white = guess.ct_eq(self.white_secret.as_bytes());
+        let black = self
+            .black_secret
+            .map_or(Choice::from(0), |black| guess.ct_eq(black.as_bytes()));
+        if white.into() {
+            Some(Color::White)
+        } else if black.into() {
+            Some(Color::Black)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the seat held by a signed-in user, if any.
+    pub fn seat_of_user(&self, user_id: Uuid) -> Option<Color> {
+        if self.white_user_id == Some(user_id) {
+            Some(Color::White)
+        } else if self.black_user_id == Some(user_id) {
+            Some(Color::Black)
+        } else {
+            None
+        }
+    }
+
+    /// How the game went for the player in `seat`; `None` while it's undecided.
+    pub fn result_for(&self, seat: Color) -> Option<PlayerResult> {
+        match self.status {
+            GameStatus::Draw | GameStatus::Stalemate => Some(PlayerResult::Draw),
+            GameStatus::Waiting | GameStatus::Active => None,
+            GameStatus::Checkmate | GameStatus::Resigned => {
+                let winner: Color = self.result.as_deref()?.parse().ok()?;
+                Some(if winner == seat {
+                    PlayerResult::Win
+                } else {
+                    PlayerResult::Loss
+                })
+            }
+        }
+    }
+
+    /// 1 if white won, 0.5 for a draw, 0 if white lost; `None` while undecided.
+    pub fn white_score(&self) -> Option<f64> {
+        Some(match self.result_for(Color::White)? {
+            PlayerResult::Win => 1.0,
+            PlayerResult::Draw => 0.5,
+            PlayerResult::Loss => 0.0,
+        })
+    }
+
+    /// Whether the game is a finished rated game whose result hasn't been
+    /// applied to the players' ratings yet.
+    pub fn awaits_rating(&self) -> bool {
+        self.rated
+            && self.white_rating_after.is_none()
+            && self.white_score().is_some()
+            && self.white_user_id.is_some()
+            && self.black_user_id.is_some()
+            && self.white_user_id != self.black_user_id
+    }
+
+    pub fn rating_changes(&self) -> Option<RatingChanges> {
+        Some(RatingChanges {
+            white: RatingChange::new(self.white_rating_before?, self.white_rating_after?),
+            black: RatingChange::new(self.black_rating_before?, self.black_rating_after?),
+        })
+    }
+
+    pub fn clock(&self) -> Option<Clock> {
+        Some(Clock {
+            initial_secs: self.clock_initial_secs?.try_into().ok()?,
+            increment_secs: self.clock_increment_secs?.try_into().ok()?,
+        })
+    }
+
+    /// A game with the same settings and the players' colors swapped.
+    pub fn rematch(&self) -> NewGame {
+        NewGame {
+            white_user: self.black_user_id,
+            variant: self.variant,
+            clock: self.clock(),
+            rated: self.rated,
+            seat_black: true,
+            black_user: self.white_user_id,
+            initial_fen: self.initial_fen.clone(),
+            rematch_of: Some(self.id),
+            // Both seats are taken, so the rematch needs no invite
+            private: self.private,
+            ..NewGame::default()
+        }
+    }
+
+    /// The invite of a private game, while it can still be used.
+    pub fn invite(&self) -> Option<Invite> {
+        Some(Invite {
+            token: self.invite_token?,
+            expires_at: self.invite_expires_at,
+            single_use: self.invite_single_use,
+        })
+    }
+
+    /// Whether `token` is this game's invite token, compared in constant time.
+    pub fn is_invite_token(&self, token: Uuid) -> bool {
+        self.invite_token
+            .is_some_and(|invite| bool::from(token.as_bytes().ct_eq(invite.as_bytes())))
+    }
+
+    /// The secret of a seat, if it has been taken.
+    pub fn secret_of(&self, seat: Color) -> Option<Uuid> {
+        match seat {
+            Color::White => Some(self.white_secret),
+            Color::Black => self.black_secret,
+        }
+    }
+
     pub fn to_response(&self) -> GameResponse {
         GameResponse {
             id: self.id,
@@ -69,9 +488,22 @@ impl GameRow {
             moves: self.moves.clone(),
             status: self.status.to_string(),
             result: self.result.clone(),
+            version: self.version,
             created_at: self.created_at,
             updated_at: self.updated_at,
             has_black: self.black_secret.is_some(),
+            white_user_id: self.white_user_id,
+            black_user_id: self.black_user_id,
+            variant: self.variant,
+            time_control: self.time_control,
+            clock: self.clock(),
+            rated: self.rated,
+            ratings: self.rating_changes(),
+            initial_fen: self.initial_fen.clone(),
+            rematch_of: self.rematch_of,
+            rematch_id: self.rematch_id,
+            private: self.private,
+            invited_user_id: self.invited_user_id,
         }
     }
 
@@ -80,6 +512,194 @@ impl GameRow {
             game: self.to_response(),
             secret,
             color: color.to_string(),
+            invite: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct UserRow {
+    pub id: Uuid,
+    pub username: String,
+    pub password_hash: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserResponse {
+    pub id: Uuid,
+    pub username: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A player's Glicko-2 rating in one variant and time control.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct RatingRow {
+    pub user_id: Uuid,
+    pub variant: Variant,
+    pub time_control: TimeControl,
+    pub rating: f64,
+    pub deviation: f64,
+    pub volatility: f64,
+    pub games: i32,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RatingResponse {
+    pub variant: Variant,
+    pub time_control: TimeControl,
+    pub rating: i32,
+    pub deviation: i32,
+    pub games: i32,
+    /// Too few games for the rating to be reliable.
+    pub provisional: bool,
+}
+
+impl RatingRow {
+    pub fn glicko(&self) -> Rating {
+        Rating {
+            rating: self.rating,
+            deviation: self.deviation,
+            volatility: self.volatility,
+        }
+    }
+
+    pub fn to_response(&self) -> RatingResponse {
+        RatingResponse {
+            variant: self.variant,
+            time_control: self.time_control,
+            rating: self.glicko().rounded(),
+            deviation: self.deviation.round() as i32,
+            games: self.games,
+            provisional: self.glicko().is_provisional(),
+        }
+    }
+}
+
+/// A player's rating right after one of their rated games.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct RatingHistoryRow {
+    pub game_id: Uuid,
+    pub variant: Variant,
+    pub time_control: TimeControl,
+    pub rating: f64,
+    pub deviation: f64,
+    pub recorded_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RatingPoint {
+    pub game_id: Uuid,
+    pub rating: i32,
+    pub deviation: i32,
+    pub recorded_at: DateTime<Utc>,
+}
+
+/// How a player's rating in one category developed, oldest first.
+#[derive(Debug, Serialize)]
+pub struct RatingSeries {
+    pub variant: Variant,
+    pub time_control: TimeControl,
+    pub points: Vec<RatingPoint>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RatingHistory {
+    pub username: String,
+    pub categories: Vec<RatingSeries>,
+}
+
+impl RatingHistory {
+    /// Groups rows sorted by category and time into one series per category.
+    pub fn from_rows(username: String, rows: &[RatingHistoryRow]) -> Self {
+        let mut categories: Vec<RatingSeries> = Vec::new();
+        for row in rows {
+            let point = RatingPoint {
+                game_id: row.game_id,
+                rating: row.rating.round() as i32,
+                deviation: row.deviation.round() as i32,
+                recorded_at: row.recorded_at,
+            };
+            match categories.last_mut() {
+                Some(series)
+                    if series.variant == row.variant && series.time_control == row.time_control =>
+                {
+                    series.points.push(point)
+                }
+                _ => categories.push(RatingSeries {
+                    variant: row.variant,
+                    time_control: row.time_control,
+                    points: vec![point],
+                }),
+            }
+        }
+        RatingHistory {
+            username,
+            categories,
+        }
+    }
+}
+
+/// A player's standing in one category, as the leaderboard query returns it.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct LeaderboardRow {
+    pub username: String,
+    pub rating: f64,
+    pub deviation: f64,
+    pub games: i32,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LeaderboardEntry {
+    pub rank: u32,
+    pub username: String,
+    pub rating: i32,
+    pub deviation: i32,
+    pub games: i32,
+    /// When the player last finished a rated game in this category.
+    pub last_played_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Leaderboard {
+    pub variant: Variant,
+    pub time_control: TimeControl,
+    pub players: Vec<LeaderboardEntry>,
+}
+
+/// A player's public page.
+#[derive(Debug, Serialize)]
+pub struct UserProfile {
+    pub username: String,
+    pub created_at: DateTime<Utc>,
+    pub games: GameCounts,
+    pub ratings: Vec<RatingResponse>,
+}
+
+/// One page of a game listing.
+#[derive(Debug, Serialize)]
+pub struct GamePage {
+    pub games: Vec<GameResponse>,
+    /// Pass as `offset` to get the next page; absent on the last one.
+    pub next_offset: Option<u32>,
+}
+
+/// Returned on sign-in; `token` is also set as the session cookie.
+#[derive(Debug, Serialize)]
+pub struct SessionResponse {
+    pub user: UserResponse,
+    pub token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl UserRow {
+    pub fn to_response(&self) -> UserResponse {
+        UserResponse {
+            id: self.id,
+            username: self.username.clone(),
+            created_at: self.created_at,
         }
     }
 }
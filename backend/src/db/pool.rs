@@ -1,36 +1,71 @@
 use sqlx::postgres::PgPoolOptions;
-use sqlx::PgPool;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions};
+use sqlx::{PgConnection, PgPool, SqlitePool};
+use std::str::FromStr;
 use std::time::Duration;
 
-pub async fn create_pool(database_url: &str) -> Result<PgPool, sqlx::Error> {
-    let pool = PgPoolOptions::new()
-        .max_connections(5)
-        .acquire_timeout(Duration::from_secs(15))
-        .test_before_acquire(true)
-        .connect(database_url)
-        .await;
+use crate::config::DatabaseConfig;
 
-    match pool {
+pub async fn create_pool(
+    database_url: &str,
+    config: &DatabaseConfig,
+) -> Result<PgPool, sqlx::Error> {
+    let connect = || {
+        PgPoolOptions::new()
+            .max_connections(config.max_connections)
+            .acquire_timeout(Duration::from_secs(config.acquire_timeout_secs))
+            .test_before_acquire(true)
+            .connect(database_url)
+    };
+
+    match connect().await {
         Ok(p) => Ok(p),
-        Err(_) => {
+        Err(e) => {
             // Neon cold-start retry with exponential backoff
-            let delays = [500, 1000, 2000];
-            let mut last_err = None;
-            for delay_ms in delays {
+            let mut last_err = e;
+            for &delay_ms in &config.connect_retry_delays_ms {
                 tracing::info!("Retrying DB connection in {delay_ms}ms...");
                 tokio::time::sleep(Duration::from_millis(delay_ms)).await;
-                match PgPoolOptions::new()
-                    .max_connections(5)
-                    .acquire_timeout(Duration::from_secs(15))
-                    .test_before_acquire(true)
-                    .connect(database_url)
-                    .await
-                {
+                match connect().await {
                     Ok(p) => return Ok(p),
-                    Err(e) => last_err = Some(e),
+                    Err(e) => last_err = e,
                 }
             }
-            Err(last_err.unwrap())
+            Err(last_err)
         }
     }
 }
+
+/// Advisory lock held by the instance serving the lobby.
+const LOBBY_LOCK: i64 = 0x006c_6f62_6279;
+
+/// Takes the lock that lets only one instance sharing the database serve the
+/// lobby. It's held as long as the returned connection is open; `None` means
+/// another instance has it.
+pub async fn claim_lobby(pool: &PgPool) -> Result<Option<PgConnection>, sqlx::Error> {
+    let mut conn = pool.acquire().await?.detach();
+    let claimed: bool = sqlx::query_scalar("SELECT pg_try_advisory_lock($1)")
+        .bind(LOBBY_LOCK)
+        .fetch_one(&mut conn)
+        .await?;
+    Ok(claimed.then_some(conn))
+}
+
+/// Opens (creating it if needed) a SQLite database and brings its schema up to date.
+pub async fn create_sqlite_pool(
+    database_url: &str,
+    config: &DatabaseConfig,
+) -> Result<SqlitePool, sqlx::Error> {
+    let options = SqliteConnectOptions::from_str(database_url)?
+        .create_if_missing(true)
+        .journal_mode(SqliteJournalMode::Wal)
+        .busy_timeout(Duration::from_secs(5));
+    let pool = SqlitePoolOptions::new()
+        .max_connections(config.max_connections)
+        .acquire_timeout(Duration::from_secs(config.acquire_timeout_secs))
+        .connect_with(options)
+        .await?;
+
+    sqlx::migrate!("./migrations_sqlite").run(&pool).await?;
+    Ok(pool)
+}
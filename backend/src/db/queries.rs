@@ -1,13 +1,43 @@
-use sqlx::PgPool;
+use chrono::{DateTime, Utc};
+use sqlx::{PgConnection, PgExecutor, PgPool};
 use uuid::Uuid;
 
-use super::models::{GameRow, GameStatus};
+use super::models::{
+    GameCounts, GameFilter, GameRow, GameStatus, LeaderboardRow, NewGame, PlayerResult,
+    RatingHistoryRow, RatingRow, TimeControl, UserRow, Variant,
+};
+use crate::rating::{self, Rating};
 
-pub async fn create_game(pool: &PgPool) -> Result<GameRow, sqlx::Error> {
+pub async fn create_game(
+    executor: impl PgExecutor<'_>,
+    game: &NewGame,
+) -> Result<GameRow, sqlx::Error> {
     sqlx::query_as::<_, GameRow>(
-        "INSERT INTO games DEFAULT VALUES RETURNING *"
+        "INSERT INTO games \
+         (white_user_id, variant, time_control, clock_initial_secs, clock_increment_secs, rated, \
+          black_secret, black_user_id, status, initial_fen, fen, rematch_of, \
+          private, invite_token, invite_expires_at, invite_single_use, invited_user_id) \
+         VALUES ($1, $2, $3, $4, $5, $6, \
+          CASE WHEN $7 THEN gen_random_uuid() END, $8, \
+          CASE WHEN $7 THEN 'active' ELSE 'waiting' END::game_status, $9, $10, $11, \
+          $12, CASE WHEN $12 AND NOT $7 THEN gen_random_uuid() END, $13, $14, $15) RETURNING *"
     )
-    .fetch_one(pool)
+    .bind(game.white_user)
+    .bind(game.variant)
+    .bind(game.time_control())
+    .bind(game.clock.map(|c| c.initial_secs as i32))
+    .bind(game.clock.map(|c| c.increment_secs as i32))
+    .bind(game.rated)
+    .bind(game.seat_black)
+    .bind(game.black_user.filter(|_| game.seat_black))
+    .bind(game.initial_fen.as_deref())
+    .bind(game.start_fen())
+    .bind(game.rematch_of)
+    .bind(game.private)
+    .bind(game.invite_expires_at)
+    .bind(game.invite_single_use)
+    .bind(game.invited_user)
+    .fetch_one(executor)
     .await
 }
 
@@ -18,42 +48,337 @@ pub async fn get_game(pool: &PgPool, id: Uuid) -> Result<Option<GameRow>, sqlx::
         .await
 }
 
+pub async fn delete_unplayed_game(pool: &PgPool, id: Uuid) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM games WHERE id = $1 AND version = 0")
+        .bind(id)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
 pub async fn list_games(pool: &PgPool) -> Result<Vec<GameRow>, sqlx::Error> {
     sqlx::query_as::<_, GameRow>(
-        "SELECT * FROM games ORDER BY created_at DESC LIMIT 50"
+        "SELECT * FROM games WHERE NOT private ORDER BY created_at DESC LIMIT 50"
     )
     .fetch_all(pool)
     .await
 }
 
-pub async fn join_game(pool: &PgPool, id: Uuid) -> Result<GameRow, sqlx::Error> {
+/// Claims the black seat and spends a single-use invite; returns `None` if the
+/// seat was already taken.
+pub async fn join_game(
+    pool: &PgPool,
+    id: Uuid,
+    black_user: Option<Uuid>,
+) -> Result<Option<GameRow>, sqlx::Error> {
     sqlx::query_as::<_, GameRow>(
-        "UPDATE games SET black_secret = gen_random_uuid(), status = 'active', updated_at = NOW() \
+        "UPDATE games SET black_secret = gen_random_uuid(), black_user_id = $2, \
+         status = 'active', version = version + 1, updated_at = NOW(), \
+         invite_token = CASE WHEN invite_single_use THEN NULL ELSE invite_token END \
          WHERE id = $1 AND black_secret IS NULL \
          RETURNING *"
     )
     .bind(id)
-    .fetch_one(pool)
+    .bind(black_user)
+    .fetch_optional(pool)
     .await
 }
 
+/// Creates the rematch and links it from the game it follows, only if that
+/// game is still at `expected_version` and has no rematch yet.
+pub async fn create_rematch(
+    pool: &PgPool,
+    id: Uuid,
+    expected_version: i64,
+    rematch: &NewGame,
+) -> Result<Option<(GameRow, GameRow)>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let created = create_game(&mut *tx, rematch).await?;
+    let game = sqlx::query_as::<_, GameRow>(
+        "UPDATE games SET rematch_id = $3, version = version + 1, updated_at = NOW() \
+         WHERE id = $1 AND version = $2 AND rematch_id IS NULL RETURNING *"
+    )
+    .bind(id)
+    .bind(expected_version)
+    .bind(created.id)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(game) = game else {
+        tx.rollback().await?;
+        return Ok(None);
+    };
+    tx.commit().await?;
+    Ok(Some((game, created)))
+}
+
+/// Writes the new game state only if the row is still at `expected_version`.
+/// Returns `None` when another writer got there first.
 pub async fn update_game_state(
     pool: &PgPool,
     id: Uuid,
+    expected_version: i64,
     fen: &str,
     moves: &[String],
     status: GameStatus,
     result: Option<&str>,
-) -> Result<GameRow, sqlx::Error> {
-    sqlx::query_as::<_, GameRow>(
-        "UPDATE games SET fen = $2, moves = $3, status = $4, result = $5, updated_at = NOW() \
-         WHERE id = $1 RETURNING *"
+) -> Result<Option<GameRow>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let row = sqlx::query_as::<_, GameRow>(
+        "UPDATE games SET fen = $3, moves = $4, status = $5, result = $6, \
+         version = version + 1, updated_at = NOW() \
+         WHERE id = $1 AND version = $2 RETURNING *"
     )
     .bind(id)
+    .bind(expected_version)
     .bind(fen)
     .bind(moves)
     .bind(status)
     .bind(result)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let row = match row {
+        Some(row) if row.awaits_rating() => Some(rate_game(&mut tx, row).await?),
+        row => row,
+    };
+    tx.commit().await?;
+    Ok(row)
+}
+
+/// Applies a finished rated game to both players' ratings and records the
+/// change on the game.
+async fn rate_game(tx: &mut PgConnection, game: GameRow) -> Result<GameRow, sqlx::Error> {
+    let (Some(white_id), Some(black_id), Some(score)) =
+        (game.white_user_id, game.black_user_id, game.white_score())
+    else {
+        return Ok(game);
+    };
+    let default = Rating::default();
+    sqlx::query(
+        "INSERT INTO ratings (user_id, variant, time_control, rating, deviation, volatility) \
+         VALUES ($1, $3, $4, $5, $6, $7), ($2, $3, $4, $5, $6, $7) ON CONFLICT DO NOTHING"
+    )
+    .bind(white_id)
+    .bind(black_id)
+    .bind(game.variant)
+    .bind(game.time_control)
+    .bind(default.rating)
+    .bind(default.deviation)
+    .bind(default.volatility)
+    .execute(&mut *tx)
+    .await?;
+    // Locked in a fixed order, so two games finishing at once can't deadlock
+    let rows = sqlx::query_as::<_, RatingRow>(
+        "SELECT * FROM ratings WHERE user_id IN ($1, $2) AND variant = $3 AND time_control = $4 \
+         ORDER BY user_id FOR UPDATE"
+    )
+    .bind(white_id)
+    .bind(black_id)
+    .bind(game.variant)
+    .bind(game.time_control)
+    .fetch_all(&mut *tx)
+    .await?;
+    let current = |id| rows.iter().find(|r| r.user_id == id).map(RatingRow::glicko).unwrap_or_default();
+    let (white_before, black_before) = (current(white_id), current(black_id));
+    let (white_after, black_after) = rating::rate_game(white_before, black_before, score);
+
+    for (user_id, after) in [(white_id, white_after), (black_id, black_after)] {
+        sqlx::query(
+            "UPDATE ratings SET rating = $4, deviation = $5, volatility = $6, \
+             games = games + 1, updated_at = NOW() \
+             WHERE user_id = $1 AND variant = $2 AND time_control = $3"
+        )
+        .bind(user_id)
+        .bind(game.variant)
+        .bind(game.time_control)
+        .bind(after.rating)
+        .bind(after.deviation)
+        .bind(after.volatility)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            "INSERT INTO rating_history \
+             (user_id, game_id, variant, time_control, rating, deviation) \
+             VALUES ($1, $2, $3, $4, $5, $6)"
+        )
+        .bind(user_id)
+        .bind(game.id)
+        .bind(game.variant)
+        .bind(game.time_control)
+        .bind(after.rating)
+        .bind(after.deviation)
+        .execute(&mut *tx)
+        .await?;
+    }
+    sqlx::query_as::<_, GameRow>(
+        "UPDATE games SET white_rating_before = $2, white_rating_after = $3, \
+         black_rating_before = $4, black_rating_after = $5 \
+         WHERE id = $1 RETURNING *"
+    )
+    .bind(game.id)
+    .bind(white_before.rounded())
+    .bind(white_after.rounded())
+    .bind(black_before.rounded())
+    .bind(black_after.rounded())
+    .fetch_one(&mut *tx)
+    .await
+}
+
+/// Registers an account; returns `None` if the name is taken, ignoring case.
+pub async fn create_user(
+    pool: &PgPool,
+    username: &str,
+    password_hash: &str,
+) -> Result<Option<UserRow>, sqlx::Error> {
+    sqlx::query_as::<_, UserRow>(
+        "INSERT INTO users (username, password_hash) VALUES ($1, $2) \
+         ON CONFLICT DO NOTHING RETURNING *"
+    )
+    .bind(username)
+    .bind(password_hash)
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn get_user_by_name(pool: &PgPool, username: &str) -> Result<Option<UserRow>, sqlx::Error> {
+    sqlx::query_as::<_, UserRow>("SELECT * FROM users WHERE LOWER(username) = LOWER($1)")
+        .bind(username)
+        .fetch_optional(pool)
+        .await
+}
+
+pub async fn create_session(
+    pool: &PgPool,
+    token_hash: &str,
+    user_id: Uuid,
+    expires_at: DateTime<Utc>,
+) -> Result<(), sqlx::Error> {
+    // Sign-ins are a good moment to drop the user's expired sessions
+    sqlx::query("DELETE FROM sessions WHERE user_id = $1 AND expires_at <= NOW()")
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+    sqlx::query("INSERT INTO sessions (token_hash, user_id, expires_at) VALUES ($1, $2, $3)")
+        .bind(token_hash)
+        .bind(user_id)
+        .bind(expires_at)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn get_session_user(pool: &PgPool, token_hash: &str) -> Result<Option<UserRow>, sqlx::Error> {
+    sqlx::query_as::<_, UserRow>(
+        "SELECT users.* FROM sessions JOIN users ON users.id = sessions.user_id \
+         WHERE sessions.token_hash = $1 AND sessions.expires_at > NOW()"
+    )
+    .bind(token_hash)
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn delete_session(pool: &PgPool, token_hash: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM sessions WHERE token_hash = $1")
+        .bind(token_hash)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// A page of a user's games, newest first. Filters bound as NULL match everything.
+pub async fn list_user_games(
+    pool: &PgPool,
+    user_id: Uuid,
+    filter: &GameFilter,
+    limit: u32,
+    offset: u32,
+) -> Result<Vec<GameRow>, sqlx::Error> {
+    sqlx::query_as::<_, GameRow>(
+        "SELECT * FROM games \
+         WHERE (white_user_id = $1 OR black_user_id = $1) \
+         AND NOT private \
+         AND ($2::text IS NULL \
+              OR ($2 = 'white' AND white_user_id = $1) \
+              OR ($2 = 'black' AND black_user_id = $1)) \
+         AND ($3::text IS NULL \
+              OR ($3 = 'draw' AND status IN ('draw', 'stalemate')) \
+              OR ($3 = 'win' AND status IN ('checkmate', 'resigned') \
+                  AND result = CASE WHEN white_user_id = $1 THEN 'white' ELSE 'black' END) \
+              OR ($3 = 'loss' AND status IN ('checkmate', 'resigned') \
+                  AND result = CASE WHEN white_user_id = $1 THEN 'black' ELSE 'white' END)) \
+         AND ($4::variant IS NULL OR variant = $4) \
+         AND ($5::time_control IS NULL OR time_control = $5) \
+         ORDER BY created_at DESC LIMIT $6 OFFSET $7"
+    )
+    .bind(user_id)
+    .bind(filter.color.map(|c| c.to_string()))
+    .bind(filter.result.map(PlayerResult::as_str))
+    .bind(filter.variant)
+    .bind(filter.time_control)
+    .bind(i64::from(limit))
+    .bind(i64::from(offset))
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn count_user_games(pool: &PgPool, user_id: Uuid) -> Result<GameCounts, sqlx::Error> {
+    sqlx::query_as::<_, GameCounts>(
+        "SELECT COUNT(*) AS total, \
+         COUNT(*) FILTER (WHERE status IN ('checkmate', 'resigned') \
+             AND result = CASE WHEN white_user_id = $1 THEN 'white' ELSE 'black' END) AS wins, \
+         COUNT(*) FILTER (WHERE status IN ('checkmate', 'resigned') \
+             AND result = CASE WHEN white_user_id = $1 THEN 'black' ELSE 'white' END) AS losses, \
+         COUNT(*) FILTER (WHERE status IN ('draw', 'stalemate')) AS draws, \
+         COUNT(*) FILTER (WHERE status IN ('waiting', 'active')) AS ongoing \
+         FROM games WHERE (white_user_id = $1 OR black_user_id = $1) AND NOT private"
+    )
+    .bind(user_id)
     .fetch_one(pool)
     .await
 }
+
+pub async fn get_user_ratings(pool: &PgPool, user_id: Uuid) -> Result<Vec<RatingRow>, sqlx::Error> {
+    sqlx::query_as::<_, RatingRow>(
+        "SELECT * FROM ratings WHERE user_id = $1 ORDER BY variant, time_control"
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn get_rating_history(
+    pool: &PgPool,
+    user_id: Uuid,
+) -> Result<Vec<RatingHistoryRow>, sqlx::Error> {
+    sqlx::query_as::<_, RatingHistoryRow>(
+        "SELECT * FROM rating_history WHERE user_id = $1 \
+         ORDER BY variant, time_control, recorded_at"
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await
+}
+
+/// Walks `ratings_leaderboard_idx` from the top; `games` isn't touched.
+pub async fn leaderboard(
+    pool: &PgPool,
+    variant: Variant,
+    time_control: TimeControl,
+    max_deviation: f64,
+    active_since: DateTime<Utc>,
+    limit: u32,
+) -> Result<Vec<LeaderboardRow>, sqlx::Error> {
+    sqlx::query_as::<_, LeaderboardRow>(
+        "SELECT users.username, ratings.rating, ratings.deviation, ratings.games, ratings.updated_at \
+         FROM ratings JOIN users ON users.id = ratings.user_id \
+         WHERE ratings.variant = $1 AND ratings.time_control = $2 \
+         AND ratings.deviation <= $3 AND ratings.updated_at >= $4 \
+         ORDER BY ratings.rating DESC LIMIT $5"
+    )
+    .bind(variant)
+    .bind(time_control)
+    .bind(max_deviation)
+    .bind(active_since)
+    .bind(i64::from(limit))
+    .fetch_all(pool)
+    .await
+}
@@ -0,0 +1,256 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::models::{
+    GameCounts, GameFilter, GameRow, GameStatus, LeaderboardRow, NewGame, RatingHistoryRow,
+    RatingRow, TimeControl, UserRow, Variant,
+};
+use super::queries;
+use crate::error::AppError;
+
+/// Storage for games and accounts. Version checks happen here, so every backend
+/// gives the same compare-and-swap guarantees.
+#[async_trait]
+pub trait GameRepository: Send + Sync {
+    /// Checks that the storage is reachable.
+    async fn ping(&self) -> Result<(), AppError>;
+
+    /// Creates a game with the creator as white.
+    async fn create_game(&self, game: &NewGame) -> Result<GameRow, AppError>;
+
+    async fn get_game(&self, id: Uuid) -> Result<Option<GameRow>, AppError>;
+
+    /// Deletes a game nothing has happened in yet, such as one its players
+    /// couldn't be told about. Returns whether it was deleted.
+    async fn delete_unplayed_game(&self, id: Uuid) -> Result<bool, AppError>;
+
+    /// The most recently created games, newest first.
+    async fn list_games(&self) -> Result<Vec<GameRow>, AppError>;
+
+    /// Claims the black seat; returns `None` if it was already taken.
+    async fn join_game(
+        &self,
+        id: Uuid,
+        black_user: Option<Uuid>,
+    ) -> Result<Option<GameRow>, AppError>;
+
+    /// Creates `rematch` and links it from the game it follows, only if that game
+    /// is still at `expected_version` and has no rematch yet. Returns the updated
+    /// game and the rematch, or `None` when either check fails.
+    async fn create_rematch(
+        &self,
+        id: Uuid,
+        expected_version: i64,
+        rematch: &NewGame,
+    ) -> Result<Option<(GameRow, GameRow)>, AppError>;
+
+    /// Writes the new game state only if the game is still at `expected_version`.
+    /// Returns `None` when another writer got there first. Finishing a rated game
+    /// updates both players' ratings in the same transaction.
+    async fn update_game_state(
+        &self,
+        id: Uuid,
+        expected_version: i64,
+        fen: &str,
+        moves: &[String],
+        status: GameStatus,
+        result: Option<&str>,
+    ) -> Result<Option<GameRow>, AppError>;
+
+    /// Registers an account; returns `None` if the name is taken, ignoring case.
+    async fn create_user(
+        &self,
+        username: &str,
+        password_hash: &str,
+    ) -> Result<Option<UserRow>, AppError>;
+
+    /// Looks an account up by name, ignoring case.
+    async fn get_user_by_name(&self, username: &str) -> Result<Option<UserRow>, AppError>;
+
+    async fn create_session(
+        &self,
+        token_hash: &str,
+        user_id: Uuid,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), AppError>;
+
+    /// The account a session belongs to, if the session exists and hasn't expired.
+    async fn get_session_user(&self, token_hash: &str) -> Result<Option<UserRow>, AppError>;
+
+    async fn delete_session(&self, token_hash: &str) -> Result<(), AppError>;
+
+    /// A page of the public games a user has a seat in, newest first.
+    async fn list_user_games(
+        &self,
+        user_id: Uuid,
+        filter: &GameFilter,
+        limit: u32,
+        offset: u32,
+    ) -> Result<Vec<GameRow>, AppError>;
+
+    /// Counts the same public games `list_user_games` lists.
+    async fn count_user_games(&self, user_id: Uuid) -> Result<GameCounts, AppError>;
+
+    /// The user's ratings in every category they have played rated games in.
+    async fn get_user_ratings(&self, user_id: Uuid) -> Result<Vec<RatingRow>, AppError>;
+
+    /// The user's rating after each of their rated games, by category, oldest first.
+    async fn get_rating_history(&self, user_id: Uuid) -> Result<Vec<RatingHistoryRow>, AppError>;
+
+    /// The highest rated players in a category whose deviation is at most
+    /// `max_deviation` and who have played it since `active_since`.
+    async fn leaderboard(
+        &self,
+        variant: Variant,
+        time_control: TimeControl,
+        max_deviation: f64,
+        active_since: DateTime<Utc>,
+        limit: u32,
+    ) -> Result<Vec<LeaderboardRow>, AppError>;
+}
+
+pub struct PgGameRepository {
+    pool: PgPool,
+}
+
+impl PgGameRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl GameRepository for PgGameRepository {
+    async fn ping(&self) -> Result<(), AppError> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn create_game(&self, game: &NewGame) -> Result<GameRow, AppError> {
+        Ok(queries::create_game(&self.pool, game).await?)
+    }
+
+    async fn delete_unplayed_game(&self, id: Uuid) -> Result<bool, AppError> {
+        Ok(queries::delete_unplayed_game(&self.pool, id).await?)
+    }
+
+    async fn get_game(&self, id: Uuid) -> Result<Option<GameRow>, AppError> {
+        Ok(queries::get_game(&self.pool, id).await?)
+    }
+
+    async fn list_games(&self) -> Result<Vec<GameRow>, AppError> {
+        Ok(queries::list_games(&self.pool).await?)
+    }
+
+    async fn join_game(
+        &self,
+        id: Uuid,
+        black_user: Option<Uuid>,
+    ) -> Result<Option<GameRow>, AppError> {
+        Ok(queries::join_game(&self.pool, id, black_user).await?)
+    }
+
+    async fn create_rematch(
+        &self,
+        id: Uuid,
+        expected_version: i64,
+        rematch: &NewGame,
+    ) -> Result<Option<(GameRow, GameRow)>, AppError> {
+        Ok(queries::create_rematch(&self.pool, id, expected_version, rematch).await?)
+    }
+
+    async fn update_game_state(
+        &self,
+        id: Uuid,
+        expected_version: i64,
+        fen: &str,
+        moves: &[String],
+        status: GameStatus,
+        result: Option<&str>,
+    ) -> Result<Option<GameRow>, AppError> {
+        Ok(
+            queries::update_game_state(
+                &self.pool,
+                id,
+                expected_version,
+                fen,
+                moves,
+                status,
+                result,
+            )
+            .await?,
+        )
+    }
+
+    async fn create_user(
+        &self,
+        username: &str,
+        password_hash: &str,
+    ) -> Result<Option<UserRow>, AppError> {
+        Ok(queries::create_user(&self.pool, username, password_hash).await?)
+    }
+
+    async fn get_user_by_name(&self, username: &str) -> Result<Option<UserRow>, AppError> {
+        Ok(queries::get_user_by_name(&self.pool, username).await?)
+    }
+
+    async fn create_session(
+        &self,
+        token_hash: &str,
+        user_id: Uuid,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), AppError> {
+        Ok(queries::create_session(&self.pool, token_hash, user_id, expires_at).await?)
+    }
+
+    async fn get_session_user(&self, token_hash: &str) -> Result<Option<UserRow>, AppError> {
+        Ok(queries::get_session_user(&self.pool, token_hash).await?)
+    }
+
+    async fn delete_session(&self, token_hash: &str) -> Result<(), AppError> {
+        Ok(queries::delete_session(&self.pool, token_hash).await?)
+    }
+
+    async fn list_user_games(
+        &self,
+        user_id: Uuid,
+        filter: &GameFilter,
+        limit: u32,
+        offset: u32,
+    ) -> Result<Vec<GameRow>, AppError> {
+        Ok(queries::list_user_games(&self.pool, user_id, filter, limit, offset).await?)
+    }
+
+    async fn count_user_games(&self, user_id: Uuid) -> Result<GameCounts, AppError> {
+        Ok(queries::count_user_games(&self.pool, user_id).await?)
+    }
+
+    async fn get_user_ratings(&self, user_id: Uuid) -> Result<Vec<RatingRow>, AppError> {
+        Ok(queries::get_user_ratings(&self.pool, user_id).await?)
+    }
+
+    async fn get_rating_history(&self, user_id: Uuid) -> Result<Vec<RatingHistoryRow>, AppError> {
+        Ok(queries::get_rating_history(&self.pool, user_id).await?)
+    }
+
+    async fn leaderboard(
+        &self,
+        variant: Variant,
+        time_control: TimeControl,
+        max_deviation: f64,
+        active_since: DateTime<Utc>,
+        limit: u32,
+    ) -> Result<Vec<LeaderboardRow>, AppError> {
+        Ok(queries::leaderboard(
+            &self.pool,
+            variant,
+            time_control,
+            max_deviation,
+            active_since,
+            limit,
+        )
+        .await?)
+    }
+}
@@ -0,0 +1,604 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::types::Json;
+use sqlx::{SqliteConnection, SqliteExecutor, SqlitePool};
+use uuid::fmt::Hyphenated;
+use uuid::Uuid;
+
+use super::models::{
+    GameCounts, GameFilter, GameRow, GameStatus, LeaderboardRow, NewGame, PlayerResult,
+    RatingHistoryRow, RatingRow, TimeControl, UserRow, Variant,
+};
+use super::repository::GameRepository;
+use crate::error::AppError;
+use crate::rating;
+
+/// A `games` row as SQLite stores it.
+#[derive(sqlx::FromRow)]
+struct SqliteGameRow {
+    id: Hyphenated,
+    white_secret: Hyphenated,
+    black_secret: Option<Hyphenated>,
+    fen: String,
+    moves: Json<Vec<String>>,
+    status: String,
+    result: Option<String>,
+    version: i64,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    white_user_id: Option<Hyphenated>,
+    black_user_id: Option<Hyphenated>,
+    variant: String,
+    time_control: String,
+    clock_initial_secs: Option<i32>,
+    clock_increment_secs: Option<i32>,
+    rated: bool,
+    white_rating_before: Option<i32>,
+    white_rating_after: Option<i32>,
+    black_rating_before: Option<i32>,
+    black_rating_after: Option<i32>,
+    initial_fen: Option<String>,
+    rematch_of: Option<Hyphenated>,
+    rematch_id: Option<Hyphenated>,
+    private: bool,
+    invite_token: Option<Hyphenated>,
+    invite_expires_at: Option<DateTime<Utc>>,
+    invite_single_use: bool,
+    invited_user_id: Option<Hyphenated>,
+}
+
+impl TryFrom<SqliteGameRow> for GameRow {
+    type Error = AppError;
+
+    fn try_from(row: SqliteGameRow) -> Result<Self, Self::Error> {
+        Ok(GameRow {
+            id: row.id.into_uuid(),
+            white_secret: row.white_secret.into_uuid(),
+            black_secret: row.black_secret.map(Hyphenated::into_uuid),
+            fen: row.fen,
+            moves: row.moves.0,
+            status: row.status.parse().map_err(AppError::Internal)?,
+            result: row.result,
+            version: row.version,
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+            white_user_id: row.white_user_id.map(Hyphenated::into_uuid),
+            black_user_id: row.black_user_id.map(Hyphenated::into_uuid),
+            variant: row.variant.parse().map_err(AppError::Internal)?,
+            time_control: row.time_control.parse().map_err(AppError::Internal)?,
+            clock_initial_secs: row.clock_initial_secs,
+            clock_increment_secs: row.clock_increment_secs,
+            rated: row.rated,
+            white_rating_before: row.white_rating_before,
+            white_rating_after: row.white_rating_after,
+            black_rating_before: row.black_rating_before,
+            black_rating_after: row.black_rating_after,
+            initial_fen: row.initial_fen,
+            rematch_of: row.rematch_of.map(Hyphenated::into_uuid),
+            rematch_id: row.rematch_id.map(Hyphenated::into_uuid),
+            private: row.private,
+            invite_token: row.invite_token.map(Hyphenated::into_uuid),
+            invite_expires_at: row.invite_expires_at,
+            invite_single_use: row.invite_single_use,
+            invited_user_id: row.invited_user_id.map(Hyphenated::into_uuid),
+        })
+    }
+}
+
+fn into_game(row: Option<SqliteGameRow>) -> Result<Option<GameRow>, AppError> {
+    row.map(GameRow::try_from).transpose()
+}
+
+/// A `users` row as SQLite stores it.
+#[derive(sqlx::FromRow)]
+struct SqliteUserRow {
+    id: Hyphenated,
+    username: String,
+    password_hash: String,
+    created_at: DateTime<Utc>,
+}
+
+impl From<SqliteUserRow> for UserRow {
+    fn from(row: SqliteUserRow) -> Self {
+        UserRow {
+            id: row.id.into_uuid(),
+            username: row.username,
+            password_hash: row.password_hash,
+            created_at: row.created_at,
+        }
+    }
+}
+
+/// A `ratings` row as SQLite stores it.
+#[derive(sqlx::FromRow)]
+struct SqliteRatingRow {
+    user_id: Hyphenated,
+    variant: String,
+    time_control: String,
+    rating: f64,
+    deviation: f64,
+    volatility: f64,
+    games: i32,
+    updated_at: DateTime<Utc>,
+}
+
+impl TryFrom<SqliteRatingRow> for RatingRow {
+    type Error = AppError;
+
+    fn try_from(row: SqliteRatingRow) -> Result<Self, Self::Error> {
+        Ok(RatingRow {
+            user_id: row.user_id.into_uuid(),
+            variant: row.variant.parse().map_err(AppError::Internal)?,
+            time_control: row.time_control.parse().map_err(AppError::Internal)?,
+            rating: row.rating,
+            deviation: row.deviation,
+            volatility: row.volatility,
+            games: row.games,
+            updated_at: row.updated_at,
+        })
+    }
+}
+
+/// A `rating_history` row as SQLite stores it.
+#[derive(sqlx::FromRow)]
+struct SqliteRatingHistoryRow {
+    game_id: Hyphenated,
+    variant: String,
+    time_control: String,
+    rating: f64,
+    deviation: f64,
+    recorded_at: DateTime<Utc>,
+}
+
+impl TryFrom<SqliteRatingHistoryRow> for RatingHistoryRow {
+    type Error = AppError;
+
+    fn try_from(row: SqliteRatingHistoryRow) -> Result<Self, Self::Error> {
+        Ok(RatingHistoryRow {
+            game_id: row.game_id.into_uuid(),
+            variant: row.variant.parse().map_err(AppError::Internal)?,
+            time_control: row.time_control.parse().map_err(AppError::Internal)?,
+            rating: row.rating,
+            deviation: row.deviation,
+            recorded_at: row.recorded_at,
+        })
+    }
+}
+
+/// Applies a finished rated game to both players' ratings and records the
+/// change on the game. SQLite holds the write lock for the whole transaction,
+/// so the ratings can't change underneath.
+async fn rate_game(tx: &mut SqliteConnection, game: GameRow) -> Result<GameRow, AppError> {
+    let (Some(white_id), Some(black_id), Some(score)) =
+        (game.white_user_id, game.black_user_id, game.white_score())
+    else {
+        return Ok(game);
+    };
+    let (variant, time_control) = (game.variant.to_string(), game.time_control.to_string());
+    let rows = sqlx::query_as::<_, SqliteRatingRow>(
+        "SELECT * FROM ratings WHERE user_id IN (?1, ?2) AND variant = ?3 AND time_control = ?4",
+    )
+    .bind(white_id.hyphenated())
+    .bind(black_id.hyphenated())
+    .bind(&variant)
+    .bind(&time_control)
+    .fetch_all(&mut *tx)
+    .await?
+    .into_iter()
+    .map(RatingRow::try_from)
+    .collect::<Result<Vec<_>, _>>()?;
+    let current = |id| {
+        rows.iter()
+            .find(|r| r.user_id == id)
+            .map(RatingRow::glicko)
+            .unwrap_or_default()
+    };
+    let (white_before, black_before) = (current(white_id), current(black_id));
+    let (white_after, black_after) = rating::rate_game(white_before, black_before, score);
+
+    let now = Utc::now();
+    for (user_id, after) in [(white_id, white_after), (black_id, black_after)] {
+        sqlx::query(
+            "INSERT INTO ratings \
+             (user_id, variant, time_control, rating, deviation, volatility, games, updated_at) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, 1, ?7) \
+             ON CONFLICT (user_id, variant, time_control) DO UPDATE SET \
+             rating = excluded.rating, deviation = excluded.deviation, \
+             volatility = excluded.volatility, games = games + 1, updated_at = excluded.updated_at",
+        )
+        .bind(user_id.hyphenated())
+        .bind(&variant)
+        .bind(&time_control)
+        .bind(after.rating)
+        .bind(after.deviation)
+        .bind(after.volatility)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            "INSERT INTO rating_history \
+             (user_id, game_id, variant, time_control, rating, deviation, recorded_at) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        )
+        .bind(user_id.hyphenated())
+        .bind(game.id.hyphenated())
+        .bind(&variant)
+        .bind(&time_control)
+        .bind(after.rating)
+        .bind(after.deviation)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+    }
+    sqlx::query_as::<_, SqliteGameRow>(
+        "UPDATE games SET white_rating_before = ?2, white_rating_after = ?3, \
+         black_rating_before = ?4, black_rating_after = ?5 \
+         WHERE id = ?1 RETURNING *",
+    )
+    .bind(game.id.hyphenated())
+    .bind(white_before.rounded())
+    .bind(white_after.rounded())
+    .bind(black_before.rounded())
+    .bind(black_after.rounded())
+    .fetch_one(&mut *tx)
+    .await?
+    .try_into()
+}
+
+/// Inserts a new game.
+async fn insert_game(
+    executor: impl SqliteExecutor<'_>,
+    game: &NewGame,
+) -> Result<GameRow, AppError> {
+    let now = Utc::now();
+    sqlx::query_as::<_, SqliteGameRow>(
+        "INSERT INTO games (id, white_secret, white_user_id, variant, time_control, \
+         clock_initial_secs, clock_increment_secs, rated, created_at, updated_at, \
+         black_secret, black_user_id, status, initial_fen, fen, rematch_of, private, \
+         invite_token, invite_expires_at, invite_single_use, invited_user_id) \
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, \
+         ?17, ?18, ?19, ?20) \
+         RETURNING *",
+    )
+    .bind(Uuid::new_v4().hyphenated())
+    .bind(Uuid::new_v4().hyphenated())
+    .bind(game.white_user.map(Uuid::hyphenated))
+    .bind(game.variant.to_string())
+    .bind(game.time_control().to_string())
+    .bind(game.clock.map(|c| c.initial_secs as i32))
+    .bind(game.clock.map(|c| c.increment_secs as i32))
+    .bind(game.rated)
+    .bind(now)
+    .bind(game.seat_black.then(|| Uuid::new_v4().hyphenated()))
+    .bind(
+        game.black_user
+            .filter(|_| game.seat_black)
+            .map(Uuid::hyphenated),
+    )
+    .bind(
+        if game.seat_black {
+            GameStatus::Active
+        } else {
+            GameStatus::Waiting
+        }
+        .to_string(),
+    )
+    .bind(game.initial_fen.as_deref())
+    .bind(game.start_fen())
+    .bind(game.rematch_of.map(Uuid::hyphenated))
+    .bind(game.private)
+    .bind((game.private && !game.seat_black).then(|| Uuid::new_v4().hyphenated()))
+    .bind(game.invite_expires_at)
+    .bind(game.invite_single_use)
+    .bind(game.invited_user.map(Uuid::hyphenated))
+    .fetch_one(executor)
+    .await?
+    .try_into()
+}
+
+pub struct SqliteGameRepository {
+    pool: SqlitePool,
+}
+
+impl SqliteGameRepository {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl GameRepository for SqliteGameRepository {
+    async fn ping(&self) -> Result<(), AppError> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn create_game(&self, game: &NewGame) -> Result<GameRow, AppError> {
+        insert_game(&self.pool, game).await
+    }
+
+    async fn get_game(&self, id: Uuid) -> Result<Option<GameRow>, AppError> {
+        let row = sqlx::query_as::<_, SqliteGameRow>("SELECT * FROM games WHERE id = ?1")
+            .bind(id.hyphenated())
+            .fetch_optional(&self.pool)
+            .await?;
+        into_game(row)
+    }
+
+    async fn delete_unplayed_game(&self, id: Uuid) -> Result<bool, AppError> {
+        let result = sqlx::query("DELETE FROM games WHERE id = ?1 AND version = 0")
+            .bind(id.hyphenated())
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn list_games(&self) -> Result<Vec<GameRow>, AppError> {
+        sqlx::query_as::<_, SqliteGameRow>(
+            "SELECT * FROM games WHERE NOT private ORDER BY created_at DESC LIMIT 50",
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(GameRow::try_from)
+        .collect()
+    }
+
+    async fn join_game(
+        &self,
+        id: Uuid,
+        black_user: Option<Uuid>,
+    ) -> Result<Option<GameRow>, AppError> {
+        let row = sqlx::query_as::<_, SqliteGameRow>(
+            "UPDATE games SET black_secret = ?2, black_user_id = ?3, status = 'active', \
+             version = version + 1, updated_at = ?4, \
+             invite_token = CASE WHEN invite_single_use THEN NULL ELSE invite_token END \
+             WHERE id = ?1 AND black_secret IS NULL \
+             RETURNING *",
+        )
+        .bind(id.hyphenated())
+        .bind(Uuid::new_v4().hyphenated())
+        .bind(black_user.map(Uuid::hyphenated))
+        .bind(Utc::now())
+        .fetch_optional(&self.pool)
+        .await?;
+        into_game(row)
+    }
+
+    async fn create_rematch(
+        &self,
+        id: Uuid,
+        expected_version: i64,
+        rematch: &NewGame,
+    ) -> Result<Option<(GameRow, GameRow)>, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let created = insert_game(&mut *tx, rematch).await?;
+        let game = sqlx::query_as::<_, SqliteGameRow>(
+            "UPDATE games SET rematch_id = ?3, version = version + 1, updated_at = ?4 \
+             WHERE id = ?1 AND version = ?2 AND rematch_id IS NULL RETURNING *",
+        )
+        .bind(id.hyphenated())
+        .bind(expected_version)
+        .bind(created.id.hyphenated())
+        .bind(Utc::now())
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(game) = game else {
+            tx.rollback().await?;
+            return Ok(None);
+        };
+        tx.commit().await?;
+        Ok(Some((game.try_into()?, created)))
+    }
+
+    async fn update_game_state(
+        &self,
+        id: Uuid,
+        expected_version: i64,
+        fen: &str,
+        moves: &[String],
+        status: GameStatus,
+        result: Option<&str>,
+    ) -> Result<Option<GameRow>, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let row = sqlx::query_as::<_, SqliteGameRow>(
+            "UPDATE games SET fen = ?3, moves = ?4, status = ?5, result = ?6, \
+             version = version + 1, updated_at = ?7 \
+             WHERE id = ?1 AND version = ?2 RETURNING *",
+        )
+        .bind(id.hyphenated())
+        .bind(expected_version)
+        .bind(fen)
+        .bind(Json(moves))
+        .bind(status.to_string())
+        .bind(result)
+        .bind(Utc::now())
+        .fetch_optional(&mut *tx)
+        .await?;
+        let row = match into_game(row)? {
+            Some(row) if row.awaits_rating() => Some(rate_game(&mut tx, row).await?),
+            row => row,
+        };
+        tx.commit().await?;
+        Ok(row)
+    }
+
+    async fn create_user(
+        &self,
+        username: &str,
+        password_hash: &str,
+    ) -> Result<Option<UserRow>, AppError> {
+        let row = sqlx::query_as::<_, SqliteUserRow>(
+            "INSERT INTO users (id, username, password_hash, created_at) \
+             VALUES (?1, ?2, ?3, ?4) ON CONFLICT DO NOTHING RETURNING *",
+        )
+        .bind(Uuid::new_v4().hyphenated())
+        .bind(username)
+        .bind(password_hash)
+        .bind(Utc::now())
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(UserRow::from))
+    }
+
+    async fn get_user_by_name(&self, username: &str) -> Result<Option<UserRow>, AppError> {
+        // The column is COLLATE NOCASE, so this ignores case
+        let row = sqlx::query_as::<_, SqliteUserRow>("SELECT * FROM users WHERE username = ?1")
+            .bind(username)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(UserRow::from))
+    }
+
+    async fn create_session(
+        &self,
+        token_hash: &str,
+        user_id: Uuid,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), AppError> {
+        let now = Utc::now();
+        // Sign-ins are a good moment to drop the user's expired sessions
+        sqlx::query("DELETE FROM sessions WHERE user_id = ?1 AND expires_at <= ?2")
+            .bind(user_id.hyphenated())
+            .bind(now)
+            .execute(&self.pool)
+            .await?;
+        sqlx::query(
+            "INSERT INTO sessions (token_hash, user_id, created_at, expires_at) \
+             VALUES (?1, ?2, ?3, ?4)",
+        )
+        .bind(token_hash)
+        .bind(user_id.hyphenated())
+        .bind(now)
+        .bind(expires_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_session_user(&self, token_hash: &str) -> Result<Option<UserRow>, AppError> {
+        let row = sqlx::query_as::<_, SqliteUserRow>(
+            "SELECT users.* FROM sessions JOIN users ON users.id = sessions.user_id \
+             WHERE sessions.token_hash = ?1 AND sessions.expires_at > ?2",
+        )
+        .bind(token_hash)
+        .bind(Utc::now())
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(UserRow::from))
+    }
+
+    async fn delete_session(&self, token_hash: &str) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM sessions WHERE token_hash = ?1")
+            .bind(token_hash)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn list_user_games(
+        &self,
+        user_id: Uuid,
+        filter: &GameFilter,
+        limit: u32,
+        offset: u32,
+    ) -> Result<Vec<GameRow>, AppError> {
+        // Filters bound as NULL match everything
+        sqlx::query_as::<_, SqliteGameRow>(
+            "SELECT * FROM games \
+             WHERE (white_user_id = ?1 OR black_user_id = ?1) \
+             AND NOT private \
+             AND (?2 IS NULL \
+                  OR (?2 = 'white' AND white_user_id = ?1) \
+                  OR (?2 = 'black' AND black_user_id = ?1)) \
+             AND (?3 IS NULL \
+                  OR (?3 = 'draw' AND status IN ('draw', 'stalemate')) \
+                  OR (?3 = 'win' AND status IN ('checkmate', 'resigned') \
+                      AND result = CASE WHEN white_user_id = ?1 THEN 'white' ELSE 'black' END) \
+                  OR (?3 = 'loss' AND status IN ('checkmate', 'resigned') \
+                      AND result = CASE WHEN white_user_id = ?1 THEN 'black' ELSE 'white' END)) \
+             AND (?4 IS NULL OR variant = ?4) \
+             AND (?5 IS NULL OR time_control = ?5) \
+             ORDER BY created_at DESC LIMIT ?6 OFFSET ?7",
+        )
+        .bind(user_id.hyphenated())
+        .bind(filter.color.map(|c| c.to_string()))
+        .bind(filter.result.map(PlayerResult::as_str))
+        .bind(filter.variant.map(|v| v.to_string()))
+        .bind(filter.time_control.map(|t| t.to_string()))
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(GameRow::try_from)
+        .collect()
+    }
+
+    async fn count_user_games(&self, user_id: Uuid) -> Result<GameCounts, AppError> {
+        Ok(sqlx::query_as::<_, GameCounts>(
+            "SELECT COUNT(*) AS total, \
+             COUNT(*) FILTER (WHERE status IN ('checkmate', 'resigned') \
+                 AND result = CASE WHEN white_user_id = ?1 THEN 'white' ELSE 'black' END) AS wins, \
+             COUNT(*) FILTER (WHERE status IN ('checkmate', 'resigned') \
+                 AND result = CASE WHEN white_user_id = ?1 THEN 'black' ELSE 'white' END) \
+                 AS losses, \
+             COUNT(*) FILTER (WHERE status IN ('draw', 'stalemate')) AS draws, \
+             COUNT(*) FILTER (WHERE status IN ('waiting', 'active')) AS ongoing \
+             FROM games WHERE (white_user_id = ?1 OR black_user_id = ?1) AND NOT private",
+        )
+        .bind(user_id.hyphenated())
+        .fetch_one(&self.pool)
+        .await?)
+    }
+
+    async fn get_user_ratings(&self, user_id: Uuid) -> Result<Vec<RatingRow>, AppError> {
+        sqlx::query_as::<_, SqliteRatingRow>(
+            "SELECT * FROM ratings WHERE user_id = ?1 ORDER BY variant, time_control",
+        )
+        .bind(user_id.hyphenated())
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(RatingRow::try_from)
+        .collect()
+    }
+
+    async fn get_rating_history(&self, user_id: Uuid) -> Result<Vec<RatingHistoryRow>, AppError> {
+        sqlx::query_as::<_, SqliteRatingHistoryRow>(
+            "SELECT * FROM rating_history WHERE user_id = ?1 \
+             ORDER BY variant, time_control, recorded_at",
+        )
+        .bind(user_id.hyphenated())
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(RatingHistoryRow::try_from)
+        .collect()
+    }
+
+    async fn leaderboard(
+        &self,
+        variant: Variant,
+        time_control: TimeControl,
+        max_deviation: f64,
+        active_since: DateTime<Utc>,
+        limit: u32,
+    ) -> Result<Vec<LeaderboardRow>, AppError> {
+        Ok(sqlx::query_as::<_, LeaderboardRow>(
+            "SELECT users.username, ratings.rating, ratings.deviation, ratings.games, \
+             ratings.updated_at \
+             FROM ratings JOIN users ON users.id = ratings.user_id \
+             WHERE ratings.variant = ?1 AND ratings.time_control = ?2 \
+             AND ratings.deviation <= ?3 AND ratings.updated_at >= ?4 \
+             ORDER BY ratings.rating DESC LIMIT ?5",
+        )
+        .bind(variant.to_string())
+        .bind(time_control.to_string())
+        .bind(max_deviation)
+        .bind(active_since)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+}
@@ -1,20 +1,149 @@
+use axum::extract::rejection::{JsonRejection, QueryRejection};
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
-use serde_json::json;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// Stable, machine-readable reason for an error, sent with every REST and
+/// WebSocket error so clients don't have to match on messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    NotFound,
+    Unauthorized,
+    Unavailable,
+    Internal,
+    /// The request body or WebSocket frame couldn't be parsed.
+    InvalidRequest,
+    GameNotFound,
+    UserNotFound,
+    GameNotActive,
+    InvalidSecret,
+    NotYourTurn,
+    /// The move isn't valid UCI.
+    InvalidMove,
+    /// The move is well-formed but not legal in the position.
+    IllegalMove,
+    InvalidFen,
+    SeatTaken,
+    /// The move was meant for a different ply than the game is at.
+    PlyMismatch,
+    /// The game changed concurrently; reload and retry.
+    StaleGame,
+    DrawAlreadyOffered,
+    NoDrawOffer,
+    ShuttingDown,
+    UsernameTaken,
+    /// Wrong username or password.
+    InvalidCredentials,
+    /// The bearer token doesn't match a live session.
+    InvalidSession,
+    /// The signed-in user doesn't hold a seat in the game.
+    NotAPlayer,
+    /// The seek was accepted or withdrawn, or never existed.
+    SeekNotFound,
+    /// The player's rating is outside the range the seek accepts.
+    RatingOutOfRange,
+    /// The challenge was answered, withdrawn or expired, or never existed.
+    ChallengeNotFound,
+    RematchAlreadyOffered,
+    NoRematchOffer,
+    /// The game is private and the invite token is missing, wrong, expired or
+    /// already used.
+    InvalidInvite,
+    /// The open seat is kept for another player.
+    SeatReserved,
+}
+
+impl ErrorCode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::Unauthorized => "unauthorized",
+            ErrorCode::Unavailable => "unavailable",
+            ErrorCode::Internal => "internal",
+            ErrorCode::InvalidRequest => "invalid_request",
+            ErrorCode::GameNotFound => "game_not_found",
+            ErrorCode::UserNotFound => "user_not_found",
+            ErrorCode::GameNotActive => "game_not_active",
+            ErrorCode::InvalidSecret => "invalid_secret",
+            ErrorCode::NotYourTurn => "not_your_turn",
+            ErrorCode::InvalidMove => "invalid_move",
+            ErrorCode::IllegalMove => "illegal_move",
+            ErrorCode::InvalidFen => "invalid_fen",
+            ErrorCode::SeatTaken => "seat_taken",
+            ErrorCode::PlyMismatch => "ply_mismatch",
+            ErrorCode::StaleGame => "stale_game",
+            ErrorCode::DrawAlreadyOffered => "draw_already_offered",
+            ErrorCode::NoDrawOffer => "no_draw_offer",
+            ErrorCode::ShuttingDown => "shutting_down",
+            ErrorCode::UsernameTaken => "username_taken",
+            ErrorCode::InvalidCredentials => "invalid_credentials",
+            ErrorCode::InvalidSession => "invalid_session",
+            ErrorCode::NotAPlayer => "not_a_player",
+            ErrorCode::SeekNotFound => "seek_not_found",
+            ErrorCode::RatingOutOfRange => "rating_out_of_range",
+            ErrorCode::ChallengeNotFound => "challenge_not_found",
+            ErrorCode::RematchAlreadyOffered => "rematch_already_offered",
+            ErrorCode::NoRematchOffer => "no_rematch_offer",
+            ErrorCode::InvalidInvite => "invalid_invite",
+            ErrorCode::SeatReserved => "seat_reserved",
+        }
+    }
+
+    fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::NotFound
+            | ErrorCode::GameNotFound
+            | ErrorCode::UserNotFound
+            | ErrorCode::SeekNotFound
+            | ErrorCode::ChallengeNotFound => StatusCode::NOT_FOUND,
+            ErrorCode::InvalidRequest
+            | ErrorCode::GameNotActive
+            | ErrorCode::NotYourTurn
+            | ErrorCode::InvalidMove
+            | ErrorCode::IllegalMove
+            | ErrorCode::InvalidFen
+            | ErrorCode::NoDrawOffer
+            | ErrorCode::NoRematchOffer => StatusCode::BAD_REQUEST,
+            ErrorCode::Unauthorized
+            | ErrorCode::InvalidSecret
+            | ErrorCode::InvalidCredentials
+            | ErrorCode::InvalidSession => StatusCode::UNAUTHORIZED,
+            ErrorCode::NotAPlayer
+            | ErrorCode::RatingOutOfRange
+            | ErrorCode::InvalidInvite
+            | ErrorCode::SeatReserved => StatusCode::FORBIDDEN,
+            ErrorCode::SeatTaken
+            | ErrorCode::UsernameTaken
+            | ErrorCode::PlyMismatch
+            | ErrorCode::StaleGame
+            | ErrorCode::DrawAlreadyOffered
+            | ErrorCode::RematchAlreadyOffered => StatusCode::CONFLICT,
+            ErrorCode::Unavailable | ErrorCode::ShuttingDown => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
 
 #[derive(Debug, thiserror::Error)]
 pub enum AppError {
     #[error("Not found: {0}")]
     NotFound(String),
 
-    #[error("Bad request: {0}")]
-    BadRequest(String),
-
     #[error("Unauthorized: {0}")]
     Unauthorized(String),
 
-    #[error("Conflict: {0}")]
-    Conflict(String),
+    #[error("Service unavailable: {0}")]
+    Unavailable(String),
+
+    /// A request refused for a specific reason clients can act on.
+    #[error("{message}")]
+    Rejected {
+        code: ErrorCode,
+        message: String,
+        details: Option<Value>,
+    },
 
     #[error("Database error: {0}")]
     Database(#[from] sqlx::Error),
@@ -23,30 +152,93 @@ pub enum AppError {
     Internal(String),
 }
 
-impl IntoResponse for AppError {
-    fn into_response(self) -> Response {
-        let (status, message) = match &self {
-            AppError::NotFound(msg) => (StatusCode::NOT_FOUND, msg.clone()),
-            AppError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg.clone()),
-            AppError::Unauthorized(msg) => (StatusCode::UNAUTHORIZED, msg.clone()),
-            AppError::Conflict(msg) => (StatusCode::CONFLICT, msg.clone()),
+impl AppError {
+    pub fn rejected(code: ErrorCode, message: impl Into<String>) -> Self {
+        AppError::Rejected {
+            code,
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    /// Attaches structured details; only meaningful on `Rejected` errors.
+    pub fn with_details(mut self, value: Value) -> Self {
+        if let AppError::Rejected { details, .. } = &mut self {
+            *details = Some(value);
+        }
+        self
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            AppError::NotFound(_) => ErrorCode::NotFound,
+            AppError::Unauthorized(_) => ErrorCode::Unauthorized,
+            AppError::Unavailable(_) => ErrorCode::Unavailable,
+            AppError::Rejected { code, .. } => *code,
+            AppError::Database(_) | AppError::Internal(_) => ErrorCode::Internal,
+        }
+    }
+
+    pub fn details(&self) -> Option<&Value> {
+        match self {
+            AppError::Rejected { details, .. } => details.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// The message shown to clients; server-side failures are logged and masked.
+    pub fn public_message(&self) -> String {
+        match self {
+            AppError::NotFound(msg)
+            | AppError::Unauthorized(msg)
+            | AppError::Unavailable(msg)
+            | AppError::Rejected { message: msg, .. } => msg.clone(),
             AppError::Database(e) => {
                 tracing::error!("Database error: {e}");
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "Internal server error".to_string(),
-                )
+                "Internal server error".to_string()
             }
             AppError::Internal(msg) => {
                 tracing::error!("Internal error: {msg}");
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "Internal server error".to_string(),
-                )
+                "Internal server error".to_string()
             }
-        };
+        }
+    }
+
+    pub fn game_not_found() -> Self {
+        AppError::rejected(ErrorCode::GameNotFound, "Game not found")
+    }
+
+    /// The game changed between reading it and writing the update.
+    pub fn stale_game() -> Self {
+        AppError::rejected(
+            ErrorCode::StaleGame,
+            "Game was updated concurrently, reload and retry",
+        )
+    }
+}
 
-        let body = axum::Json(json!({ "error": message }));
-        (status, body).into_response()
+impl From<JsonRejection> for AppError {
+    fn from(rejection: JsonRejection) -> Self {
+        AppError::rejected(ErrorCode::InvalidRequest, rejection.body_text())
+    }
+}
+
+impl From<QueryRejection> for AppError {
+    fn from(rejection: QueryRejection) -> Self {
+        AppError::rejected(ErrorCode::InvalidRequest, rejection.body_text())
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let code = self.code();
+        let mut body = json!({
+            "error": self.public_message(),
+            "code": code,
+        });
+        if let Some(details) = self.details() {
+            body["details"] = details.clone();
+        }
+        (code.status(), axum::Json(body)).into_response()
     }
 }
@@ -0,0 +1,1199 @@
+use chrono::Utc;
+use futures_util::future::join_all;
+use serde_json::json;
+use shakmaty::{Chess, Color};
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::time::{Instant, MissedTickBehavior};
+use tracing::{field, info_span, Instrument, Span};
+use uuid::Uuid;
+
+use super::{
+    Catchup, Command, Credential, MoveOutcome, MoveReceipt, MoveRequest, RoomStats, SeatAction,
+    Subscription, SyncReason,
+};
+use crate::chess;
+use crate::db::models::{GameRow, GameStatus};
+use crate::error::{AppError, ErrorCode};
+use crate::protocol::{GameEvent, ServerMessage};
+use crate::pubsub::{GameChange, Seat, SeatMessage};
+use crate::state::AppState;
+use crate::telemetry;
+
+/// Sequence numbers reserved for the events of each game version. Deriving them
+/// from the version keeps them consistent across instances and restarts.
+const SEQS_PER_VERSION: u64 = 4;
+
+/// First sequence number of the events that produced `version`.
+fn version_seq(version: i64) -> u64 {
+    version.max(0) as u64 * SEQS_PER_VERSION
+}
+
+/// Sequence number of a snapshot: after every event up to `version`.
+fn snapshot_seq(version: i64) -> u64 {
+    version_seq(version + 1) - 1
+}
+
+/// How many recent move receipts are remembered per game.
+const MOVE_RECEIPTS_PER_GAME: usize = 32;
+
+/// A subscribed socket's private queue, plus the seat it has proven it holds.
+struct Connection {
+    seat: Option<Color>,
+    out: mpsc::Sender<ServerMessage>,
+}
+
+struct GameActor {
+    state: AppState,
+    /// Game state as last written to the database.
+    row: GameRow,
+    pos: Chess,
+    draw_offer: Option<Color>,
+    rematch_offer: Option<Color>,
+    /// Recent moves by the seat that made them and its request id.
+    receipts: VecDeque<(Color, String, MoveReceipt)>,
+    events: broadcast::Sender<GameEvent>,
+    history: VecDeque<GameEvent>,
+    /// Clients resuming from a seq below this get a snapshot instead of a replay.
+    history_floor: u64,
+    /// Events already published for the current version.
+    version_events: u64,
+    /// Events of the current command, sent out once it has been written.
+    pending: Vec<GameEvent>,
+    /// Whether the current command changed the row.
+    dirty: bool,
+    /// Messages of the current command for one seat, to relay to its sockets
+    /// on other instances.
+    seat_messages: Vec<SeatMessage>,
+    connections: HashMap<Uuid, Connection>,
+    announcements: mpsc::UnboundedSender<Announcement>,
+    last_activity: Instant,
+    /// When the last subscriber left, if there are none now.
+    empty_since: Option<Instant>,
+    /// Set once the server is shutting down; changes are refused from then on.
+    closing: bool,
+}
+
+enum Announcement {
+    /// Tells other instances about a change already written to the database.
+    Change {
+        change: GameChange,
+        /// Span of the command that made the change.
+        span: Span,
+    },
+    /// Answered once every earlier change has been announced.
+    Flush(oneshot::Sender<()>),
+}
+
+/// What a command may change, kept to roll it back if it can't be written.
+struct Checkpoint {
+    row: GameRow,
+    pos: Chess,
+    draw_offer: Option<Color>,
+    rematch_offer: Option<Color>,
+    receipts: VecDeque<(Color, String, MoveReceipt)>,
+    version_events: u64,
+}
+
+/// How often an actor checks whether it can retire.
+const ROOM_SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long a finished game stays loaded after its last subscriber leaves.
+const FINISHED_ROOM_TTL: Duration = Duration::from_secs(60);
+
+/// How long an unfinished game stays loaded with no subscribers and no commands.
+const IDLE_ROOM_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// How long a full socket queue is waited on for the shutdown notice.
+const SHUTDOWN_NOTICE_TIMEOUT: Duration = Duration::from_millis(500);
+
+pub(super) async fn run(
+    state: AppState,
+    game_id: Uuid,
+    mailbox: mpsc::WeakSender<Command>,
+    mut rx: mpsc::Receiver<Command>,
+) {
+    let registry = state.channels.clone();
+    // Never removes a successor's handle; with `rx`, only if nothing is queued
+    let deregister = |rx: Option<&mpsc::Receiver<Command>>| {
+        let Some(me) = mailbox.upgrade() else {
+            return true;
+        };
+        registry
+            .remove_if(&game_id, |_, handle| {
+                handle.tx.same_channel(&me) && rx.is_none_or(|rx| rx.is_empty())
+            })
+            .is_some()
+    };
+
+    let mut actor = match GameActor::load(state, game_id).await {
+        Ok(actor) => actor,
+        Err(e) => {
+            deregister(None);
+            let not_found = e.code() == ErrorCode::GameNotFound;
+            if !not_found {
+                tracing::error!("Failed to load game {game_id}: {e}");
+            }
+            rx.close();
+            while let Some(cmd) = rx.recv().await {
+                cmd.fail(if not_found {
+                    AppError::game_not_found()
+                } else {
+                    AppError::Internal(e.to_string())
+                });
+            }
+            return;
+        }
+    };
+
+    let mut sweep = tokio::time::interval(ROOM_SWEEP_INTERVAL);
+    sweep.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            cmd = rx.recv() => {
+                let Some(cmd) = cmd else { break };
+                actor.last_activity = Instant::now();
+                actor.handle(cmd).await;
+            }
+            _ = sweep.tick() => {
+                if actor.can_retire() {
+                    // Flush first so a successor actor never loads stale state
+                    actor.flush().await;
+                    if deregister(Some(&rx)) {
+                        tracing::debug!("Retiring room for game {game_id}");
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    // Serve anything that slipped in before the mailbox closed
+    rx.close();
+    while let Some(cmd) = rx.recv().await {
+        actor.handle(cmd).await;
+    }
+    actor.flush().await;
+    telemetry::add_game_sockets(-(actor.connections.len() as isize));
+}
+
+impl GameActor {
+    async fn load(state: AppState, game_id: Uuid) -> Result<Self, AppError> {
+        let row = state
+            .repo
+            .get_game(game_id)
+            .await?
+            .ok_or_else(AppError::game_not_found)?;
+        let pos = chess::parse_fen(&row.fen)?;
+
+        let (announcements, announcements_rx) = mpsc::unbounded_channel();
+        tokio::spawn(announce_loop(state.clone(), announcements_rx));
+
+        let rooms = state.rooms.clone();
+        Ok(Self {
+            state,
+            history_floor: snapshot_seq(row.version),
+            row,
+            pos,
+            draw_offer: None,
+            rematch_offer: None,
+            receipts: VecDeque::with_capacity(MOVE_RECEIPTS_PER_GAME),
+            events: broadcast::channel(rooms.broadcast_capacity).0,
+            history: VecDeque::with_capacity(rooms.history_size),
+            version_events: 0,
+            pending: Vec::new(),
+            dirty: false,
+            seat_messages: Vec::new(),
+            connections: HashMap::new(),
+            announcements,
+            last_activity: Instant::now(),
+            empty_since: Some(Instant::now()),
+            closing: false,
+        })
+    }
+
+    async fn handle(&mut self, cmd: Command) {
+        self.dispatch(cmd).await;
+    }
+
+    async fn dispatch(&mut self, cmd: Command) {
+        if self.closing && cmd.is_write() {
+            cmd.fail(AppError::rejected(
+                ErrorCode::ShuttingDown,
+                "Server is shutting down, reconnect and retry",
+            ));
+            return;
+        }
+
+        match cmd {
+            Command::Snapshot { reply } => {
+                let _ = reply.send(Ok(self.row.clone()));
+            }
+            Command::Resync { reply } => {
+                let _ = reply.send(Ok(self.snapshot_event()));
+            }
+            Command::Join {
+                user,
+                invite,
+                reply,
+            } => {
+                let result = self.join(user, invite).await;
+                self.announce();
+                let _ = reply.send(result);
+            }
+            Command::MakeMove { req, span, reply } => {
+                let span = info_span!(
+                    parent: &span,
+                    "game.make_move",
+                    game_id = %self.row.id,
+                    ply = self.row.moves.len() + 1,
+                    color = field::Empty,
+                    uci = %req.uci,
+                );
+                let conn = req.conn;
+                let checkpoint = self.checkpoint();
+                let result = match span.in_scope(|| self.make_move(req)) {
+                    Ok(outcome) => self
+                        .save(checkpoint)
+                        .instrument(span.clone())
+                        .await
+                        .map(|()| outcome),
+                    Err(e) => Err(e),
+                };
+                span.in_scope(|| match &result {
+                    Ok(outcome) if !outcome.duplicate => telemetry::record_move(),
+                    Ok(_) => {}
+                    Err(e) => telemetry::record_rejected_move(e),
+                });
+                if let (Ok(outcome), Some(conn)) = (&result, conn) {
+                    self.bind_seat(conn, outcome.seat);
+                }
+                let _ = reply.send(result);
+            }
+            Command::Seat {
+                action,
+                credential,
+                conn,
+                span,
+                reply,
+            } => {
+                let span = info_span!(
+                    parent: &span,
+                    "game.seat_action",
+                    game_id = %self.row.id,
+                    ply = self.row.moves.len(),
+                    color = field::Empty,
+                    action = ?action,
+                );
+                let checkpoint = self.checkpoint();
+                let result = match self
+                    .seat_action(action, credential)
+                    .instrument(span.clone())
+                    .await
+                {
+                    Ok(seat) => self
+                        .save(checkpoint)
+                        .instrument(span.clone())
+                        .await
+                        .map(|()| seat),
+                    Err(e) => Err(e),
+                };
+                if let (Ok(seat), Some(conn)) = (&result, conn) {
+                    self.bind_seat(conn, *seat);
+                }
+                let _ = reply.send(result);
+            }
+            Command::Subscribe {
+                conn,
+                out,
+                since,
+                reply,
+            } => {
+                let _ = reply.send(Ok(self.subscribe(conn, out, since)));
+            }
+            Command::Unsubscribe { conn } => {
+                if self.connections.remove(&conn).is_some() {
+                    telemetry::add_game_sockets(-1);
+                }
+                if self.connections.is_empty() {
+                    self.empty_since = Some(Instant::now());
+                }
+            }
+            Command::Stats { reply } => {
+                let _ = reply.send(Ok(self.stats()));
+            }
+            Command::Sync { reason } => self.sync(reason).await,
+            Command::Shutdown {
+                reconnect_after_ms,
+                reply,
+            } => {
+                self.closing = true;
+                // A socket that is behind gets a moment to make room, since
+                // this is what tells its client to back off
+                join_all(self.connections.values().map(|conn| {
+                    conn.out.send_timeout(
+                        ServerMessage::ServerShutdown { reconnect_after_ms },
+                        SHUTDOWN_NOTICE_TIMEOUT,
+                    )
+                }))
+                .await;
+                self.flush().await;
+                let _ = reply.send(Ok(()));
+            }
+        }
+    }
+
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            row: self.row.clone(),
+            pos: self.pos.clone(),
+            draw_offer: self.draw_offer,
+            rematch_offer: self.rematch_offer,
+            receipts: self.receipts.clone(),
+            version_events: self.version_events,
+        }
+    }
+
+    /// Writes what the current command changed before anyone hears of it, so
+    /// a change is never confirmed and then lost. One that can't be written is
+    /// rolled back and the caller gets the error.
+    async fn save(&mut self, checkpoint: Checkpoint) -> Result<(), AppError> {
+        if std::mem::take(&mut self.dirty) {
+            let write_span = info_span!(
+                "db.write_game",
+                game_id = %self.row.id,
+                ply = self.row.moves.len(),
+                version = self.row.version,
+            );
+            match write_row(&self.state, &self.row)
+                .instrument(write_span)
+                .await
+            {
+                WriteOutcome::Written(written) => {
+                    // Finishing a rated game rates it in the same write
+                    self.row.white_rating_before = written.white_rating_before;
+                    self.row.white_rating_after = written.white_rating_after;
+                    self.row.black_rating_before = written.black_rating_before;
+                    self.row.black_rating_after = written.black_rating_after;
+                }
+                WriteOutcome::Conflict => {
+                    self.rollback(checkpoint);
+                    // Subscribers get a snapshot of whatever won
+                    self.sync(SyncReason::Conflict).await;
+                    return Err(AppError::stale_game());
+                }
+                WriteOutcome::Failed => {
+                    self.rollback(checkpoint);
+                    return Err(AppError::Unavailable(
+                        "Couldn't save the change, retry".to_string(),
+                    ));
+                }
+            }
+        }
+        self.announce();
+        Ok(())
+    }
+
+    fn rollback(&mut self, checkpoint: Checkpoint) {
+        self.row = checkpoint.row;
+        self.pos = checkpoint.pos;
+        self.draw_offer = checkpoint.draw_offer;
+        self.rematch_offer = checkpoint.rematch_offer;
+        self.receipts = checkpoint.receipts;
+        self.version_events = checkpoint.version_events;
+        self.pending.clear();
+        self.seat_messages.clear();
+    }
+
+    /// Sends the events of the last command to local subscribers and queues
+    /// them, with the offers and seat messages, for other instances.
+    fn announce(&mut self) {
+        if self.pending.is_empty() && self.seat_messages.is_empty() {
+            return;
+        }
+        let events = std::mem::take(&mut self.pending);
+        for event in &events {
+            self.record(event.clone());
+        }
+        let change = GameChange {
+            game_id: self.row.id,
+            version: self.row.version,
+            events,
+            origin: self.state.instance_id,
+            draw_offer: self.draw_offer.map(Seat::from),
+            rematch_offer: self.rematch_offer.map(Seat::from),
+            seat_messages: std::mem::take(&mut self.seat_messages),
+        };
+        let _ = self.announcements.send(Announcement::Change {
+            change,
+            span: Span::current(),
+        });
+    }
+
+    /// Reloads the game from the database after someone else changed it.
+    async fn sync(&mut self, reason: SyncReason) {
+        if let SyncReason::Remote(change) = &reason {
+            if change.origin == self.state.instance_id || change.version < self.row.version {
+                // Our own change coming back, or one we already have
+                return;
+            }
+            if change.version == self.row.version {
+                // Offers and seat messages leave the row as it was
+                self.take_offers(change);
+                return;
+            }
+        }
+
+        let loaded = self.state.repo.get_game(self.row.id).await.and_then(|row| {
+            let row = row.ok_or_else(AppError::game_not_found)?;
+            let pos = chess::parse_fen(&row.fen)?;
+            Ok((row, pos))
+        });
+        let (row, pos) = match loaded {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                tracing::error!("Failed to reload game {}: {e}", self.row.id);
+                return;
+            }
+        };
+
+        let conflict = matches!(reason, SyncReason::Conflict);
+        if !conflict && row.version <= self.row.version {
+            return;
+        }
+
+        // Without a gap the remote events can be relayed as they were sent
+        let contiguous = match &reason {
+            SyncReason::Remote(change) => {
+                change.version == self.row.version + 1
+                    && change.version == row.version
+                    && !change.events.is_empty()
+            }
+            _ => false,
+        };
+
+        self.row = row;
+        self.pos = pos;
+        self.draw_offer = None;
+        self.rematch_offer = None;
+
+        match &reason {
+            SyncReason::Remote(change) if contiguous => {
+                self.version_events = change.events.len() as u64;
+                for event in &change.events {
+                    self.record(event.clone());
+                }
+            }
+            _ => {
+                self.history.clear();
+                self.version_events = 0;
+                let snapshot = self.snapshot_event();
+                self.history_floor = snapshot.seq;
+                let _ = self.events.send(snapshot);
+            }
+        }
+        if let SyncReason::Remote(change) = &reason {
+            self.take_offers(change);
+        }
+    }
+
+    /// Takes over the offers another instance announced and passes its seat
+    /// messages on to the sockets here.
+    fn take_offers(&mut self, change: &GameChange) {
+        self.draw_offer = change.draw_offer.map(Color::from);
+        self.rematch_offer = change.rematch_offer.map(Color::from);
+        for message in &change.seat_messages {
+            self.deliver_to_seat(message.seat.into(), message.msg.clone());
+        }
+    }
+
+    /// A room can go once nobody is watching and the game is over or has gone quiet.
+    fn can_retire(&self) -> bool {
+        if !self.connections.is_empty() {
+            return false;
+        }
+        let Some(empty_since) = self.empty_since else {
+            return false;
+        };
+        let finished = !matches!(self.row.status, GameStatus::Waiting | GameStatus::Active);
+        if finished {
+            empty_since.elapsed() >= FINISHED_ROOM_TTL
+        } else {
+            empty_since.elapsed() >= IDLE_ROOM_TTL && self.last_activity.elapsed() >= IDLE_ROOM_TTL
+        }
+    }
+
+    /// Waits until every queued change has been announced.
+    async fn flush(&self) {
+        let (done, wait) = oneshot::channel();
+        if self.announcements.send(Announcement::Flush(done)).is_ok() {
+            let _ = wait.await;
+        }
+    }
+
+    fn stats(&self) -> RoomStats {
+        // Rough footprint of what the room keeps in memory
+        let history_bytes: usize = self
+            .history
+            .iter()
+            .map(|e| serde_json::to_vec(e).map_or(0, |v| v.len()))
+            .sum();
+        let state_bytes = self.row.fen.len()
+            + self.row.moves.iter().map(|m| m.len()).sum::<usize>()
+            + self
+                .receipts
+                .iter()
+                .map(|(_, id, r)| id.len() + r.mv.len() + r.san.len())
+                .sum::<usize>();
+
+        RoomStats {
+            game_id: self.row.id,
+            status: self.row.status.to_string(),
+            subscribers: self.connections.len(),
+            history_events: self.history.len(),
+            approx_bytes: std::mem::size_of::<Self>() + history_bytes + state_bytes,
+            idle_secs: self.last_activity.elapsed().as_secs(),
+        }
+    }
+
+    fn authorize(&self, credential: Credential) -> Result<Color, AppError> {
+        match credential {
+            Credential::Secret(secret) => self
+                .row
+                .seat_of(secret)
+                .ok_or_else(|| AppError::rejected(ErrorCode::InvalidSecret, "Invalid secret")),
+            Credential::User(user_id) => self.row.seat_of_user(user_id).ok_or_else(|| {
+                AppError::rejected(ErrorCode::NotAPlayer, "You don't have a seat in this game")
+            }),
+            Credential::Socket(conn) => self
+                .connections
+                .get(&conn)
+                .and_then(|c| c.seat)
+                .ok_or_else(|| {
+                    AppError::Unauthorized("Authenticate the connection first".to_string())
+                }),
+        }
+    }
+
+    fn ensure_active(&self) -> Result<(), AppError> {
+        if self.row.status != GameStatus::Active {
+            return Err(
+                AppError::rejected(ErrorCode::GameNotActive, "Game is not active")
+                    .with_details(json!({ "status": self.row.status })),
+            );
+        }
+        Ok(())
+    }
+
+    fn bind_seat(&mut self, conn: Uuid, seat: Color) {
+        if let Some(conn) = self.connections.get_mut(&conn) {
+            conn.seat = Some(seat);
+        }
+    }
+
+    /// Queues an event for everyone watching; it goes out once the command
+    /// that made it has been saved.
+    fn publish(&mut self, msg: ServerMessage) {
+        let event = GameEvent {
+            seq: version_seq(self.row.version) + self.version_events,
+            msg,
+        };
+        self.version_events += 1;
+        self.pending.push(event);
+    }
+
+    /// Adds an event to the replay buffer and sends it to local subscribers.
+    fn record(&mut self, event: GameEvent) {
+        let _span = info_span!(
+            "game.broadcast",
+            game_id = %self.row.id,
+            ply = self.row.moves.len(),
+            seq = event.seq,
+            receivers = self.events.receiver_count(),
+        )
+        .entered();
+        if self.history.len() >= self.state.rooms.history_size {
+            if let Some(dropped) = self.history.pop_front() {
+                self.history_floor = dropped.seq;
+            }
+        }
+        self.history.push_back(event.clone());
+        let _ = self.events.send(event);
+    }
+
+    /// Sends a message only to the connections bound to `seat`, on every instance.
+    fn send_to_seat(&mut self, seat: Color, msg: ServerMessage) {
+        self.deliver_to_seat(seat, msg.clone());
+        self.seat_messages.push(SeatMessage {
+            seat: seat.into(),
+            msg,
+        });
+    }
+
+    /// Delivers a message to the local connections bound to `seat`.
+    fn deliver_to_seat(&self, seat: Color, msg: ServerMessage) {
+        let game_id = self.row.id;
+        for conn in self.connections.values().filter(|c| c.seat == Some(seat)) {
+            if conn.out.try_send(msg.clone()).is_err() {
+                tracing::warn!("Dropping message for {seat} in game {game_id}: queue full");
+            }
+        }
+    }
+
+    /// Records a state change in memory, to be written before the command replies.
+    fn commit(&mut self, status: GameStatus, result: Option<String>) {
+        self.row.fen = chess::position_to_fen(&self.pos);
+        self.row.status = status;
+        self.row.result = result;
+        self.row.version += 1;
+        self.row.updated_at = Utc::now();
+        self.version_events = 0;
+        self.dirty = true;
+    }
+
+    fn snapshot_event(&self) -> GameEvent {
+        let legal = if self.row.status == GameStatus::Active {
+            chess::legal_moves_uci(&self.pos)
+        } else {
+            vec![]
+        };
+
+        GameEvent {
+            seq: snapshot_seq(self.row.version),
+            msg: ServerMessage::GameState {
+                id: self.row.id,
+                fen: self.row.fen.clone(),
+                moves: self.row.moves.clone(),
+                status: self.row.status.to_string(),
+                result: self.row.result.clone(),
+                legal_moves: legal,
+                white_connected: true,
+                black_connected: self.row.black_secret.is_some(),
+            },
+        }
+    }
+
+    fn subscribe(
+        &mut self,
+        conn: Uuid,
+        out: mpsc::Sender<ServerMessage>,
+        since: Option<u64>,
+    ) -> Subscription {
+        let added = self
+            .connections
+            .insert(conn, Connection { seat: None, out })
+            .is_none();
+        if added {
+            telemetry::add_game_sockets(1);
+        }
+        self.empty_since = None;
+
+        let rx = self.events.subscribe();
+        let current = snapshot_seq(self.row.version);
+        let catchup = match since {
+            Some(since) if since == current => Catchup::Replay(Vec::new()),
+            Some(since) if since >= self.history_floor && since < current => Catchup::Replay(
+                self.history
+                    .iter()
+                    .filter(|e| e.seq > since)
+                    .cloned()
+                    .collect(),
+            ),
+            _ => Catchup::Snapshot(self.snapshot_event()),
+        };
+        Subscription { rx, catchup }
+    }
+
+    async fn join(
+        &mut self,
+        user: Option<Uuid>,
+        invite: Option<Uuid>,
+    ) -> Result<(GameRow, Uuid), AppError> {
+        if self.row.black_secret.is_some() {
+            return Err(
+                AppError::rejected(ErrorCode::SeatTaken, "Game already has two players")
+                    .with_details(json!({ "color": "black" })),
+            );
+        }
+        if self.row.rated {
+            if user.is_none() {
+                return Err(AppError::Unauthorized(
+                    "Sign in to play rated games".to_string(),
+                ));
+            }
+            if user == self.row.white_user_id {
+                return Err(AppError::rejected(
+                    ErrorCode::InvalidRequest,
+                    "You can't play a rated game against yourself",
+                ));
+            }
+        }
+        self.check_invite(user, invite)?;
+
+        self.row = self
+            .state
+            .repo
+            .join_game(self.row.id, user)
+            .await?
+            .ok_or_else(AppError::stale_game)?;
+        self.version_events = 0;
+        let secret = self
+            .row
+            .black_secret
+            .ok_or_else(|| AppError::Internal("Failed to generate black secret".to_string()))?;
+
+        self.publish(ServerMessage::PlayerJoined {
+            color: "black".to_string(),
+            fen: self.row.fen.clone(),
+            status: self.row.status.to_string(),
+            legal_moves: chess::legal_moves_uci(&self.pos),
+        });
+
+        Ok((self.row.clone(), secret))
+    }
+
+    /// The open seat of a private game needs its invite, and a seat kept for a
+    /// player can only be taken by them.
+    fn check_invite(&self, user: Option<Uuid>, invite: Option<Uuid>) -> Result<(), AppError> {
+        if self.row.private {
+            let Some(token) = invite else {
+                return Err(AppError::rejected(
+                    ErrorCode::InvalidInvite,
+                    "This game is private; join it with its invite",
+                ));
+            };
+            if !self.row.is_invite_token(token) {
+                return Err(AppError::rejected(
+                    ErrorCode::InvalidInvite,
+                    "Invite is wrong or has already been used",
+                ));
+            }
+            if let Some(expires_at) = self.row.invite_expires_at.filter(|at| *at <= Utc::now()) {
+                return Err(
+                    AppError::rejected(ErrorCode::InvalidInvite, "Invite has expired")
+                        .with_details(json!({ "expires_at": expires_at })),
+                );
+            }
+        }
+        if let Some(invited) = self.row.invited_user_id {
+            let Some(user) = user else {
+                return Err(AppError::Unauthorized(
+                    "Sign in to take this seat".to_string(),
+                ));
+            };
+            if user != invited {
+                return Err(AppError::rejected(
+                    ErrorCode::SeatReserved,
+                    "This seat is kept for another player",
+                )
+                .with_details(json!({ "user_id": invited })));
+            }
+        }
+        Ok(())
+    }
+
+    /// The receipt of a move `seat` made under `request_id`; the other seat
+    /// may well have used the same id.
+    fn move_receipt(&self, seat: Color, request_id: &str) -> Option<MoveReceipt> {
+        self.receipts
+            .iter()
+            .find(|(by, id, _)| *by == seat && id == request_id)
+            .map(|(_, _, receipt)| receipt.clone())
+    }
+
+    /// Resolves a retried move to the result it originally produced, either by its
+    /// request id or by finding the same move already played at the claimed ply.
+    fn find_duplicate_move(
+        &self,
+        seat: Color,
+        request_id: Option<&str>,
+        ply: Option<u32>,
+        uci_move: &str,
+    ) -> Result<Option<MoveReceipt>, AppError> {
+        if let Some(receipt) = request_id.and_then(|id| self.move_receipt(seat, id)) {
+            return Ok(Some(receipt));
+        }
+
+        let Some(ply) = ply else {
+            return Ok(None);
+        };
+        let moves = &self.row.moves;
+        let next_ply = moves.len() as u32 + 1;
+        if ply == next_ply {
+            return Ok(None);
+        }
+        if ply == 0 || ply > next_ply {
+            return Err(AppError::rejected(
+                ErrorCode::PlyMismatch,
+                format!("Expected ply {next_ply}, got {ply}"),
+            )
+            .with_details(json!({ "expected_ply": next_ply, "ply": ply })));
+        }
+
+        let played = &moves[ply as usize - 1];
+        let before =
+            chess::replay_san(self.row.initial_fen.as_deref(), &moves[..ply as usize - 1])?;
+        if chess::turn_color(&before) == seat {
+            if let Ok((_, san)) = chess::apply_uci_move(&before, uci_move) {
+                if &san == played {
+                    return Ok(Some(MoveReceipt {
+                        ply,
+                        mv: uci_move.to_string(),
+                        san,
+                    }));
+                }
+            }
+        }
+
+        Err(AppError::rejected(
+            ErrorCode::PlyMismatch,
+            format!("Ply {ply} was already played; expected ply {next_ply}"),
+        )
+        .with_details(json!({ "expected_ply": next_ply, "ply": ply })))
+    }
+
+    fn make_move(&mut self, req: MoveRequest) -> Result<MoveOutcome, AppError> {
+        let seat = self.authorize(req.credential)?;
+        Span::current().record("color", field::display(seat));
+
+        if let Some(receipt) =
+            self.find_duplicate_move(seat, req.request_id.as_deref(), req.ply, &req.uci)?
+        {
+            return Ok(MoveOutcome {
+                game: self.row.clone(),
+                seat,
+                receipt,
+                duplicate: true,
+            });
+        }
+
+        self.ensure_active()?;
+        let turn = chess::turn_color(&self.pos);
+        if seat != turn {
+            return Err(
+                AppError::rejected(ErrorCode::NotYourTurn, "Not your turn").with_details(json!({
+                    "move": req.uci,
+                    "seat": seat.to_string(),
+                    "turn": turn.to_string(),
+                })),
+            );
+        }
+
+        let (new_pos, san) = info_span!(
+            "chess.validate_move",
+            game_id = %self.row.id,
+            ply = self.row.moves.len() + 1,
+            color = %seat,
+            uci = %req.uci,
+        )
+        .in_scope(|| chess::apply_uci_move(&self.pos, &req.uci))?;
+        self.pos = new_pos;
+        self.row.moves.push(san.clone());
+
+        let (status, result) = chess::game_outcome(&self.pos)
+            .map(|(s, r)| {
+                let gs = match s {
+                    "checkmate" => GameStatus::Checkmate,
+                    "stalemate" => GameStatus::Stalemate,
+                    _ => GameStatus::Draw,
+                };
+                (gs, r.map(|r| r.to_string()))
+            })
+            .unwrap_or((GameStatus::Active, None));
+        self.commit(status.clone(), result.clone());
+
+        let receipt = MoveReceipt {
+            ply: self.row.moves.len() as u32,
+            mv: req.uci.clone(),
+            san: san.clone(),
+        };
+        if let Some(request_id) = req.request_id {
+            if self.receipts.len() == MOVE_RECEIPTS_PER_GAME {
+                self.receipts.pop_front();
+            }
+            self.receipts.push_back((seat, request_id, receipt.clone()));
+        }
+
+        let legal = if status == GameStatus::Active {
+            chess::legal_moves_uci(&self.pos)
+        } else {
+            vec![]
+        };
+
+        self.publish(ServerMessage::MoveMade {
+            mv: req.uci,
+            san,
+            fen: self.row.fen.clone(),
+            moves: self.row.moves.clone(),
+            status: status.to_string(),
+            result: result.clone(),
+            legal_moves: legal,
+        });
+
+        if status != GameStatus::Active {
+            self.publish(ServerMessage::GameOver {
+                status: status.to_string(),
+                result,
+            });
+            self.draw_offer = None;
+        } else if self.draw_offer != Some(seat) {
+            // Moving instead of answering declines the opponent's draw offer
+            self.draw_offer = None;
+        }
+
+        Ok(MoveOutcome {
+            game: self.row.clone(),
+            seat,
+            receipt,
+            duplicate: false,
+        })
+    }
+
+    async fn seat_action(
+        &mut self,
+        action: SeatAction,
+        credential: Credential,
+    ) -> Result<Color, AppError> {
+        let seat = self.authorize(credential)?;
+        Span::current().record("color", field::display(seat));
+
+        match action {
+            SeatAction::Authenticate => {}
+            SeatAction::Resign => {
+                self.ensure_active()?;
+                let winner = seat.other().to_string();
+                self.commit(GameStatus::Resigned, Some(winner.clone()));
+                self.draw_offer = None;
+                self.publish(ServerMessage::GameOver {
+                    status: GameStatus::Resigned.to_string(),
+                    result: Some(winner),
+                });
+            }
+            SeatAction::OfferDraw => {
+                self.ensure_active()?;
+                if let Some(by) = self.draw_offer {
+                    let message = if by == seat {
+                        "Draw already offered"
+                    } else {
+                        "Opponent already offered a draw"
+                    };
+                    return Err(AppError::rejected(ErrorCode::DrawAlreadyOffered, message)
+                        .with_details(json!({ "by": by.to_string() })));
+                }
+                self.draw_offer = Some(seat);
+                self.send_to_seat(
+                    seat.other(),
+                    ServerMessage::DrawOffered {
+                        by: seat.to_string(),
+                    },
+                );
+            }
+            SeatAction::AcceptDraw => {
+                self.ensure_active()?;
+                if self.draw_offer != Some(seat.other()) {
+                    return Err(AppError::rejected(
+                        ErrorCode::NoDrawOffer,
+                        "No draw offer to accept",
+                    ));
+                }
+                self.draw_offer = None;
+                self.commit(GameStatus::Draw, None);
+                self.publish(ServerMessage::GameOver {
+                    status: GameStatus::Draw.to_string(),
+                    result: None,
+                });
+            }
+            SeatAction::DeclineDraw => {
+                if self.draw_offer != Some(seat.other()) {
+                    return Err(AppError::rejected(
+                        ErrorCode::NoDrawOffer,
+                        "No draw offer to decline",
+                    ));
+                }
+                self.draw_offer = None;
+                self.send_to_seat(
+                    seat.other(),
+                    ServerMessage::DrawDeclined {
+                        by: seat.to_string(),
+                    },
+                );
+            }
+            SeatAction::OfferRematch => {
+                self.ensure_rematch_possible()?;
+                if let Some(by) = self.rematch_offer {
+                    let message = if by == seat {
+                        "Rematch already offered"
+                    } else {
+                        "Opponent already offered a rematch"
+                    };
+                    return Err(
+                        AppError::rejected(ErrorCode::RematchAlreadyOffered, message)
+                            .with_details(json!({ "by": by.to_string() })),
+                    );
+                }
+                self.rematch_offer = Some(seat);
+                self.send_to_seat(
+                    seat.other(),
+                    ServerMessage::RematchOffered {
+                        by: seat.to_string(),
+                    },
+                );
+            }
+            SeatAction::AcceptRematch => {
+                self.ensure_rematch_possible()?;
+                if self.rematch_offer != Some(seat.other()) {
+                    return Err(AppError::rejected(
+                        ErrorCode::NoRematchOffer,
+                        "No rematch offer to accept",
+                    ));
+                }
+                self.start_rematch().await?;
+            }
+            SeatAction::DeclineRematch => {
+                if self.rematch_offer != Some(seat.other()) {
+                    return Err(AppError::rejected(
+                        ErrorCode::NoRematchOffer,
+                        "No rematch offer to decline",
+                    ));
+                }
+                self.rematch_offer = None;
+                self.send_to_seat(
+                    seat.other(),
+                    ServerMessage::RematchDeclined {
+                        by: seat.to_string(),
+                    },
+                );
+            }
+        }
+
+        Ok(seat)
+    }
+
+    /// A rematch can be offered once the game is over, and only one is played.
+    fn ensure_rematch_possible(&self) -> Result<(), AppError> {
+        if matches!(self.row.status, GameStatus::Waiting | GameStatus::Active) {
+            return Err(
+                AppError::rejected(ErrorCode::InvalidRequest, "The game isn't over yet")
+                    .with_details(json!({ "status": self.row.status })),
+            );
+        }
+        if let Some(rematch_id) = self.row.rematch_id {
+            return Err(AppError::rejected(
+                ErrorCode::InvalidRequest,
+                "This game already has a rematch",
+            )
+            .with_details(json!({ "rematch_id": rematch_id })));
+        }
+        Ok(())
+    }
+
+    /// Creates the rematch, links it from this game and hands both players
+    /// their new seat.
+    async fn start_rematch(&mut self) -> Result<(), AppError> {
+        let (row, rematch) = self
+            .state
+            .repo
+            .create_rematch(self.row.id, self.row.version, &self.row.rematch())
+            .await?
+            .ok_or_else(AppError::stale_game)?;
+        self.row = row;
+        self.version_events = 0;
+        self.rematch_offer = None;
+
+        self.publish(ServerMessage::RematchStarted {
+            game_id: rematch.id,
+        });
+        for seat in [Color::White, Color::Black] {
+            // Colors are swapped in the rematch
+            let color = seat.other();
+            let secret = rematch.secret_of(color).ok_or_else(|| {
+                AppError::Internal("Rematch was created without both seats".to_string())
+            })?;
+            self.send_to_seat(
+                seat,
+                ServerMessage::RematchSeat {
+                    game_id: rematch.id,
+                    color: color.to_string(),
+                    secret,
+                },
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Announces changes to other instances in the order they were written.
+async fn announce_loop(state: AppState, mut rx: mpsc::UnboundedReceiver<Announcement>) {
+    while let Some(job) = rx.recv().await {
+        let (change, span) = match job {
+            Announcement::Change { change, span } => (change, span),
+            Announcement::Flush(done) => {
+                let _ = done.send(());
+                continue;
+            }
+        };
+        let publish_span = info_span!(
+            parent: &span,
+            "pubsub.publish",
+            game_id = %change.game_id,
+            version = change.version,
+            events = change.events.len(),
+        );
+        state.pubsub.publish(change).instrument(publish_span).await;
+    }
+}
+
+enum WriteOutcome {
+    Written(Box<GameRow>),
+    /// The row changed in the database since it was loaded.
+    Conflict,
+    /// Retries ran out; the change was not written.
+    Failed,
+}
+
+async fn write_row(state: &AppState, row: &GameRow) -> WriteOutcome {
+    let mut attempt = 0;
+    loop {
+        let written = state
+            .repo
+            .update_game_state(
+                row.id,
+                row.version - 1,
+                &row.fen,
+                &row.moves,
+                row.status.clone(),
+                row.result.as_deref(),
+            )
+            .await;
+
+        match written {
+            Ok(Some(written)) => return WriteOutcome::Written(Box::new(written)),
+            Ok(None) => {
+                tracing::error!(
+                    "Game {} changed in the database; version {} was not persisted",
+                    row.id,
+                    row.version
+                );
+                return WriteOutcome::Conflict;
+            }
+            Err(e) if attempt < state.rooms.persist_retry_delays_ms.len() => {
+                let delay_ms = state.rooms.persist_retry_delays_ms[attempt];
+                tracing::warn!(
+                    "Persisting game {} failed, retrying in {delay_ms}ms: {e}",
+                    row.id
+                );
+                tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+                attempt += 1;
+            }
+            Err(e) => {
+                tracing::error!(
+                    "Giving up persisting game {} version {}: {e}",
+                    row.id,
+                    row.version
+                );
+                return WriteOutcome::Failed;
+            }
+        }
+    }
+}
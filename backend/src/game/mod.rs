@@ -0,0 +1,438 @@
+mod actor;
+
+use serde::Serialize;
+use shakmaty::Color;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tracing::Span;
+use uuid::Uuid;
+
+use crate::db::models::GameRow;
+use crate::error::AppError;
+use crate::protocol::{GameEvent, ServerMessage};
+use crate::pubsub::GameChange;
+use crate::state::AppState;
+
+/// Outcome of an accepted move, kept so a retried request gets the same answer.
+#[derive(Debug, Clone)]
+pub struct MoveReceipt {
+    pub ply: u32,
+    pub mv: String,
+    pub san: String,
+}
+
+/// How a request proves which seat it speaks for.
+#[derive(Debug, Clone, Copy)]
+pub enum Credential {
+    /// The seat secret handed out on create or join.
+    Secret(Uuid),
+    /// A signed-in account linked to a seat.
+    User(Uuid),
+    /// A WebSocket connection that already authenticated.
+    Socket(Uuid),
+}
+
+pub struct MoveRequest {
+    pub uci: String,
+    pub credential: Credential,
+    /// Client-chosen id; retries with the same id are idempotent.
+    pub request_id: Option<String>,
+    /// The ply this move is meant to become (1 for White's first move).
+    pub ply: Option<u32>,
+    /// The WebSocket connection that sent the move, if any.
+    pub conn: Option<Uuid>,
+}
+
+pub struct MoveOutcome {
+    pub game: GameRow,
+    pub seat: Color,
+    pub receipt: MoveReceipt,
+    /// True when the request repeated a move that had already been applied.
+    pub duplicate: bool,
+}
+
+/// Commands that only need the player's seat to be verified.
+#[derive(Debug, Clone, Copy)]
+pub enum SeatAction {
+    Authenticate,
+    Resign,
+    OfferDraw,
+    AcceptDraw,
+    DeclineDraw,
+    OfferRematch,
+    AcceptRematch,
+    DeclineRematch,
+}
+
+/// What a new subscriber has to be sent before live events.
+pub enum Catchup {
+    /// The missed events, all still in the replay buffer.
+    Replay(Vec<GameEvent>),
+    /// The buffer can't cover the gap, so the client gets a full snapshot.
+    Snapshot(GameEvent),
+}
+
+/// What a live room holds, for the admin API.
+#[derive(Debug, Serialize)]
+pub struct RoomStats {
+    pub game_id: Uuid,
+    pub status: String,
+    pub subscribers: usize,
+    pub history_events: usize,
+    /// Rough estimate of the memory held by the room.
+    pub approx_bytes: usize,
+    pub idle_secs: u64,
+}
+
+/// Why an actor should reload its game from the database.
+#[derive(Debug)]
+pub enum SyncReason {
+    /// Another instance changed the game.
+    Remote(GameChange),
+    /// Notifications may have been missed.
+    Reconnected,
+    /// A write lost a version race, so the in-memory state is stale.
+    Conflict,
+}
+
+pub struct Subscription {
+    pub rx: broadcast::Receiver<GameEvent>,
+    pub catchup: Catchup,
+}
+
+type Reply<T> = oneshot::Sender<Result<T, AppError>>;
+
+enum Command {
+    Snapshot {
+        reply: Reply<GameRow>,
+    },
+    Resync {
+        reply: Reply<GameEvent>,
+    },
+    Join {
+        user: Option<Uuid>,
+        invite: Option<Uuid>,
+        reply: Reply<(GameRow, Uuid)>,
+    },
+    MakeMove {
+        req: MoveRequest,
+        /// The caller's span, so the actor's work shows up in the same trace.
+        span: Span,
+        reply: Reply<MoveOutcome>,
+    },
+    Seat {
+        action: SeatAction,
+        credential: Credential,
+        conn: Option<Uuid>,
+        span: Span,
+        reply: Reply<Color>,
+    },
+    Subscribe {
+        conn: Uuid,
+        out: mpsc::Sender<ServerMessage>,
+        since: Option<u64>,
+        reply: Reply<Subscription>,
+    },
+    Unsubscribe {
+        conn: Uuid,
+    },
+    Stats {
+        reply: Reply<RoomStats>,
+    },
+    Sync {
+        reason: SyncReason,
+    },
+    Shutdown {
+        reconnect_after_ms: u64,
+        reply: Reply<()>,
+    },
+}
+
+impl Command {
+    /// Whether the command can change the game.
+    fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Command::Join { .. } | Command::MakeMove { .. } | Command::Seat { .. }
+        )
+    }
+
+    /// Answers the command with `err` without running it.
+    fn fail(self, err: AppError) {
+        match self {
+            Command::Snapshot { reply } => drop(reply.send(Err(err))),
+            Command::Resync { reply } => drop(reply.send(Err(err))),
+            Command::Join { reply, .. } => drop(reply.send(Err(err))),
+            Command::MakeMove { reply, .. } => drop(reply.send(Err(err))),
+            Command::Seat { reply, .. } => drop(reply.send(Err(err))),
+            Command::Subscribe { reply, .. } => drop(reply.send(Err(err))),
+            Command::Stats { reply } => drop(reply.send(Err(err))),
+            Command::Shutdown { reply, .. } => drop(reply.send(Err(err))),
+            Command::Unsubscribe { .. } | Command::Sync { .. } => {}
+        }
+    }
+}
+
+/// Mailbox of the actor that owns a live game. All reads and writes of that
+/// game go through here, so they are applied one at a time.
+#[derive(Clone)]
+pub struct GameHandle {
+    tx: mpsc::Sender<Command>,
+}
+
+fn unavailable() -> AppError {
+    AppError::Internal("Game actor stopped".to_string())
+}
+
+impl GameHandle {
+    /// Starts the actor; it loads the game from the database before serving commands
+    /// and removes itself from `state.channels` when it retires or fails to load.
+    pub fn spawn(state: AppState, game_id: Uuid) -> Self {
+        let (tx, rx) = mpsc::channel(state.rooms.mailbox_size);
+        tokio::spawn(actor::run(state, game_id, tx.downgrade(), rx));
+        Self { tx }
+    }
+
+    async fn request<T>(&self, make: impl FnOnce(Reply<T>) -> Command) -> Result<T, AppError> {
+        let (reply, rx) = oneshot::channel();
+        self.tx.send(make(reply)).await.map_err(|_| unavailable())?;
+        rx.await.map_err(|_| unavailable())?
+    }
+
+    pub async fn snapshot(&self) -> Result<GameRow, AppError> {
+        self.request(|reply| Command::Snapshot { reply }).await
+    }
+
+    /// Current full state as an event tagged with the latest sequence number.
+    pub async fn resync(&self) -> Result<GameEvent, AppError> {
+        self.request(|reply| Command::Resync { reply }).await
+    }
+
+    /// Seats a second player as black, returning the game and their secret.
+    /// `user` links the seat to a signed-in account; `invite` is the token a
+    /// private game needs.
+    pub async fn join(
+        &self,
+        user: Option<Uuid>,
+        invite: Option<Uuid>,
+    ) -> Result<(GameRow, Uuid), AppError> {
+        self.request(|reply| Command::Join {
+            user,
+            invite,
+            reply,
+        })
+        .await
+    }
+
+    pub async fn make_move(&self, req: MoveRequest) -> Result<MoveOutcome, AppError> {
+        self.request(|reply| Command::MakeMove {
+            req,
+            span: Span::current(),
+            reply,
+        })
+        .await
+    }
+
+    pub async fn seat_action(
+        &self,
+        action: SeatAction,
+        credential: Credential,
+        conn: Option<Uuid>,
+    ) -> Result<Color, AppError> {
+        self.request(|reply| Command::Seat {
+            action,
+            credential,
+            conn,
+            span: Span::current(),
+            reply,
+        })
+        .await
+    }
+
+    /// Registers a socket's private queue and subscribes it to game events.
+    pub async fn subscribe(
+        &self,
+        conn: Uuid,
+        out: mpsc::Sender<ServerMessage>,
+        since: Option<u64>,
+    ) -> Result<Subscription, AppError> {
+        self.request(|reply| Command::Subscribe {
+            conn,
+            out,
+            since,
+            reply,
+        })
+        .await
+    }
+
+    pub async fn stats(&self) -> Result<RoomStats, AppError> {
+        self.request(|reply| Command::Stats { reply }).await
+    }
+
+    pub async fn unsubscribe(&self, conn: Uuid) {
+        let _ = self.tx.send(Command::Unsubscribe { conn }).await;
+    }
+
+    /// Tells the game's sockets to reconnect elsewhere, stops taking changes and
+    /// waits until the pending ones are written.
+    pub async fn shutdown(&self, reconnect_after_ms: u64) -> Result<(), AppError> {
+        self.request(|reply| Command::Shutdown {
+            reconnect_after_ms,
+            reply,
+        })
+        .await
+    }
+
+    /// Makes the actor catch up with changes it didn't make itself.
+    pub async fn sync(&self, reason: SyncReason) {
+        let _ = self.tx.send(Command::Sync { reason }).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::db::memory::MemoryGameRepository;
+    use crate::db::models::{GameStatus, NewGame};
+    use crate::db::repository::GameRepository;
+    use crate::error::ErrorCode;
+    use crate::pubsub::LocalPubSub;
+
+    fn move_by(secret: Uuid, uci: &str) -> MoveRequest {
+        MoveRequest {
+            uci: uci.to_string(),
+            credential: Credential::Secret(secret),
+            request_id: None,
+            ply: None,
+            conn: None,
+        }
+    }
+
+    /// Starts a game with both seats taken and returns its actor.
+    async fn started_game() -> (AppState, Arc<MemoryGameRepository>, GameRow, GameHandle) {
+        let repo = Arc::new(MemoryGameRepository::default());
+        let state = AppState::new(
+            repo.clone(),
+            Arc::new(LocalPubSub),
+            &Config::default(),
+            None,
+        );
+        let game = repo
+            .create_game(&NewGame {
+                seat_black: true,
+                ..NewGame::default()
+            })
+            .await
+            .unwrap();
+        let handle = state.game(game.id);
+        (state, repo, game, handle)
+    }
+
+    #[tokio::test]
+    async fn request_ids_are_per_seat() {
+        let (_, _, game, handle) = started_game().await;
+        let white = game.white_secret;
+        let black = game.black_secret.unwrap();
+
+        let mut first = move_by(white, "e2e4");
+        first.request_id = Some("1".to_string());
+        handle.make_move(first).await.unwrap();
+
+        // Black's id happens to match White's; it's still a new move
+        let mut reply = move_by(black, "e7e5");
+        reply.request_id = Some("1".to_string());
+        let outcome = handle.make_move(reply).await.unwrap();
+        assert!(!outcome.duplicate);
+        assert_eq!(outcome.game.moves, ["e4", "e5"]);
+
+        // A retry by White still gets White's receipt
+        let mut retry = move_by(white, "e2e4");
+        retry.request_id = Some("1".to_string());
+        let outcome = handle.make_move(retry).await.unwrap();
+        assert!(outcome.duplicate);
+        assert_eq!(outcome.receipt.san, "e4");
+    }
+
+    #[tokio::test]
+    async fn move_losing_to_a_concurrent_writer_is_rejected_as_stale() {
+        let (_, repo, game, handle) = started_game().await;
+        let white = game.white_secret;
+        let black = game.black_secret.unwrap();
+        handle.make_move(move_by(white, "e2e4")).await.unwrap();
+
+        // Another instance writes Black's reply first
+        let moves = ["e4".to_string(), "e5".to_string()];
+        let fen = "rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 2";
+        repo.update_game_state(game.id, 1, fen, &moves, GameStatus::Active, None)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let Err(err) = handle.make_move(move_by(black, "c7c5")).await else {
+            panic!("a move made from a stale position was accepted");
+        };
+        assert_eq!(err.code(), ErrorCode::StaleGame);
+
+        // The actor reloaded the winning write and play goes on from there
+        let row = handle.snapshot().await.unwrap();
+        assert_eq!(row.moves, moves);
+        assert_eq!(row.fen, fen);
+        let outcome = handle.make_move(move_by(white, "g1f3")).await.unwrap();
+        assert_eq!(outcome.game.moves, ["e4", "e5", "Nf3"]);
+        let stored = repo.get_game(game.id).await.unwrap().unwrap();
+        assert_eq!(stored.moves, outcome.game.moves);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn room_with_a_subscriber_is_not_retired() {
+        let (state, _, game, handle) = started_game().await;
+        let (out, _out_rx) = mpsc::channel(8);
+        let mut sub = handle.subscribe(Uuid::new_v4(), out, None).await.unwrap();
+        handle
+            .seat_action(
+                SeatAction::Resign,
+                Credential::Secret(game.white_secret),
+                None,
+            )
+            .await
+            .unwrap();
+
+        // Well past how long a finished room or an idle one is kept
+        tokio::time::sleep(Duration::from_secs(30 * 60)).await;
+        assert!(state.channels.contains_key(&game.id));
+        assert!(!matches!(
+            sub.rx.try_recv(),
+            Err(broadcast::error::TryRecvError::Closed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn shutdown_notice_waits_for_a_full_socket() {
+        let (_, _, _, handle) = started_game().await;
+        let (out, mut out_rx) = mpsc::channel(1);
+        let _sub = handle
+            .subscribe(Uuid::new_v4(), out.clone(), None)
+            .await
+            .unwrap();
+        out.try_send(ServerMessage::ServerShutdown {
+            reconnect_after_ms: 0,
+        })
+        .unwrap();
+
+        let shutdown = tokio::spawn(async move { handle.shutdown(1000).await });
+        // The client reads the message that filled its queue a little later
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        out_rx.recv().await.unwrap();
+        let notice = tokio::time::timeout(Duration::from_secs(1), out_rx.recv()).await;
+        assert!(matches!(
+            notice,
+            Ok(Some(ServerMessage::ServerShutdown {
+                reconnect_after_ms: 1000
+            }))
+        ));
+        shutdown.await.unwrap().unwrap();
+    }
+}
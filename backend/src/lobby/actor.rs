@@ -0,0 +1,801 @@
+use chrono::{Duration, Utc};
+use futures_util::future::join_all;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::MissedTickBehavior;
+use uuid::Uuid;
+
+use super::matchmaker::MatchmakerHandle;
+use super::{
+    Challenge, ChallengeRemoval, ChallengeRequest, Command, DeclineReason, LobbySubscription,
+    LobbyUser, Seek, SeekColor, SeekRemoval, SeekRequest,
+};
+use crate::config::ChallengeConfig;
+use crate::db::models::{GameRow, NewGame, TimeControl, Variant};
+use crate::db::repository::GameRepository;
+use crate::error::{AppError, ErrorCode};
+use crate::protocol::LobbyServerMessage;
+use crate::routes::games::CreateGameRequest;
+
+/// How many seeks one socket can have open at a time.
+const MAX_SEEKS_PER_CONNECTION: usize = 5;
+
+/// How long a full socket queue is waited on for the shutdown notice.
+const SHUTDOWN_NOTICE_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// A lobby socket's private queue and the account behind it.
+struct Connection {
+    user: Option<LobbyUser>,
+    out: mpsc::Sender<LobbyServerMessage>,
+}
+
+/// A seek plus who posted it, which clients don't get to see.
+struct OpenSeek {
+    seek: Seek,
+    conn: Uuid,
+    user_id: Option<Uuid>,
+}
+
+/// A challenge plus what's needed to start its game.
+struct OpenChallenge {
+    challenge: Challenge,
+    /// The socket the challenger sent it from, which gets their seat.
+    conn: Uuid,
+    challenger_id: Uuid,
+    recipient_id: Uuid,
+    settings: CreateGameRequest,
+}
+
+struct LobbyActor {
+    repo: Arc<dyn GameRepository>,
+    matchmaker: MatchmakerHandle,
+    config: ChallengeConfig,
+    /// Open seeks, oldest first.
+    seeks: Vec<OpenSeek>,
+    /// Unanswered challenges, oldest first.
+    challenges: Vec<OpenChallenge>,
+    connections: HashMap<Uuid, Connection>,
+    events: broadcast::Sender<LobbyServerMessage>,
+    /// Set once the server is shutting down; no new seeks or games from then on.
+    closing: bool,
+}
+
+pub(super) async fn run(
+    repo: Arc<dyn GameRepository>,
+    matchmaker: MatchmakerHandle,
+    config: ChallengeConfig,
+    broadcast_capacity: usize,
+    mut rx: mpsc::Receiver<Command>,
+) {
+    let mut actor = LobbyActor {
+        repo,
+        matchmaker,
+        config,
+        seeks: Vec::new(),
+        challenges: Vec::new(),
+        connections: HashMap::new(),
+        events: broadcast::channel(broadcast_capacity).0,
+        closing: false,
+    };
+    // Challenges only expire to the second, so checking once a second is enough
+    let mut sweep = tokio::time::interval(std::time::Duration::from_secs(1));
+    sweep.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            cmd = rx.recv() => {
+                let Some(cmd) = cmd else { break };
+                actor.dispatch(cmd).await;
+            }
+            _ = sweep.tick() => actor.expire_challenges(),
+        }
+    }
+}
+
+impl LobbyActor {
+    async fn dispatch(&mut self, cmd: Command) {
+        if self.closing && cmd.is_write() {
+            cmd.fail(AppError::rejected(
+                ErrorCode::ShuttingDown,
+                "Server is shutting down, reconnect and retry",
+            ));
+            return;
+        }
+
+        match cmd {
+            Command::Connect {
+                conn,
+                user,
+                out,
+                reply,
+            } => {
+                let challenges = match &user {
+                    Some(user) => self.open_challenges(user.id),
+                    None => Vec::new(),
+                };
+                self.connections.insert(conn, Connection { user, out });
+                let _ = reply.send(Ok(LobbySubscription {
+                    rx: self.events.subscribe(),
+                    seeks: self.open_seeks(),
+                    challenges,
+                }));
+            }
+            Command::Disconnect { conn } => {
+                self.connections.remove(&conn);
+                self.withdraw_seeks(conn);
+                self.withdraw_challenges(conn);
+                self.matchmaker.forget(conn);
+            }
+            Command::StartGame {
+                game,
+                white_conn,
+                black_conn,
+                reply,
+            } => {
+                let _ = reply.send(self.start_game(&game, white_conn, black_conn));
+            }
+            Command::Seeks { reply } => {
+                let _ = reply.send(Ok(self.open_seeks()));
+            }
+            Command::CreateSeek { conn, req, reply } => {
+                let _ = reply.send(self.create_seek(conn, req).await);
+            }
+            Command::CancelSeek {
+                conn,
+                seek_id,
+                reply,
+            } => {
+                let _ = reply.send(self.cancel_seek(conn, seek_id));
+            }
+            Command::AcceptSeek {
+                conn,
+                seek_id,
+                reply,
+            } => {
+                let _ = reply.send(self.accept_seek(conn, seek_id).await);
+            }
+            Command::CreateChallenge { conn, req, reply } => {
+                let _ = reply.send(self.create_challenge(conn, req).await);
+            }
+            Command::CancelChallenge {
+                conn,
+                challenge_id,
+                reply,
+            } => {
+                let _ = reply.send(self.cancel_challenge(conn, challenge_id));
+            }
+            Command::AcceptChallenge {
+                conn,
+                challenge_id,
+                reply,
+            } => {
+                let _ = reply.send(self.accept_challenge(conn, challenge_id).await);
+            }
+            Command::DeclineChallenge {
+                conn,
+                challenge_id,
+                reason,
+                reply,
+            } => {
+                let _ = reply.send(self.decline_challenge(conn, challenge_id, reason));
+            }
+            Command::Shutdown {
+                reconnect_after_ms,
+                reply,
+            } => {
+                self.closing = true;
+                // A socket that is behind gets a moment to make room, since
+                // this is what tells its client to back off
+                join_all(self.connections.values().map(|conn| {
+                    conn.out.send_timeout(
+                        LobbyServerMessage::ServerShutdown { reconnect_after_ms },
+                        SHUTDOWN_NOTICE_TIMEOUT,
+                    )
+                }))
+                .await;
+                let _ = reply.send(Ok(()));
+            }
+        }
+    }
+
+    fn open_seeks(&self) -> Vec<Seek> {
+        self.seeks.iter().map(|s| s.seek.clone()).collect()
+    }
+
+    fn broadcast(&self, msg: LobbyServerMessage) {
+        // No receivers just means nobody is watching the lobby
+        let _ = self.events.send(msg);
+    }
+
+    fn send_to(&self, conn: Uuid, msg: LobbyServerMessage) {
+        let Some(connection) = self.connections.get(&conn) else {
+            return;
+        };
+        if connection.out.try_send(msg).is_err() {
+            tracing::warn!("Dropping lobby message for socket {conn}: queue full");
+        }
+    }
+
+    /// Sends `msg` to every lobby socket the user has open.
+    fn send_to_user(&self, user_id: Uuid, msg: &LobbyServerMessage) {
+        for (&conn, connection) in &self.connections {
+            if connection.user.as_ref().is_some_and(|u| u.id == user_id) {
+                self.send_to(conn, msg.clone());
+            }
+        }
+    }
+
+    fn connection(&self, conn: Uuid) -> Result<&Connection, AppError> {
+        self.connections
+            .get(&conn)
+            .ok_or_else(|| AppError::Internal("Lobby connection not registered".to_string()))
+    }
+
+    /// The user's rating in a category as shown to players; unrated players
+    /// count as the starting rating.
+    async fn rating_of(
+        &self,
+        user_id: Uuid,
+        variant: Variant,
+        time_control: TimeControl,
+    ) -> Result<i32, AppError> {
+        let ratings = self.repo.get_user_ratings(user_id).await?;
+        Ok(ratings
+            .iter()
+            .find(|r| r.variant == variant && r.time_control == time_control)
+            .map(|r| r.glicko())
+            .unwrap_or_default()
+            .rounded())
+    }
+
+    async fn create_seek(&mut self, conn: Uuid, req: SeekRequest) -> Result<Uuid, AppError> {
+        let user = self.connection(conn)?.user.clone();
+        if req.rated && user.is_none() {
+            return Err(AppError::Unauthorized(
+                "Sign in to play rated games".to_string(),
+            ));
+        }
+        if self.seeks.iter().filter(|s| s.conn == conn).count() >= MAX_SEEKS_PER_CONNECTION {
+            return Err(AppError::rejected(
+                ErrorCode::InvalidRequest,
+                format!("You can have at most {MAX_SEEKS_PER_CONNECTION} open seeks"),
+            )
+            .with_details(json!({ "limit": MAX_SEEKS_PER_CONNECTION })));
+        }
+
+        let time_control = TimeControl::of(req.clock);
+        let rating = match &user {
+            Some(user) => Some(self.rating_of(user.id, req.variant, time_control).await?),
+            None => None,
+        };
+        let seek = Seek {
+            id: Uuid::new_v4(),
+            username: user.as_ref().map(|u| u.username.clone()),
+            rating,
+            variant: req.variant,
+            time_control,
+            clock: req.clock,
+            rated: req.rated,
+            color: req.color,
+            rating_range: req.rating_range,
+            created_at: Utc::now(),
+        };
+        let id = seek.id;
+        self.broadcast(LobbyServerMessage::SeekCreated { seek: seek.clone() });
+        self.seeks.push(OpenSeek {
+            seek,
+            conn,
+            user_id: user.map(|u| u.id),
+        });
+        Ok(id)
+    }
+
+    fn cancel_seek(&mut self, conn: Uuid, seek_id: Uuid) -> Result<(), AppError> {
+        let index = self.find_seek(seek_id)?;
+        if self.seeks[index].conn != conn {
+            return Err(AppError::rejected(
+                ErrorCode::InvalidRequest,
+                "Only the player who posted a seek can cancel it",
+            ));
+        }
+        self.seeks.remove(index);
+        self.broadcast(LobbyServerMessage::SeekRemoved {
+            seek_id,
+            reason: SeekRemoval::Cancelled,
+        });
+        Ok(())
+    }
+
+    fn find_seek(&self, seek_id: Uuid) -> Result<usize, AppError> {
+        self.seeks
+            .iter()
+            .position(|s| s.seek.id == seek_id)
+            .ok_or_else(|| {
+                AppError::rejected(ErrorCode::SeekNotFound, "Seek not found")
+                    .with_details(json!({ "seek_id": seek_id }))
+            })
+    }
+
+    /// Withdraws every seek `conn` still has open.
+    fn withdraw_seeks(&mut self, conn: Uuid) {
+        let (withdrawn, open) = std::mem::take(&mut self.seeks)
+            .into_iter()
+            .partition(|s| s.conn == conn);
+        self.seeks = open;
+        for seek in withdrawn {
+            self.broadcast(LobbyServerMessage::SeekRemoved {
+                seek_id: seek.seek.id,
+                reason: SeekRemoval::Cancelled,
+            });
+        }
+    }
+
+    async fn accept_seek(&mut self, conn: Uuid, seek_id: Uuid) -> Result<(), AppError> {
+        let index = self.find_seek(seek_id)?;
+        let acceptor = self.connection(conn)?.user.clone();
+        let open = &self.seeks[index];
+        let seek = &open.seek;
+        if open.conn == conn
+            || (open.user_id.is_some() && open.user_id == acceptor.as_ref().map(|u| u.id))
+        {
+            return Err(AppError::rejected(
+                ErrorCode::InvalidRequest,
+                "You can't accept your own seek",
+            ));
+        }
+        if seek.rated && acceptor.is_none() {
+            return Err(AppError::Unauthorized(
+                "Sign in to play rated games".to_string(),
+            ));
+        }
+        if let Some(range) = seek.rating_range {
+            let Some(acceptor) = &acceptor else {
+                return Err(AppError::Unauthorized(
+                    "Sign in to accept seeks with a rating range".to_string(),
+                ));
+            };
+            let rating = self
+                .rating_of(acceptor.id, seek.variant, seek.time_control)
+                .await?;
+            if !range.contains(rating) {
+                return Err(AppError::rejected(
+                    ErrorCode::RatingOutOfRange,
+                    "Your rating is outside the range this seek accepts",
+                )
+                .with_details(json!({ "rating": rating, "min": range.min, "max": range.max })));
+            }
+        }
+
+        // Taking the seek out first means nobody else can accept it meanwhile
+        let open = self.seeks.remove(index);
+        let seeker_white = match open.seek.color {
+            SeekColor::White => true,
+            SeekColor::Black => false,
+            SeekColor::Random => rand::random(),
+        };
+        let acceptor_id = acceptor.map(|u| u.id);
+        let (white_user, black_user) = if seeker_white {
+            (open.user_id, acceptor_id)
+        } else {
+            (acceptor_id, open.user_id)
+        };
+        let created = self
+            .repo
+            .create_game(&NewGame {
+                white_user,
+                variant: open.seek.variant,
+                clock: open.seek.clock,
+                rated: open.seek.rated,
+                seat_black: true,
+                black_user,
+                ..NewGame::default()
+            })
+            .await;
+        let game = match created {
+            Ok(game) => game,
+            Err(e) => {
+                self.seeks.insert(index, open);
+                return Err(e);
+            }
+        };
+
+        let started = if seeker_white {
+            self.start_game(&game, open.conn, conn)
+        } else {
+            self.start_game(&game, conn, open.conn)
+        };
+        if let Err(e) = started {
+            super::abandon_game(self.repo.as_ref(), game.id).await;
+            self.seeks.insert(index, open);
+            return Err(e);
+        }
+        self.broadcast(LobbyServerMessage::SeekRemoved {
+            seek_id,
+            reason: SeekRemoval::Accepted,
+        });
+        Ok(())
+    }
+
+    /// Hands both players of a game created in the lobby their seat. Nothing
+    /// is sent unless both sockets have room, so a player is never seated in
+    /// a game their opponent didn't hear about.
+    fn start_game(
+        &mut self,
+        game: &GameRow,
+        white_conn: Uuid,
+        black_conn: Uuid,
+    ) -> Result<(), AppError> {
+        let black_secret = game
+            .black_secret
+            .ok_or_else(|| AppError::Internal("Failed to generate black secret".to_string()))?;
+        let reserve = |conn: Uuid| {
+            self.connections
+                .get(&conn)
+                .and_then(|connection| connection.out.try_reserve().ok())
+        };
+        let (Some(white), Some(black)) = (reserve(white_conn), reserve(black_conn)) else {
+            return Err(AppError::Unavailable(
+                "A player's connection can't take the game right now".to_string(),
+            ));
+        };
+        for (permit, secret, color) in [
+            (white, game.white_secret, "white"),
+            (black, black_secret, "black"),
+        ] {
+            permit.send(LobbyServerMessage::GameStarted {
+                game: Box::new(game.to_with_secret(secret, color)),
+            });
+        }
+        for player in [white_conn, black_conn] {
+            // Neither player should be paired into a second game
+            self.withdraw_seeks(player);
+            self.matchmaker.forget(player);
+        }
+        Ok(())
+    }
+
+    /// The open challenges the user sent or received, oldest first.
+    fn open_challenges(&self, user_id: Uuid) -> Vec<Challenge> {
+        self.challenges
+            .iter()
+            .filter(|c| c.challenger_id == user_id || c.recipient_id == user_id)
+            .map(|c| c.challenge.clone())
+            .collect()
+    }
+
+    /// Tells both players of a challenge about it.
+    fn notify_players(&self, open: &OpenChallenge, msg: LobbyServerMessage) {
+        self.send_to_user(open.challenger_id, &msg);
+        self.send_to_user(open.recipient_id, &msg);
+    }
+
+    fn remove_challenge(
+        &mut self,
+        index: usize,
+        reason: ChallengeRemoval,
+        decline_reason: Option<DeclineReason>,
+    ) -> OpenChallenge {
+        let open = self.challenges.remove(index);
+        self.notify_players(
+            &open,
+            LobbyServerMessage::ChallengeRemoved {
+                challenge_id: open.challenge.id,
+                reason,
+                decline_reason,
+            },
+        );
+        open
+    }
+
+    fn find_challenge(&self, challenge_id: Uuid) -> Result<usize, AppError> {
+        self.challenges
+            .iter()
+            .position(|c| c.challenge.id == challenge_id)
+            .ok_or_else(|| {
+                AppError::rejected(ErrorCode::ChallengeNotFound, "Challenge not found")
+                    .with_details(json!({ "challenge_id": challenge_id }))
+            })
+    }
+
+    /// Finds a challenge only its recipient may answer. Anyone else but the
+    /// challenger is told it doesn't exist.
+    fn find_received_challenge(&self, conn: Uuid, challenge_id: Uuid) -> Result<usize, AppError> {
+        let index = self.find_challenge(challenge_id)?;
+        let user_id = self.connection(conn)?.user.as_ref().map(|u| u.id);
+        let open = &self.challenges[index];
+        if user_id == Some(open.recipient_id) {
+            Ok(index)
+        } else if user_id == Some(open.challenger_id) {
+            Err(AppError::rejected(
+                ErrorCode::InvalidRequest,
+                "Only the challenged player can answer a challenge",
+            ))
+        } else {
+            Err(
+                AppError::rejected(ErrorCode::ChallengeNotFound, "Challenge not found")
+                    .with_details(json!({ "challenge_id": challenge_id })),
+            )
+        }
+    }
+
+    async fn create_challenge(
+        &mut self,
+        conn: Uuid,
+        req: ChallengeRequest,
+    ) -> Result<Uuid, AppError> {
+        let Some(challenger) = self.connection(conn)?.user.clone() else {
+            return Err(AppError::Unauthorized(
+                "Sign in to challenge players".to_string(),
+            ));
+        };
+        let recipient = self
+            .repo
+            .get_user_by_name(&req.username)
+            .await?
+            .ok_or_else(|| {
+                AppError::rejected(ErrorCode::UserNotFound, "User not found")
+                    .with_details(json!({ "username": req.username }))
+            })?;
+        if recipient.id == challenger.id {
+            return Err(AppError::rejected(
+                ErrorCode::InvalidRequest,
+                "You can't challenge yourself",
+            ));
+        }
+        let pending = self
+            .challenges
+            .iter()
+            .filter(|c| c.challenger_id == challenger.id)
+            .count();
+        if pending >= self.config.max_pending {
+            return Err(AppError::rejected(
+                ErrorCode::InvalidRequest,
+                format!(
+                    "You can have at most {} unanswered challenges",
+                    self.config.max_pending
+                ),
+            )
+            .with_details(json!({ "limit": self.config.max_pending })));
+        }
+        // Checked up front, so nobody is sent a challenge that can't start a game
+        let game = req.settings.to_new_game(Some(challenger.id))?;
+
+        let now = Utc::now();
+        let challenge = Challenge {
+            id: Uuid::new_v4(),
+            challenger: challenger.username,
+            recipient: recipient.username,
+            variant: game.variant,
+            time_control: game.time_control(),
+            clock: game.clock,
+            rated: game.rated,
+            color: req.color,
+            fen: game.initial_fen.clone(),
+            created_at: now,
+            expires_at: now + Duration::seconds(self.config.expire_after_secs.into()),
+        };
+        let id = challenge.id;
+        let open = OpenChallenge {
+            challenge: challenge.clone(),
+            conn,
+            challenger_id: challenger.id,
+            recipient_id: recipient.id,
+            settings: CreateGameRequest {
+                fen: game.initial_fen,
+                ..req.settings
+            },
+        };
+        self.notify_players(&open, LobbyServerMessage::ChallengeCreated { challenge });
+        self.challenges.push(open);
+        Ok(id)
+    }
+
+    fn cancel_challenge(&mut self, conn: Uuid, challenge_id: Uuid) -> Result<(), AppError> {
+        let index = self.find_challenge(challenge_id)?;
+        let user_id = self.connection(conn)?.user.as_ref().map(|u| u.id);
+        if user_id != Some(self.challenges[index].challenger_id) {
+            return Err(AppError::rejected(
+                ErrorCode::InvalidRequest,
+                "Only the challenger can cancel a challenge",
+            ));
+        }
+        self.remove_challenge(index, ChallengeRemoval::Cancelled, None);
+        Ok(())
+    }
+
+    fn decline_challenge(
+        &mut self,
+        conn: Uuid,
+        challenge_id: Uuid,
+        reason: DeclineReason,
+    ) -> Result<(), AppError> {
+        let index = self.find_received_challenge(conn, challenge_id)?;
+        self.remove_challenge(index, ChallengeRemoval::Declined, Some(reason));
+        Ok(())
+    }
+
+    async fn accept_challenge(&mut self, conn: Uuid, challenge_id: Uuid) -> Result<(), AppError> {
+        // One past its time is gone even if the sweep hasn't got to it yet
+        self.expire_challenges();
+        let index = self.find_received_challenge(conn, challenge_id)?;
+        // Taking the challenge out first means it can't expire or be
+        // cancelled while the game is created
+        let open = self.challenges.remove(index);
+        let challenger_white = match open.challenge.color {
+            SeekColor::White => true,
+            SeekColor::Black => false,
+            SeekColor::Random => rand::random(),
+        };
+        let (white_user, black_user) = if challenger_white {
+            (open.challenger_id, open.recipient_id)
+        } else {
+            (open.recipient_id, open.challenger_id)
+        };
+        let created = match open.settings.to_new_game(Some(white_user)) {
+            Ok(game) => {
+                self.repo
+                    .create_game(&NewGame {
+                        seat_black: true,
+                        black_user: Some(black_user),
+                        ..game
+                    })
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        let game = match created {
+            Ok(game) => game,
+            Err(e) => {
+                self.challenges.insert(index, open);
+                return Err(e);
+            }
+        };
+
+        let started = if challenger_white {
+            self.start_game(&game, open.conn, conn)
+        } else {
+            self.start_game(&game, conn, open.conn)
+        };
+        if let Err(e) = started {
+            super::abandon_game(self.repo.as_ref(), game.id).await;
+            self.challenges.insert(index, open);
+            return Err(e);
+        }
+        self.notify_players(
+            &open,
+            LobbyServerMessage::ChallengeRemoved {
+                challenge_id,
+                reason: ChallengeRemoval::Accepted,
+                decline_reason: None,
+            },
+        );
+        Ok(())
+    }
+
+    /// Withdraws every challenge sent from `conn`.
+    fn withdraw_challenges(&mut self, conn: Uuid) {
+        while let Some(index) = self.challenges.iter().position(|c| c.conn == conn) {
+            self.remove_challenge(index, ChallengeRemoval::Cancelled, None);
+        }
+    }
+
+    fn expire_challenges(&mut self) {
+        let now = Utc::now();
+        while let Some(index) = self
+            .challenges
+            .iter()
+            .position(|c| c.challenge.expires_at <= now)
+        {
+            self.remove_challenge(index, ChallengeRemoval::Expired, None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::memory::MemoryGameRepository;
+
+    struct Player {
+        conn: Uuid,
+        rx: mpsc::Receiver<LobbyServerMessage>,
+    }
+
+    impl Player {
+        /// Why the challenge was removed, from the messages received so far.
+        fn removal(
+            &mut self,
+            challenge_id: Uuid,
+        ) -> Option<(ChallengeRemoval, Option<DeclineReason>)> {
+            std::iter::from_fn(|| self.rx.try_recv().ok()).find_map(|msg| match msg {
+                LobbyServerMessage::ChallengeRemoved {
+                    challenge_id: id,
+                    reason,
+                    decline_reason,
+                } if id == challenge_id => Some((reason, decline_reason)),
+                _ => None,
+            })
+        }
+    }
+
+    fn lobby() -> LobbyActor {
+        LobbyActor {
+            repo: Arc::new(MemoryGameRepository::default()),
+            matchmaker: MatchmakerHandle::new(8).0,
+            config: ChallengeConfig::default(),
+            seeks: Vec::new(),
+            challenges: Vec::new(),
+            connections: HashMap::new(),
+            events: broadcast::channel(8).0,
+            closing: false,
+        }
+    }
+
+    async fn sign_in(lobby: &mut LobbyActor, username: &str) -> Player {
+        let user = lobby
+            .repo
+            .create_user(username, "hash")
+            .await
+            .unwrap()
+            .unwrap();
+        let conn = Uuid::new_v4();
+        let (out, rx) = mpsc::channel(16);
+        let user = Some(LobbyUser {
+            id: user.id,
+            username: user.username,
+        });
+        lobby.connections.insert(conn, Connection { user, out });
+        Player { conn, rx }
+    }
+
+    async fn challenge(lobby: &mut LobbyActor) -> (Player, Player, Uuid) {
+        let challenger = sign_in(lobby, "alice").await;
+        let recipient = sign_in(lobby, "bob").await;
+        let req = ChallengeRequest {
+            username: "bob".to_string(),
+            settings: CreateGameRequest::default(),
+            color: SeekColor::Random,
+        };
+        let id = lobby.create_challenge(challenger.conn, req).await.unwrap();
+        (challenger, recipient, id)
+    }
+
+    #[tokio::test]
+    async fn expired_challenge_cannot_be_accepted() {
+        let mut lobby = lobby();
+        let (mut challenger, mut recipient, id) = challenge(&mut lobby).await;
+        // Its time ran out, but the sweep hasn't run yet
+        lobby.challenges[0].challenge.expires_at = Utc::now() - Duration::seconds(1);
+
+        let err = lobby
+            .accept_challenge(recipient.conn, id)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::ChallengeNotFound);
+        assert!(lobby.challenges.is_empty());
+        for player in [&mut challenger, &mut recipient] {
+            assert_eq!(player.removal(id), Some((ChallengeRemoval::Expired, None)));
+        }
+    }
+
+    #[tokio::test]
+    async fn declined_challenge_is_removed() {
+        let mut lobby = lobby();
+        let (mut challenger, mut recipient, id) = challenge(&mut lobby).await;
+
+        lobby
+            .decline_challenge(recipient.conn, id, DeclineReason::TooFast)
+            .unwrap();
+        assert!(lobby.challenges.is_empty());
+        let declined = Some((ChallengeRemoval::Declined, Some(DeclineReason::TooFast)));
+        for player in [&mut challenger, &mut recipient] {
+            assert_eq!(player.removal(id), declined);
+        }
+        let err = lobby
+            .accept_challenge(recipient.conn, id)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::ChallengeNotFound);
+    }
+}
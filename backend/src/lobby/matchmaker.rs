@@ -0,0 +1,493 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::MissedTickBehavior;
+use uuid::Uuid;
+
+use super::{LobbyHandle, LobbyUser};
+use crate::config::MatchmakingConfig;
+use crate::db::models::{Clock, NewGame, TimeControl, Variant};
+use crate::db::repository::GameRepository;
+use crate::error::{AppError, ErrorCode};
+
+/// Players are only paired with others in the same pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Pool {
+    pub variant: Variant,
+    pub time_control: TimeControl,
+    pub rated: bool,
+}
+
+impl Pool {
+    /// The clock games from this pool are played with.
+    fn clock(&self) -> Option<Clock> {
+        let (initial_secs, increment_secs) = match self.time_control {
+            TimeControl::Bullet => (60, 0),
+            TimeControl::Blitz => (180, 2),
+            TimeControl::Rapid => (600, 0),
+            TimeControl::Classical => (1800, 0),
+            TimeControl::Correspondence => return None,
+        };
+        Some(Clock {
+            initial_secs,
+            increment_secs,
+        })
+    }
+}
+
+type Reply<T> = oneshot::Sender<Result<T, AppError>>;
+
+enum Command {
+    Join {
+        conn: Uuid,
+        user: Option<LobbyUser>,
+        pool: Pool,
+        reply: Reply<()>,
+    },
+    Leave {
+        conn: Uuid,
+        reply: Reply<()>,
+    },
+    Shutdown {
+        reply: Reply<()>,
+    },
+}
+
+/// Commands waiting for the matchmaker task to start.
+pub struct Mailbox {
+    rx: mpsc::Receiver<Command>,
+    forget_rx: mpsc::UnboundedReceiver<Uuid>,
+}
+
+/// Mailbox of the background task that pairs queued lobby sockets.
+#[derive(Clone)]
+pub struct MatchmakerHandle {
+    tx: mpsc::Sender<Command>,
+    /// Sockets to drop from the queue. Unbounded so the lobby never has to
+    /// wait on the matchmaker, which may be waiting on it, and none are lost.
+    forget_tx: mpsc::UnboundedSender<Uuid>,
+}
+
+fn unavailable() -> AppError {
+    AppError::Internal("Matchmaker stopped".to_string())
+}
+
+impl MatchmakerHandle {
+    /// Creates the handle before the task exists, so the lobby can hold it
+    /// while the matchmaker holds the lobby's.
+    pub fn new(mailbox_size: usize) -> (Self, Mailbox) {
+        let (tx, rx) = mpsc::channel(mailbox_size);
+        let (forget_tx, forget_rx) = mpsc::unbounded_channel();
+        (Self { tx, forget_tx }, Mailbox { rx, forget_rx })
+    }
+
+    /// Starts pairing; games are announced through `lobby`.
+    pub fn start(
+        mailbox: Mailbox,
+        repo: Arc<dyn GameRepository>,
+        lobby: LobbyHandle,
+        config: MatchmakingConfig,
+    ) {
+        let matchmaker = Matchmaker {
+            repo,
+            lobby,
+            config,
+            queue: Vec::new(),
+            recent: HashMap::new(),
+            closing: false,
+        };
+        tokio::spawn(matchmaker.run(mailbox));
+    }
+
+    async fn request<T>(&self, make: impl FnOnce(Reply<T>) -> Command) -> Result<T, AppError> {
+        let (reply, rx) = oneshot::channel();
+        self.tx.send(make(reply)).await.map_err(|_| unavailable())?;
+        rx.await.map_err(|_| unavailable())?
+    }
+
+    /// Queues the socket in `pool`, replacing any earlier place it had.
+    pub async fn join(
+        &self,
+        conn: Uuid,
+        user: Option<LobbyUser>,
+        pool: Pool,
+    ) -> Result<(), AppError> {
+        self.request(|reply| Command::Join {
+            conn,
+            user,
+            pool,
+            reply,
+        })
+        .await
+    }
+
+    pub async fn leave(&self, conn: Uuid) -> Result<(), AppError> {
+        self.request(|reply| Command::Leave { conn, reply }).await
+    }
+
+    /// Drops the socket from the queue without waiting. The lobby calls this
+    /// and must never block on the matchmaker, which may be waiting on it.
+    pub fn forget(&self, conn: Uuid) {
+        // Only fails once the matchmaker has stopped and there's no queue
+        let _ = self.forget_tx.send(conn);
+    }
+
+    /// Stops taking players and pairing them.
+    pub async fn shutdown(&self) -> Result<(), AppError> {
+        self.request(|reply| Command::Shutdown { reply }).await
+    }
+}
+
+/// A queued lobby socket.
+struct Entry {
+    conn: Uuid,
+    user: Option<LobbyUser>,
+    pool: Pool,
+    rating: i32,
+    joined_at: Instant,
+}
+
+impl Entry {
+    /// Identifies the player across sockets when they're signed in.
+    fn player(&self) -> Uuid {
+        self.user.as_ref().map_or(self.conn, |u| u.id)
+    }
+}
+
+struct Matchmaker {
+    repo: Arc<dyn GameRepository>,
+    lobby: LobbyHandle,
+    config: MatchmakingConfig,
+    /// Waiting players, longest waiting first.
+    queue: Vec<Entry>,
+    /// Each player's latest queue opponents and when they were paired,
+    /// newest last. Players without one that's still recent are dropped.
+    recent: HashMap<Uuid, VecDeque<(Uuid, Instant)>>,
+    closing: bool,
+}
+
+impl Matchmaker {
+    async fn run(mut self, mailbox: Mailbox) {
+        let Mailbox {
+            mut rx,
+            mut forget_rx,
+        } = mailbox;
+        let mut tick = tokio::time::interval(Duration::from_millis(self.config.interval_ms));
+        tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                cmd = rx.recv() => {
+                    let Some(cmd) = cmd else { break };
+                    self.dispatch(cmd).await;
+                }
+                Some(conn) = forget_rx.recv() => {
+                    self.remove(conn);
+                }
+                _ = tick.tick() => {
+                    // Sockets that left since the last command mustn't be paired
+                    while let Ok(conn) = forget_rx.try_recv() {
+                        self.remove(conn);
+                    }
+                    if !self.closing {
+                        self.pair_all().await;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn dispatch(&mut self, cmd: Command) {
+        match cmd {
+            Command::Join {
+                conn,
+                user,
+                pool,
+                reply,
+            } => {
+                let _ = reply.send(self.join(conn, user, pool).await);
+            }
+            Command::Leave { conn, reply } => {
+                let result = if self.remove(conn) {
+                    Ok(())
+                } else {
+                    Err(AppError::rejected(
+                        ErrorCode::InvalidRequest,
+                        "You aren't in the queue",
+                    ))
+                };
+                let _ = reply.send(result);
+            }
+            Command::Shutdown { reply } => {
+                self.closing = true;
+                self.queue.clear();
+                let _ = reply.send(Ok(()));
+            }
+        }
+    }
+
+    fn remove(&mut self, conn: Uuid) -> bool {
+        let before = self.queue.len();
+        self.queue.retain(|e| e.conn != conn);
+        self.queue.len() != before
+    }
+
+    async fn join(
+        &mut self,
+        conn: Uuid,
+        user: Option<LobbyUser>,
+        pool: Pool,
+    ) -> Result<(), AppError> {
+        if self.closing {
+            return Err(AppError::rejected(
+                ErrorCode::ShuttingDown,
+                "Server is shutting down, reconnect and retry",
+            ));
+        }
+        if pool.rated && user.is_none() {
+            return Err(AppError::Unauthorized(
+                "Sign in to play rated games".to_string(),
+            ));
+        }
+        // Guests are matched as if they had the starting rating
+        let rating = match &user {
+            Some(user) => self
+                .repo
+                .get_user_ratings(user.id)
+                .await?
+                .iter()
+                .find(|r| r.variant == pool.variant && r.time_control == pool.time_control)
+                .map(|r| r.glicko())
+                .unwrap_or_default(),
+            None => Default::default(),
+        };
+        self.remove(conn);
+        self.queue.push(Entry {
+            conn,
+            user,
+            pool,
+            rating: rating.rounded(),
+            joined_at: Instant::now(),
+        });
+        Ok(())
+    }
+
+    /// The rating difference a player accepts after waiting since `joined_at`.
+    fn window(&self, joined_at: Instant, now: Instant) -> u32 {
+        let waited = now.duration_since(joined_at).as_secs() as u32;
+        self.config
+            .initial_window
+            .saturating_add(waited.saturating_mul(self.config.window_growth_per_sec))
+            .min(self.config.max_window)
+    }
+
+    fn met_recently(&self, a: Uuid, b: Uuid) -> bool {
+        self.recent
+            .get(&a)
+            .is_some_and(|r| r.iter().any(|&(opponent, _)| opponent == b))
+    }
+
+    /// Whether `a` and `b` can be paired: both accept the rating difference
+    /// and they haven't just played each other.
+    fn compatible(&self, a: &Entry, b: &Entry, now: Instant) -> bool {
+        let window = self
+            .window(a.joined_at, now)
+            .min(self.window(b.joined_at, now));
+        a.pool == b.pool
+            && a.player() != b.player()
+            && a.rating.abs_diff(b.rating) <= window
+            && !self.met_recently(a.player(), b.player())
+    }
+
+    /// Pairs whoever can be paired. The longest waiting players choose first,
+    /// each taking the closest rated opponent they accept.
+    async fn pair_all(&mut self) {
+        for (a, b) in self.pairings(Instant::now()) {
+            if let Err(e) = self.start_game(a, b).await {
+                tracing::error!("Failed to start a queued game: {e}");
+            }
+        }
+    }
+
+    /// The sockets to pair at `now`, longest waiting first in each pair.
+    fn pairings(&mut self, now: Instant) -> Vec<(Uuid, Uuid)> {
+        self.forget_old_opponents(now);
+        let mut taken = HashSet::new();
+        let mut pairs = Vec::new();
+        for (i, a) in self.queue.iter().enumerate() {
+            if taken.contains(&i) {
+                continue;
+            }
+            let best = self
+                .queue
+                .iter()
+                .enumerate()
+                .skip(i + 1)
+                .filter(|(j, b)| !taken.contains(j) && self.compatible(a, b, now))
+                .min_by_key(|(_, b)| a.rating.abs_diff(b.rating));
+            if let Some((j, b)) = best {
+                taken.insert(i);
+                taken.insert(j);
+                pairs.push((a.conn, b.conn));
+            }
+        }
+        pairs
+    }
+
+    /// Creates the game for two queued sockets and hands both their seat.
+    /// They stay queued if the game can't be created or handed to both, and
+    /// a game neither heard about is deleted.
+    async fn start_game(&mut self, a: Uuid, b: Uuid) -> Result<(), AppError> {
+        let position = |conn| self.queue.iter().position(|e| e.conn == conn);
+        let (Some(a), Some(b)) = (position(a), position(b)) else {
+            return Ok(());
+        };
+        let (white, black) = if rand::random() { (a, b) } else { (b, a) };
+        let (white, black) = (&self.queue[white], &self.queue[black]);
+        let pool = white.pool;
+        let game = self
+            .repo
+            .create_game(&NewGame {
+                white_user: white.user.as_ref().map(|u| u.id),
+                variant: pool.variant,
+                clock: pool.clock(),
+                rated: pool.rated,
+                seat_black: true,
+                black_user: black.user.as_ref().map(|u| u.id),
+                ..NewGame::default()
+            })
+            .await?;
+
+        let (white_player, black_player) = (white.player(), black.player());
+        let (white, black) = (white.conn, black.conn);
+        let game_id = game.id;
+        if let Err(e) = self.lobby.start_game(game, white, black).await {
+            super::abandon_game(self.repo.as_ref(), game_id).await;
+            return Err(e);
+        }
+        self.remove(white);
+        self.remove(black);
+        let now = Instant::now();
+        self.remember(white_player, black_player, now);
+        self.remember(black_player, white_player, now);
+        Ok(())
+    }
+
+    /// Drops opponents paired longer ago than `recent_opponent_secs`, and
+    /// players with none left. Guests are keyed by socket and never come back,
+    /// so this is what eventually forgets them.
+    fn forget_old_opponents(&mut self, now: Instant) {
+        let keep = Duration::from_secs(self.config.recent_opponent_secs);
+        self.recent.retain(|_, recent| {
+            while recent
+                .front()
+                .is_some_and(|&(_, at)| now.duration_since(at) >= keep)
+            {
+                recent.pop_front();
+            }
+            !recent.is_empty()
+        });
+    }
+
+    fn remember(&mut self, player: Uuid, opponent: Uuid, at: Instant) {
+        let limit = self.config.recent_opponents;
+        if limit == 0 {
+            return;
+        }
+        let recent = self.recent.entry(player).or_default();
+        if recent.len() == limit {
+            recent.pop_front();
+        }
+        recent.push_back((opponent, at));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ChallengeConfig, RoomConfig};
+    use crate::db::memory::MemoryGameRepository;
+
+    const BLITZ: Pool = Pool {
+        variant: Variant::Standard,
+        time_control: TimeControl::Blitz,
+        rated: false,
+    };
+
+    fn matchmaker() -> Matchmaker {
+        let repo: Arc<dyn GameRepository> = Arc::new(MemoryGameRepository::default());
+        let (handle, _) = MatchmakerHandle::new(8);
+        let lobby = LobbyHandle::spawn(
+            repo.clone(),
+            &RoomConfig::default(),
+            ChallengeConfig::default(),
+            handle,
+        );
+        Matchmaker {
+            repo,
+            lobby,
+            config: MatchmakingConfig::default(),
+            queue: Vec::new(),
+            recent: HashMap::new(),
+            closing: false,
+        }
+    }
+
+    /// Queues a signed-in player, returning their socket.
+    fn queue(matchmaker: &mut Matchmaker, rating: i32, joined_at: Instant) -> Uuid {
+        let conn = Uuid::new_v4();
+        matchmaker.queue.push(Entry {
+            conn,
+            user: Some(LobbyUser {
+                id: Uuid::new_v4(),
+                username: conn.to_string(),
+            }),
+            pool: BLITZ,
+            rating,
+            joined_at,
+        });
+        conn
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[tokio::test]
+    async fn window_widens_while_waiting() {
+        let mut matchmaker = matchmaker();
+        let start = Instant::now();
+        // Defaults: 100 at first, 10 more a second, at most 500
+        assert_eq!(matchmaker.window(start, start), 100);
+        assert_eq!(matchmaker.window(start, start + secs(30)), 400);
+        assert_eq!(matchmaker.window(start, start + secs(60)), 500);
+
+        let a = queue(&mut matchmaker, 1500, start);
+        let b = queue(&mut matchmaker, 1850, start);
+        assert!(matchmaker.pairings(start).is_empty());
+        assert_eq!(matchmaker.pairings(start + secs(30)), [(a, b)]);
+
+        let mut matchmaker = self::matchmaker();
+        let a = queue(&mut matchmaker, 1500, start);
+        let b = queue(&mut matchmaker, 1950, start);
+        assert!(matchmaker.pairings(start + secs(30)).is_empty());
+        assert_eq!(matchmaker.pairings(start + secs(60)), [(a, b)]);
+    }
+
+    #[tokio::test]
+    async fn recent_opponents_are_not_paired_again_until_they_expire() {
+        let mut matchmaker = matchmaker();
+        let start = Instant::now();
+        let a = queue(&mut matchmaker, 1500, start);
+        let b = queue(&mut matchmaker, 1500, start);
+        let (player_a, player_b) = (matchmaker.queue[0].player(), matchmaker.queue[1].player());
+        matchmaker.remember(player_a, player_b, start);
+        matchmaker.remember(player_b, player_a, start);
+
+        assert!(matchmaker.pairings(start + secs(1)).is_empty());
+        let expiry = secs(matchmaker.config.recent_opponent_secs);
+        assert!(matchmaker.pairings(start + expiry - secs(1)).is_empty());
+        assert_eq!(matchmaker.pairings(start + expiry), [(a, b)]);
+        assert!(matchmaker.recent.is_empty());
+    }
+}
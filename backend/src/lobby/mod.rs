@@ -0,0 +1,418 @@
+mod actor;
+pub mod matchmaker;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use uuid::Uuid;
+
+use crate::config::{ChallengeConfig, RoomConfig};
+use crate::db::models::{Clock, GameRow, TimeControl, Variant};
+use crate::db::repository::GameRepository;
+use crate::error::AppError;
+use crate::protocol::LobbyServerMessage;
+use crate::routes::games::CreateGameRequest;
+use matchmaker::MatchmakerHandle;
+
+/// The side the player posting a seek wants to play.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SeekColor {
+    White,
+    Black,
+    #[default]
+    Random,
+}
+
+/// Ratings an opponent needs to accept a seek, both ends included.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct RatingRange {
+    pub min: i32,
+    pub max: i32,
+}
+
+impl RatingRange {
+    pub fn contains(&self, rating: i32) -> bool {
+        (self.min..=self.max).contains(&rating)
+    }
+}
+
+/// What a player asks for when posting a seek.
+#[derive(Debug, Clone)]
+pub struct SeekRequest {
+    pub variant: Variant,
+    pub clock: Option<Clock>,
+    pub rated: bool,
+    pub color: SeekColor,
+    pub rating_range: Option<RatingRange>,
+}
+
+/// An open seek as lobby clients see it.
+#[derive(Debug, Serialize, Clone)]
+pub struct Seek {
+    pub id: Uuid,
+    /// `None` when a guest posted the seek.
+    pub username: Option<String>,
+    /// The poster's rating in the seek's category; `None` for guests.
+    pub rating: Option<i32>,
+    pub variant: Variant,
+    pub time_control: TimeControl,
+    pub clock: Option<Clock>,
+    pub rated: bool,
+    pub color: SeekColor,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rating_range: Option<RatingRange>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Why a seek left the lobby.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SeekRemoval {
+    /// Someone accepted it and the game has started.
+    Accepted,
+    /// Its player withdrew it, started another game or left the lobby.
+    Cancelled,
+}
+
+/// What a player asks for when challenging someone.
+#[derive(Debug, Clone)]
+pub struct ChallengeRequest {
+    /// The name of the player being challenged.
+    pub username: String,
+    pub settings: CreateGameRequest,
+    /// The side the challenger wants to play.
+    pub color: SeekColor,
+}
+
+/// A challenge as its two players see it.
+#[derive(Debug, Serialize, Clone)]
+pub struct Challenge {
+    pub id: Uuid,
+    pub challenger: String,
+    pub recipient: String,
+    pub variant: Variant,
+    pub time_control: TimeControl,
+    pub clock: Option<Clock>,
+    pub rated: bool,
+    /// The side the challenger gets.
+    pub color: SeekColor,
+    /// Absent when the game starts from the standard position.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fen: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Why a challenge was turned down; clients show it to the challenger.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeclineReason {
+    #[default]
+    Generic,
+    Later,
+    TooFast,
+    TooSlow,
+    Rated,
+    Casual,
+    Variant,
+    Position,
+}
+
+/// Why a challenge is no longer open.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChallengeRemoval {
+    /// The recipient accepted it and the game has started.
+    Accepted,
+    Declined,
+    /// The challenger withdrew it or left the lobby.
+    Cancelled,
+    /// Nobody answered it in time.
+    Expired,
+}
+
+/// The signed-in account behind a lobby socket.
+#[derive(Debug, Clone)]
+pub struct LobbyUser {
+    pub id: Uuid,
+    pub username: String,
+}
+
+pub struct LobbySubscription {
+    pub rx: broadcast::Receiver<LobbyServerMessage>,
+    /// The seeks open at the moment of subscribing, oldest first.
+    pub seeks: Vec<Seek>,
+    /// The challenges the socket's player sent or received that are still
+    /// open, oldest first; empty for guests.
+    pub challenges: Vec<Challenge>,
+}
+
+type Reply<T> = oneshot::Sender<Result<T, AppError>>;
+
+enum Command {
+    Connect {
+        conn: Uuid,
+        user: Option<LobbyUser>,
+        out: mpsc::Sender<LobbyServerMessage>,
+        reply: Reply<LobbySubscription>,
+    },
+    Disconnect {
+        conn: Uuid,
+    },
+    Seeks {
+        reply: Reply<Vec<Seek>>,
+    },
+    CreateSeek {
+        conn: Uuid,
+        req: SeekRequest,
+        reply: Reply<Uuid>,
+    },
+    CancelSeek {
+        conn: Uuid,
+        seek_id: Uuid,
+        reply: Reply<()>,
+    },
+    AcceptSeek {
+        conn: Uuid,
+        seek_id: Uuid,
+        reply: Reply<()>,
+    },
+    CreateChallenge {
+        conn: Uuid,
+        req: ChallengeRequest,
+        reply: Reply<Uuid>,
+    },
+    CancelChallenge {
+        conn: Uuid,
+        challenge_id: Uuid,
+        reply: Reply<()>,
+    },
+    AcceptChallenge {
+        conn: Uuid,
+        challenge_id: Uuid,
+        reply: Reply<()>,
+    },
+    DeclineChallenge {
+        conn: Uuid,
+        challenge_id: Uuid,
+        reason: DeclineReason,
+        reply: Reply<()>,
+    },
+    /// A game the matchmaker created for two queued sockets.
+    StartGame {
+        game: Box<GameRow>,
+        white_conn: Uuid,
+        black_conn: Uuid,
+        reply: Reply<()>,
+    },
+    Shutdown {
+        reconnect_after_ms: u64,
+        reply: Reply<()>,
+    },
+}
+
+impl Command {
+    /// Whether the command can open or start games.
+    fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Command::CreateSeek { .. }
+                | Command::AcceptSeek { .. }
+                | Command::CreateChallenge { .. }
+                | Command::AcceptChallenge { .. }
+                | Command::StartGame { .. }
+        )
+    }
+
+    /// Answers the command with `err` without running it.
+    fn fail(self, err: AppError) {
+        match self {
+            Command::Connect { reply, .. } => drop(reply.send(Err(err))),
+            Command::Seeks { reply } => drop(reply.send(Err(err))),
+            Command::CreateSeek { reply, .. } => drop(reply.send(Err(err))),
+            Command::CancelSeek { reply, .. } => drop(reply.send(Err(err))),
+            Command::AcceptSeek { reply, .. } => drop(reply.send(Err(err))),
+            Command::CreateChallenge { reply, .. } => drop(reply.send(Err(err))),
+            Command::CancelChallenge { reply, .. } => drop(reply.send(Err(err))),
+            Command::AcceptChallenge { reply, .. } => drop(reply.send(Err(err))),
+            Command::DeclineChallenge { reply, .. } => drop(reply.send(Err(err))),
+            Command::StartGame { reply, .. } => drop(reply.send(Err(err))),
+            Command::Shutdown { reply, .. } => drop(reply.send(Err(err))),
+            Command::Disconnect { .. } => {}
+        }
+    }
+}
+
+/// Mailbox of the actor that owns the open seeks and challenges and the
+/// lobby's sockets. Seeks and challenges belong to the socket that posted them
+/// and are withdrawn when it disconnects.
+#[derive(Clone)]
+pub struct LobbyHandle {
+    tx: mpsc::Sender<Command>,
+}
+
+fn unavailable() -> AppError {
+    AppError::Internal("Lobby actor stopped".to_string())
+}
+
+/// Deletes a game whose players couldn't be handed their seats.
+async fn abandon_game(repo: &dyn GameRepository, game_id: Uuid) {
+    if let Err(e) = repo.delete_unplayed_game(game_id).await {
+        tracing::error!("Failed to delete undelivered game {game_id}: {e}");
+    }
+}
+
+impl LobbyHandle {
+    /// Starts the actor; `matchmaker` is told about sockets that leave or
+    /// start a game, so they aren't paired again.
+    pub fn spawn(
+        repo: Arc<dyn GameRepository>,
+        rooms: &RoomConfig,
+        challenges: ChallengeConfig,
+        matchmaker: MatchmakerHandle,
+    ) -> Self {
+        let (tx, rx) = mpsc::channel(rooms.mailbox_size);
+        tokio::spawn(actor::run(
+            repo,
+            matchmaker,
+            challenges,
+            rooms.broadcast_capacity,
+            rx,
+        ));
+        Self { tx }
+    }
+
+    async fn request<T>(&self, make: impl FnOnce(Reply<T>) -> Command) -> Result<T, AppError> {
+        let (reply, rx) = oneshot::channel();
+        self.tx.send(make(reply)).await.map_err(|_| unavailable())?;
+        rx.await.map_err(|_| unavailable())?
+    }
+
+    /// Registers a socket's private queue and subscribes it to seek changes.
+    pub async fn connect(
+        &self,
+        conn: Uuid,
+        user: Option<LobbyUser>,
+        out: mpsc::Sender<LobbyServerMessage>,
+    ) -> Result<LobbySubscription, AppError> {
+        self.request(|reply| Command::Connect {
+            conn,
+            user,
+            out,
+            reply,
+        })
+        .await
+    }
+
+    /// Forgets the socket, withdraws its seeks and takes it out of the queue.
+    pub async fn disconnect(&self, conn: Uuid) {
+        let _ = self.tx.send(Command::Disconnect { conn }).await;
+    }
+
+    /// The open seeks, oldest first.
+    pub async fn seeks(&self) -> Result<Vec<Seek>, AppError> {
+        self.request(|reply| Command::Seeks { reply }).await
+    }
+
+    /// Posts a seek for the player on `conn`, returning its id.
+    pub async fn create_seek(&self, conn: Uuid, req: SeekRequest) -> Result<Uuid, AppError> {
+        self.request(|reply| Command::CreateSeek { conn, req, reply })
+            .await
+    }
+
+    pub async fn cancel_seek(&self, conn: Uuid, seek_id: Uuid) -> Result<(), AppError> {
+        self.request(|reply| Command::CancelSeek {
+            conn,
+            seek_id,
+            reply,
+        })
+        .await
+    }
+
+    /// Starts the seek's game with the player on `conn` as the opponent. Both
+    /// players are sent their seat over their lobby sockets.
+    pub async fn accept_seek(&self, conn: Uuid, seek_id: Uuid) -> Result<(), AppError> {
+        self.request(|reply| Command::AcceptSeek {
+            conn,
+            seek_id,
+            reply,
+        })
+        .await
+    }
+
+    /// Challenges another player from the socket `conn`, returning the
+    /// challenge's id. The recipient hears about it on all their lobby sockets.
+    pub async fn create_challenge(
+        &self,
+        conn: Uuid,
+        req: ChallengeRequest,
+    ) -> Result<Uuid, AppError> {
+        self.request(|reply| Command::CreateChallenge { conn, req, reply })
+            .await
+    }
+
+    /// Withdraws a challenge the player on `conn` sent.
+    pub async fn cancel_challenge(&self, conn: Uuid, challenge_id: Uuid) -> Result<(), AppError> {
+        self.request(|reply| Command::CancelChallenge {
+            conn,
+            challenge_id,
+            reply,
+        })
+        .await
+    }
+
+    /// Starts the challenge's game. The recipient gets their seat on `conn`
+    /// and the challenger on the socket they challenged from.
+    pub async fn accept_challenge(&self, conn: Uuid, challenge_id: Uuid) -> Result<(), AppError> {
+        self.request(|reply| Command::AcceptChallenge {
+            conn,
+            challenge_id,
+            reply,
+        })
+        .await
+    }
+
+    pub async fn decline_challenge(
+        &self,
+        conn: Uuid,
+        challenge_id: Uuid,
+        reason: DeclineReason,
+    ) -> Result<(), AppError> {
+        self.request(|reply| Command::DeclineChallenge {
+            conn,
+            challenge_id,
+            reason,
+            reply,
+        })
+        .await
+    }
+
+    /// Sends both sockets their seat in a game the matchmaker created and
+    /// withdraws their seeks. Fails without telling either if one of them
+    /// can't take it.
+    pub async fn start_game(
+        &self,
+        game: GameRow,
+        white_conn: Uuid,
+        black_conn: Uuid,
+    ) -> Result<(), AppError> {
+        self.request(|reply| Command::StartGame {
+            game: Box::new(game),
+            white_conn,
+            black_conn,
+            reply,
+        })
+        .await
+    }
+
+    /// Tells the lobby's sockets to reconnect later and stops taking seeks.
+    pub async fn shutdown(&self, reconnect_after_ms: u64) -> Result<(), AppError> {
+        self.request(|reply| Command::Shutdown {
+            reconnect_after_ms,
+            reply,
+        })
+        .await
+    }
+}
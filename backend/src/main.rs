@@ -1,51 +1,212 @@
+mod auth;
 mod chess;
+mod config;
 mod db;
 mod error;
+mod game;
+mod lobby;
 mod protocol;
+mod pubsub;
+mod rating;
 mod routes;
 mod state;
+mod telemetry;
 
+use axum::http::HeaderValue;
 use axum::routing::{get, post};
 use axum::Router;
-use tower_http::cors::CorsLayer;
+use clap::Parser;
+use sqlx::PgPool;
+use std::future::IntoFuture;
+use std::sync::Arc;
+use std::time::Duration;
+use tower_http::cors::{Any, CorsLayer};
 use tower_http::trace::TraceLayer;
-use tracing_subscriber::EnvFilter;
 
+use config::{Args, Config, DatabaseKind, PubSubKind};
+use db::memory::MemoryGameRepository;
+use db::metered::MeteredRepository;
+use db::repository::{GameRepository, PgGameRepository};
+use db::sqlite::SqliteGameRepository;
+use pubsub::{LocalPubSub, PgPubSub, PubSub};
 use state::AppState;
 
 #[tokio::main]
 async fn main() {
     dotenvy::dotenv().ok();
 
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()))
-        .init();
+    let args = Args::parse();
+    let config = Config::load(&args).unwrap_or_else(|e| {
+        eprintln!("Invalid configuration: {e}");
+        std::process::exit(1);
+    });
+    let tracer_provider = telemetry::init_tracing(&config.telemetry);
 
-    let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let pool = db::pool::create_pool(&database_url)
-        .await
-        .expect("Failed to create DB pool");
+    let database_url = config.database.url.as_deref().unwrap_or_default();
+    let (repo, pool): (Arc<dyn GameRepository>, Option<PgPool>) = match config.database_kind() {
+        Some(DatabaseKind::Sqlite) => {
+            let pool = db::pool::create_sqlite_pool(database_url, &config.database)
+                .await
+                .expect("Failed to open SQLite database");
+            tracing::info!("Opened SQLite database");
+            (Arc::new(SqliteGameRepository::new(pool)), None)
+        }
+        Some(DatabaseKind::Postgres) => {
+            let pool = db::pool::create_pool(database_url, &config.database)
+                .await
+                .expect("Failed to create DB pool");
+            tracing::info!("Connected to database");
+            (Arc::new(PgGameRepository::new(pool.clone())), Some(pool))
+        }
+        None => {
+            tracing::warn!("Using in-memory storage; games are lost on exit");
+            (Arc::new(MemoryGameRepository::default()), None)
+        }
+    };
+
+    let metrics = config.features.metrics.then(telemetry::install_metrics);
+    let repo: Arc<dyn GameRepository> = if metrics.is_some() {
+        Arc::new(MeteredRepository::new(repo))
+    } else {
+        repo
+    };
 
-    tracing::info!("Connected to database");
+    // Postgres fan-out lets several instances serve the same games
+    let pg_pubsub = match (config.pubsub(), &pool) {
+        (PubSubKind::Postgres, Some(pool)) => Some(Arc::new(PgPubSub::new(pool.clone()))),
+        _ => None,
+    };
+    let pubsub: Arc<dyn PubSub> = match &pg_pubsub {
+        Some(pg) => pg.clone(),
+        None => Arc::new(LocalPubSub),
+    };
+    // Seeks, challenges and the queue aren't shared, so a second instance
+    // serving the lobby would split it; such an instance only serves games
+    let (serve_lobby, _lobby_lock) = match (&pg_pubsub, &pool) {
+        (Some(_), Some(pool)) if config.features.lobby => match db::pool::claim_lobby(pool).await {
+            Ok(Some(lock)) => (true, Some(lock)),
+            Ok(None) => {
+                tracing::warn!("Another instance serves the lobby; not serving it here");
+                (false, None)
+            }
+            Err(e) => {
+                tracing::error!("Failed to take the lobby lock, not serving the lobby: {e}");
+                (false, None)
+            }
+        },
+        _ => (config.features.lobby, None),
+    };
+    let state = AppState::new(repo, pubsub, &config, metrics);
+    if let Some(pg) = pg_pubsub {
+        pg.listen(state.channels.clone());
+    }
 
-    let state = AppState::new(pool);
+    let cors = if config.cors.allowed_origins.iter().any(|o| o == "*") {
+        CorsLayer::permissive()
+    } else {
+        let origins: Vec<HeaderValue> = config
+            .cors
+            .allowed_origins
+            .iter()
+            .filter_map(|o| o.parse().ok())
+            .collect();
+        CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods(Any)
+            .allow_headers(Any)
+    };
 
-    let app = Router::new()
+    let mut app = Router::new()
         .route("/api/games", post(routes::games::create_game))
         .route("/api/games", get(routes::games::list_games))
         .route("/api/games/{id}", get(routes::games::get_game))
         .route("/api/games/{id}/join", post(routes::games::join_game))
         .route("/api/games/{id}/moves", post(routes::games::make_move))
+        .route("/api/games/{id}/seat", get(routes::games::my_seat))
+        .route("/api/auth/register", post(routes::auth::register))
+        .route("/api/auth/login", post(routes::auth::login))
+        .route("/api/auth/logout", post(routes::auth::logout))
+        .route("/api/auth/me", get(routes::auth::me))
+        .route("/api/users/{name}", get(routes::users::get_user))
+        .route("/api/users/{name}/games", get(routes::users::user_games))
+        .route("/api/users/{name}/rating-history", get(routes::users::rating_history))
+        .route("/api/leaderboard", get(routes::leaderboard::leaderboard))
+        .route("/api/admin/rooms", get(routes::admin::list_rooms))
         .route("/ws/games/{id}", get(routes::ws::ws_handler))
-        .layer(TraceLayer::new_for_http())
-        .layer(CorsLayer::permissive())
-        .with_state(state);
+        .route("/healthz", get(routes::health::healthz))
+        .route("/readyz", get(routes::health::readyz))
+        .route("/metrics", get(routes::health::metrics));
+    if serve_lobby {
+        app = app.route("/ws/lobby", get(routes::lobby::lobby_handler));
+    }
+    let app = app
+        .layer(TraceLayer::new_for_http().make_span_with(telemetry::request_span))
+        .layer(cors)
+        .with_state(state.clone());
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000")
+    let listener = tokio::net::TcpListener::bind(config.listen)
         .await
         .expect("Failed to bind");
 
-    tracing::info!("Server listening on http://0.0.0.0:3000");
+    tracing::info!("Server listening on http://{}", config.listen);
+
+    // Closing `stop` makes the server stop accepting connections
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let mut server = tokio::spawn(
+        axum::serve(listener, app)
+            .with_graceful_shutdown(async {
+                let _ = stopped.await;
+            })
+            .into_future(),
+    );
+
+    tokio::select! {
+        result = &mut server => {
+            result.expect("Server task panicked").expect("Server failed");
+            return;
+        }
+        _ = shutdown_signal() => {}
+    }
+
+    tracing::info!("Shutting down");
+    drop(stop);
+    let deadline = Duration::from_secs(config.shutdown.grace_secs);
+    let drained = tokio::time::timeout(deadline, async {
+        state.shutdown(config.shutdown.reconnect_after_ms).await;
+        let _ = server.await;
+    })
+    .await;
+    if drained.is_err() {
+        tracing::warn!("Shutdown took longer than {deadline:?}, exiting anyway");
+    }
+
+    if let Some(provider) = tracer_provider {
+        if let Err(e) = provider.shutdown() {
+            tracing::warn!("Failed to flush spans: {e}");
+        }
+    }
+}
+
+/// Resolves on Ctrl+C or SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for Ctrl+C");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
-    axum::serve(listener, app).await.expect("Server failed");
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
 }
@@ -1,7 +1,14 @@
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use uuid::Uuid;
 
-#[derive(Debug, Serialize, Clone)]
+use crate::db::models::{Clock, GameWithSecret, TimeControl, Variant};
+use crate::error::{AppError, ErrorCode};
+use crate::lobby::{
+    Challenge, ChallengeRemoval, DeclineReason, RatingRange, Seek, SeekColor, SeekRemoval,
+};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ServerMessage {
     GameState {
@@ -34,20 +41,331 @@ pub enum ServerMessage {
         status: String,
         result: Option<String>,
     },
+    /// Sent only to the opponent of the player offering a draw.
+    DrawOffered {
+        by: String,
+    },
+    /// Sent only to the player whose draw offer was declined.
+    DrawDeclined {
+        by: String,
+    },
+    /// Sent only to the opponent of the player offering a rematch.
+    RematchOffered {
+        by: String,
+    },
+    /// Sent only to the player whose rematch offer was declined.
+    RematchDeclined {
+        by: String,
+    },
+    /// The rematch of a finished game has been created.
+    RematchStarted {
+        game_id: Uuid,
+    },
+    /// Sent only to each player of a new rematch, with their seat in it.
+    RematchSeat {
+        game_id: Uuid,
+        color: String,
+        secret: Uuid,
+    },
+    /// Sent only to the connection that submitted the move.
+    MoveAccepted {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+        ply: u32,
+        #[serde(rename = "move")]
+        mv: String,
+        san: String,
+        /// True when the request repeated a move that had already been applied.
+        duplicate: bool,
+    },
+    /// Sent only to the connection that issued the request.
+    Ack {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+    },
+    /// Sent to every socket before the server goes down; the socket is then
+    /// closed and the client should reconnect after the hinted delay.
+    ServerShutdown {
+        reconnect_after_ms: u64,
+    },
+    /// Sent only to the connection that issued the request.
     Error {
         message: String,
+        code: ErrorCode,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        details: Option<Value>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
     },
 }
 
+impl ServerMessage {
+    pub fn error(err: &AppError, request_id: Option<String>) -> Self {
+        ServerMessage::Error {
+            message: err.public_message(),
+            code: err.code(),
+            details: err.details().cloned(),
+            request_id,
+        }
+    }
+}
+
+/// A broadcast game event stamped with its position in the game's event stream.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GameEvent {
+    pub seq: u64,
+    #[serde(flatten)]
+    pub msg: ServerMessage,
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ClientMessage {
+    /// Binds the connection to a seat so it receives messages meant for that
+    /// player and can act for them. Not needed if the socket was opened with a
+    /// `token` or by a signed-in player.
+    Authenticate {
+        secret: Uuid,
+        #[serde(default)]
+        request_id: Option<String>,
+    },
+    /// Moves, resignations and draw messages act for the seat the connection
+    /// is bound to.
     MakeMove {
         #[serde(rename = "move")]
         mv: String,
-        secret: Uuid,
+        #[serde(default)]
+        request_id: Option<String>,
+        /// The ply this move is meant to become (1 for White's first move).
+        #[serde(default)]
+        ply: Option<u32>,
     },
     Resign {
-        secret: Uuid,
+        #[serde(default)]
+        request_id: Option<String>,
+    },
+    OfferDraw {
+        #[serde(default)]
+        request_id: Option<String>,
+    },
+    AcceptDraw {
+        #[serde(default)]
+        request_id: Option<String>,
     },
+    DeclineDraw {
+        #[serde(default)]
+        request_id: Option<String>,
+    },
+    /// Offers the opponent a new game with the same settings and colors
+    /// swapped, once this one is over.
+    OfferRematch {
+        #[serde(default)]
+        request_id: Option<String>,
+    },
+    AcceptRematch {
+        #[serde(default)]
+        request_id: Option<String>,
+    },
+    DeclineRematch {
+        #[serde(default)]
+        request_id: Option<String>,
+    },
+}
+
+impl ClientMessage {
+    pub fn request_id(&self) -> Option<&str> {
+        match self {
+            ClientMessage::Authenticate { request_id, .. }
+            | ClientMessage::MakeMove { request_id, .. }
+            | ClientMessage::Resign { request_id, .. }
+            | ClientMessage::OfferDraw { request_id, .. }
+            | ClientMessage::AcceptDraw { request_id, .. }
+            | ClientMessage::DeclineDraw { request_id, .. }
+            | ClientMessage::OfferRematch { request_id, .. }
+            | ClientMessage::AcceptRematch { request_id, .. }
+            | ClientMessage::DeclineRematch { request_id, .. } => request_id.as_deref(),
+        }
+    }
+}
+
+/// Messages on `/ws/lobby`.
+#[derive(Debug, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LobbyServerMessage {
+    /// Every open seek, oldest first. Sent on connect and whenever the socket
+    /// fell too far behind to be caught up one change at a time.
+    Seeks {
+        seeks: Vec<Seek>,
+    },
+    SeekCreated {
+        seek: Seek,
+    },
+    SeekRemoved {
+        seek_id: Uuid,
+        reason: SeekRemoval,
+    },
+    /// The open challenges the signed-in player sent or received. Sent on
+    /// connect, to signed-in sockets only.
+    Challenges {
+        challenges: Vec<Challenge>,
+    },
+    /// Sent to every lobby socket of both the challenger and the recipient.
+    ChallengeCreated {
+        challenge: Challenge,
+    },
+    /// Sent to every lobby socket of both the challenger and the recipient.
+    ChallengeRemoved {
+        challenge_id: Uuid,
+        reason: ChallengeRemoval,
+        /// Why the recipient declined; only set for `declined`.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        decline_reason: Option<DeclineReason>,
+    },
+    /// Sent only to the two players of a game that just started, each with
+    /// their own seat secret.
+    GameStarted {
+        game: Box<GameWithSecret>,
+    },
+    /// Sent only to the connection that issued the request.
+    Ack {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+        /// The seek a `create_seek` request opened.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        seek_id: Option<Uuid>,
+        /// The challenge a `create_challenge` request sent.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        challenge_id: Option<Uuid>,
+    },
+    ServerShutdown {
+        reconnect_after_ms: u64,
+    },
+    /// Sent only to the connection that issued the request.
+    Error {
+        message: String,
+        code: ErrorCode,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        details: Option<Value>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+    },
+}
+
+impl LobbyServerMessage {
+    pub fn error(err: &AppError, request_id: Option<String>) -> Self {
+        LobbyServerMessage::Error {
+            message: err.public_message(),
+            code: err.code(),
+            details: err.details().cloned(),
+            request_id,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LobbyClientMessage {
+    /// Opens a seek that stays up until it's accepted, cancelled or the
+    /// socket disconnects.
+    CreateSeek {
+        #[serde(default)]
+        variant: Variant,
+        #[serde(default)]
+        clock: Option<Clock>,
+        #[serde(default)]
+        rated: bool,
+        #[serde(default)]
+        color: SeekColor,
+        /// Ratings an opponent needs in the seek's category.
+        #[serde(default)]
+        rating_range: Option<RatingRange>,
+        #[serde(default)]
+        request_id: Option<String>,
+    },
+    CancelSeek {
+        seek_id: Uuid,
+        #[serde(default)]
+        request_id: Option<String>,
+    },
+    /// Starts the seek's game against the player who posted it.
+    AcceptSeek {
+        seek_id: Uuid,
+        #[serde(default)]
+        request_id: Option<String>,
+    },
+    /// Waits to be paired with a player of similar rating; the game arrives
+    /// as `game_started`.
+    JoinQueue {
+        time_control: TimeControl,
+        #[serde(default)]
+        variant: Variant,
+        #[serde(default)]
+        rated: bool,
+        #[serde(default)]
+        request_id: Option<String>,
+    },
+    LeaveQueue {
+        #[serde(default)]
+        request_id: Option<String>,
+    },
+    /// Challenges a signed-in player by name. The challenge stays open until
+    /// it's answered, withdrawn or expires, or the socket disconnects.
+    CreateChallenge {
+        username: String,
+        #[serde(default)]
+        variant: Variant,
+        #[serde(default)]
+        clock: Option<Clock>,
+        #[serde(default)]
+        rated: bool,
+        /// The side the challenger wants.
+        #[serde(default)]
+        color: SeekColor,
+        /// Position to start from; casual games only.
+        #[serde(default)]
+        fen: Option<String>,
+        #[serde(default)]
+        request_id: Option<String>,
+    },
+    CancelChallenge {
+        challenge_id: Uuid,
+        #[serde(default)]
+        request_id: Option<String>,
+    },
+    /// Starts the challenge's game; only the recipient can accept.
+    AcceptChallenge {
+        challenge_id: Uuid,
+        #[serde(default)]
+        request_id: Option<String>,
+    },
+    DeclineChallenge {
+        challenge_id: Uuid,
+        #[serde(default)]
+        reason: DeclineReason,
+        #[serde(default)]
+        request_id: Option<String>,
+    },
+}
+
+impl LobbyClientMessage {
+    pub fn request_id(&self) -> Option<&str> {
+        match self {
+            LobbyClientMessage::CreateSeek { request_id, .. }
+            | LobbyClientMessage::CancelSeek { request_id, .. }
+            | LobbyClientMessage::AcceptSeek { request_id, .. }
+            | LobbyClientMessage::JoinQueue { request_id, .. }
+            | LobbyClientMessage::LeaveQueue { request_id, .. }
+            | LobbyClientMessage::CreateChallenge { request_id, .. }
+            | LobbyClientMessage::CancelChallenge { request_id, .. }
+            | LobbyClientMessage::AcceptChallenge { request_id, .. }
+            | LobbyClientMessage::DeclineChallenge { request_id, .. } => request_id.as_deref(),
+        }
+    }
+}
+
+/// Pulls `request_id` out of a frame that failed to parse as a `ClientMessage`,
+/// so the error can still be correlated by the client.
+pub fn extract_request_id(text: &str) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_str(text).ok()?;
+    value.get("request_id")?.as_str().map(str::to_string)
 }
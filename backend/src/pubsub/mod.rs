@@ -0,0 +1,77 @@
+mod postgres;
+
+pub use postgres::PgPubSub;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use shakmaty::Color;
+use uuid::Uuid;
+
+use crate::protocol::{GameEvent, ServerMessage};
+
+/// A persisted state change of one game, announced to every backend instance.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GameChange {
+    pub game_id: Uuid,
+    /// Version of the game row after the change.
+    pub version: i64,
+    /// Events the change produced; empty when they didn't fit the transport,
+    /// in which case receivers fall back to a snapshot.
+    pub events: Vec<GameEvent>,
+    /// The instance that made the change.
+    pub origin: Uuid,
+    /// Offers standing after the change. They are never written to the
+    /// database, so other instances take them from here.
+    pub draw_offer: Option<Seat>,
+    pub rematch_offer: Option<Seat>,
+    /// Messages for the sockets of one seat only, such as a draw offer.
+    pub seat_messages: Vec<SeatMessage>,
+}
+
+/// A seat of a game as it travels between instances.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Seat {
+    White,
+    Black,
+}
+
+impl From<Color> for Seat {
+    fn from(color: Color) -> Self {
+        match color {
+            Color::White => Seat::White,
+            Color::Black => Seat::Black,
+        }
+    }
+}
+
+impl From<Seat> for Color {
+    fn from(seat: Seat) -> Self {
+        match seat {
+            Seat::White => Color::White,
+            Seat::Black => Color::Black,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SeatMessage {
+    pub seat: Seat,
+    pub msg: ServerMessage,
+}
+
+/// Fans game changes out to the other instances, which relay them to their
+/// local sockets.
+#[async_trait]
+pub trait PubSub: Send + Sync {
+    /// Announces a change that has already been written to the database.
+    async fn publish(&self, change: GameChange);
+}
+
+/// For a single instance there is nobody else to tell.
+pub struct LocalPubSub;
+
+#[async_trait]
+impl PubSub for LocalPubSub {
+    async fn publish(&self, _change: GameChange) {}
+}
@@ -0,0 +1,94 @@
+use async_trait::async_trait;
+use sqlx::postgres::PgListener;
+use sqlx::PgPool;
+use std::time::Duration;
+
+use super::{GameChange, PubSub};
+use crate::game::SyncReason;
+use crate::state::GameChannels;
+
+const CHANNEL: &str = "game_changes";
+
+/// NOTIFY payloads must stay under 8000 bytes.
+const MAX_PAYLOAD_BYTES: usize = 7900;
+
+/// Relays game changes between instances through Postgres `LISTEN/NOTIFY`.
+pub struct PgPubSub {
+    pool: PgPool,
+}
+
+impl PgPubSub {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    /// Listens for changes made by any instance and hands them to the local
+    /// actor of that game, if this instance has one.
+    pub fn listen(&self, channels: GameChannels) {
+        let pool = self.pool.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = relay(&pool, &channels).await {
+                    tracing::error!("Game change listener failed, restarting: {e}");
+                }
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        });
+    }
+}
+
+async fn relay(pool: &PgPool, channels: &GameChannels) -> Result<(), sqlx::Error> {
+    let mut listener = PgListener::connect_with(pool).await?;
+    listener.listen(CHANNEL).await?;
+    // Anything could have happened while we weren't listening
+    resync_all(channels).await;
+
+    loop {
+        let Some(notification) = listener.try_recv().await? else {
+            tracing::warn!("Lost the game change listener connection, reconnecting");
+            // The next `try_recv` reconnects; changes made meanwhile were missed
+            resync_all(channels).await;
+            continue;
+        };
+
+        let change: GameChange = match serde_json::from_str(notification.payload()) {
+            Ok(change) => change,
+            Err(e) => {
+                tracing::warn!("Ignoring malformed game change: {e}");
+                continue;
+            }
+        };
+
+        let handle = channels.get(&change.game_id).map(|handle| handle.clone());
+        if let Some(handle) = handle {
+            handle.sync(SyncReason::Remote(change)).await;
+        }
+    }
+}
+
+async fn resync_all(channels: &GameChannels) {
+    let handles: Vec<_> = channels.iter().map(|e| e.value().clone()).collect();
+    for handle in handles {
+        handle.sync(SyncReason::Reconnected).await;
+    }
+}
+
+#[async_trait]
+impl PubSub for PgPubSub {
+    async fn publish(&self, mut change: GameChange) {
+        let mut payload = serde_json::to_string(&change).unwrap();
+        if payload.len() > MAX_PAYLOAD_BYTES {
+            change.events.clear();
+            payload = serde_json::to_string(&change).unwrap();
+        }
+
+        if let Err(e) = sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(CHANNEL)
+            .bind(payload)
+            .execute(&self.pool)
+            .await
+        {
+            tracing::error!("Failed to announce change to game {}: {e}", change.game_id);
+        }
+    }
+}
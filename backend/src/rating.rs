@@ -0,0 +1,159 @@
+use std::f64::consts::PI;
+
+pub const DEFAULT_RATING: f64 = 1500.0;
+pub const DEFAULT_DEVIATION: f64 = 350.0;
+pub const DEFAULT_VOLATILITY: f64 = 0.06;
+/// Ratings less certain than this are provisional.
+pub const PROVISIONAL_DEVIATION: f64 = 110.0;
+
+/// Keeps a very active player's rating from freezing in place.
+const MIN_DEVIATION: f64 = 45.0;
+/// Constrains how fast volatility can change.
+const TAU: f64 = 0.5;
+/// Converts between the Glicko and Glicko-2 scales.
+const SCALE: f64 = 173.7178;
+const CONVERGENCE: f64 = 0.000_001;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rating {
+    pub rating: f64,
+    pub deviation: f64,
+    pub volatility: f64,
+}
+
+impl Rating {
+    pub fn is_provisional(&self) -> bool {
+        self.deviation > PROVISIONAL_DEVIATION
+    }
+
+    /// The rating as shown to players.
+    pub fn rounded(&self) -> i32 {
+        self.rating.round() as i32
+    }
+}
+
+impl Default for Rating {
+    fn default() -> Self {
+        Rating {
+            rating: DEFAULT_RATING,
+            deviation: DEFAULT_DEVIATION,
+            volatility: DEFAULT_VOLATILITY,
+        }
+    }
+}
+
+/// Rates a game between `white` and `black` with Glicko-2
+/// (<http://www.glicko.net/glicko/glicko2.pdf>), treating the game as its own
+/// rating period. `white_score` is 1 for a white win, 0.5 for a draw and 0 for a loss.
+pub fn rate_game(white: Rating, black: Rating, white_score: f64) -> (Rating, Rating) {
+    (
+        update(white, &[(black, white_score)]),
+        update(black, &[(white, 1.0 - white_score)]),
+    )
+}
+
+fn g(phi: f64) -> f64 {
+    1.0 / (1.0 + 3.0 * phi * phi / (PI * PI)).sqrt()
+}
+
+/// Rates `player` on a rating period's games, each an opponent and the
+/// player's score against them.
+fn update(player: Rating, games: &[(Rating, f64)]) -> Rating {
+    let mu = (player.rating - DEFAULT_RATING) / SCALE;
+    let phi = player.deviation / SCALE;
+
+    let mut v_inv = 0.0;
+    let mut improvement = 0.0;
+    for &(opponent, score) in games {
+        let mu_j = (opponent.rating - DEFAULT_RATING) / SCALE;
+        let g_j = g(opponent.deviation / SCALE);
+        let expected = 1.0 / (1.0 + (-g_j * (mu - mu_j)).exp());
+        v_inv += g_j * g_j * expected * (1.0 - expected);
+        improvement += g_j * (score - expected);
+    }
+    let v = 1.0 / v_inv;
+    let delta = v * improvement;
+
+    let sigma = new_volatility(phi, player.volatility, v, delta);
+    let phi_star = (phi * phi + sigma * sigma).sqrt();
+    let phi_new = 1.0 / (1.0 / (phi_star * phi_star) + 1.0 / v).sqrt();
+    let mu_new = mu + phi_new * phi_new * improvement;
+
+    Rating {
+        rating: mu_new * SCALE + DEFAULT_RATING,
+        deviation: (phi_new * SCALE).clamp(MIN_DEVIATION, DEFAULT_DEVIATION),
+        volatility: sigma,
+    }
+}
+
+/// Step 5 of the paper: solves for the new volatility with the Illinois algorithm.
+fn new_volatility(phi: f64, sigma: f64, v: f64, delta: f64) -> f64 {
+    let a = (sigma * sigma).ln();
+    let f = |x: f64| {
+        let ex = x.exp();
+        let d = phi * phi + v + ex;
+        ex * (delta * delta - phi * phi - v - ex) / (2.0 * d * d) - (x - a) / (TAU * TAU)
+    };
+
+    let mut lower = a;
+    let mut upper = if delta * delta > phi * phi + v {
+        (delta * delta - phi * phi - v).ln()
+    } else {
+        let mut k = 1.0;
+        while f(a - k * TAU) < 0.0 {
+            k += 1.0;
+        }
+        a - k * TAU
+    };
+    let mut f_lower = f(lower);
+    let mut f_upper = f(upper);
+    while (upper - lower).abs() > CONVERGENCE {
+        let c = lower + (lower - upper) * f_lower / (f_upper - f_lower);
+        let f_c = f(c);
+        if f_c * f_upper <= 0.0 {
+            lower = upper;
+            f_lower = f_upper;
+        } else {
+            f_lower /= 2.0;
+        }
+        upper = c;
+        f_upper = f_c;
+    }
+    (lower / 2.0).exp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rating(rating: f64, deviation: f64) -> Rating {
+        Rating {
+            rating,
+            deviation,
+            volatility: DEFAULT_VOLATILITY,
+        }
+    }
+
+    #[test]
+    fn matches_the_papers_example() {
+        let player = rating(1500.0, 200.0);
+        let games = [
+            (rating(1400.0, 30.0), 1.0),
+            (rating(1550.0, 100.0), 0.0),
+            (rating(1700.0, 300.0), 0.0),
+        ];
+        let rated = update(player, &games);
+        assert!((rated.rating - 1464.06).abs() < 0.01, "{rated:?}");
+        assert!((rated.deviation - 151.52).abs() < 0.01, "{rated:?}");
+        assert!((rated.volatility - 0.05999).abs() < 0.00001, "{rated:?}");
+    }
+
+    #[test]
+    fn draw_between_equals_keeps_the_rating() {
+        let (white, black) = rate_game(Rating::default(), Rating::default(), 0.5);
+        for rated in [white, black] {
+            assert!((rated.rating - DEFAULT_RATING).abs() < 1e-9, "{rated:?}");
+            assert!(rated.deviation < DEFAULT_DEVIATION, "{rated:?}");
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use axum::extract::State;
+use axum::http::HeaderMap;
+use axum::Json;
+use serde::Serialize;
+use subtle::ConstantTimeEq;
+
+use crate::error::AppError;
+use crate::game::RoomStats;
+use crate::state::AppState;
+
+#[derive(Serialize)]
+pub struct RoomsResponse {
+    pub rooms: usize,
+    pub subscribers: usize,
+    /// Sum of the per-room estimates.
+    pub approx_room_bytes: usize,
+    /// Resident set size of the whole process, where the OS reports it.
+    pub process_rss_bytes: Option<u64>,
+    pub games: Vec<RoomStats>,
+}
+
+fn require_admin(state: &AppState, headers: &HeaderMap) -> Result<(), AppError> {
+    let Some(expected) = state.admin_token.as_deref() else {
+        return Err(AppError::NotFound("Admin API is disabled".to_string()));
+    };
+    let presented = headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    let matches = presented.is_some_and(|p| bool::from(p.as_bytes().ct_eq(expected.as_bytes())));
+    if !matches {
+        return Err(AppError::Unauthorized("Invalid admin token".to_string()));
+    }
+    Ok(())
+}
+
+/// Reads VmRSS from procfs; `None` on platforms without it.
+fn process_rss_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+pub async fn list_rooms(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<RoomsResponse>, AppError> {
+    require_admin(&state, &headers)?;
+
+    let handles: Vec<_> = state
+        .channels
+        .iter()
+        .map(|entry| entry.value().clone())
+        .collect();
+
+    let mut games = Vec::with_capacity(handles.len());
+    for handle in handles {
+        // Rooms that retire or fail to load meanwhile are simply left out
+        if let Ok(stats) = handle.stats().await {
+            games.push(stats);
+        }
+    }
+
+    Ok(Json(RoomsResponse {
+        rooms: games.len(),
+        subscribers: games.iter().map(|g| g.subscribers).sum(),
+        approx_room_bytes: games.iter().map(|g| g.approx_bytes).sum(),
+        process_rss_bytes: process_rss_bytes(),
+        games,
+    }))
+}
@@ -0,0 +1,120 @@
+use axum::extract::rejection::JsonRejection;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use axum_extra::extract::cookie::CookieJar;
+use chrono::{Duration, Utc};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::auth::{self, CurrentUser};
+use crate::db::models::{SessionResponse, UserResponse, UserRow};
+use crate::error::{AppError, ErrorCode};
+use crate::state::AppState;
+
+#[derive(Deserialize)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+fn invalid_field(field: &str, message: &str) -> AppError {
+    AppError::rejected(ErrorCode::InvalidRequest, message).with_details(json!({ "field": field }))
+}
+
+fn validate(credentials: &Credentials) -> Result<(), AppError> {
+    let name = &credentials.username;
+    if !(3..=24).contains(&name.len())
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(invalid_field(
+            "username",
+            "Username must be 3-24 letters, digits, '_' or '-'",
+        ));
+    }
+    if !(8..=128).contains(&credentials.password.chars().count()) {
+        return Err(invalid_field(
+            "password",
+            "Password must be 8-128 characters",
+        ));
+    }
+    Ok(())
+}
+
+/// Opens a session for `user`, returning it along with the cookie that carries it.
+async fn sign_in(
+    state: &AppState,
+    jar: CookieJar,
+    user: UserRow,
+) -> Result<(CookieJar, Json<SessionResponse>), AppError> {
+    let token = auth::new_session_token();
+    let expires_at = Utc::now() + Duration::days(state.auth.session_ttl_days.into());
+    state
+        .repo
+        .create_session(&auth::token_hash(&token), user.id, expires_at)
+        .await?;
+    let jar = jar.add(auth::session_cookie(token.clone(), &state.auth));
+    Ok((
+        jar,
+        Json(SessionResponse {
+            user: user.to_response(),
+            token,
+            expires_at,
+        }),
+    ))
+}
+
+pub async fn register(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    payload: Result<Json<Credentials>, JsonRejection>,
+) -> Result<(CookieJar, Json<SessionResponse>), AppError> {
+    let Json(credentials) = payload?;
+    validate(&credentials)?;
+
+    let hash = auth::hash_password(credentials.password).await?;
+    let user = state
+        .repo
+        .create_user(&credentials.username, &hash)
+        .await?
+        .ok_or_else(|| {
+            AppError::rejected(ErrorCode::UsernameTaken, "Username is already taken")
+                .with_details(json!({ "username": credentials.username }))
+        })?;
+    sign_in(&state, jar, user).await
+}
+
+pub async fn login(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    payload: Result<Json<Credentials>, JsonRejection>,
+) -> Result<(CookieJar, Json<SessionResponse>), AppError> {
+    let Json(credentials) = payload?;
+    let user = state.repo.get_user_by_name(&credentials.username).await?;
+    let hash = user.as_ref().map(|u| u.password_hash.clone());
+    if !auth::verify_password(credentials.password, hash).await? {
+        return Err(AppError::rejected(
+            ErrorCode::InvalidCredentials,
+            "Wrong username or password",
+        ));
+    }
+    let user = user.ok_or_else(|| AppError::Internal("Verified a missing user".to_string()))?;
+    sign_in(&state, jar, user).await
+}
+
+pub async fn logout(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    jar: CookieJar,
+) -> Result<(StatusCode, CookieJar), AppError> {
+    if let Some(token) = auth::session_token(&headers) {
+        state.repo.delete_session(&auth::token_hash(&token)).await?;
+    }
+    Ok((StatusCode::NO_CONTENT, jar.remove(auth::removal_cookie())))
+}
+
+pub async fn me(CurrentUser(user): CurrentUser) -> Json<UserResponse> {
+    Json(user.to_response())
+}
@@ -1,165 +1,289 @@
-use axum::extract::{Path, State};
+use axum::extract::rejection::{JsonRejection, QueryRejection};
+use axum::extract::{Path, Query, State};
 use axum::Json;
+use chrono::{Duration, Utc};
 use serde::Deserialize;
+use serde_json::json;
+use shakmaty::Color;
 use uuid::Uuid;
 
+use crate::auth::{CurrentUser, SeatCredential};
 use crate::chess;
-use crate::db::models::{GameResponse, GameStatus, GameWithSecret};
-use crate::db::queries;
-use crate::error::AppError;
-use crate::protocol::ServerMessage;
+use crate::db::models::{
+    Clock, GameResponse, GameRow, GameWithSecret, MoveResponse, NewGame, Variant,
+};
+use crate::error::{AppError, ErrorCode};
+use crate::game::MoveRequest;
 use crate::state::AppState;
 
+/// Optional settings for a new game; a request without a body gets a standard
+/// game with no clock.
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct CreateGameRequest {
+    #[serde(default)]
+    pub variant: Variant,
+    #[serde(default)]
+    pub clock: Option<Clock>,
+    /// Rated games need both players signed in.
+    #[serde(default)]
+    pub rated: bool,
+    /// Position to start from instead of the standard one; casual games only.
+    #[serde(default)]
+    pub fen: Option<String>,
+    /// Hides the game from listings; joining it needs the invite token.
+    #[serde(default)]
+    pub private: bool,
+    #[serde(default)]
+    pub invite: InviteSettings,
+}
+
+/// How the invite of a private game can be used.
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct InviteSettings {
+    /// How long the token works for; it doesn't expire if unset.
+    #[serde(default)]
+    pub expires_in_secs: Option<u32>,
+    /// Whether the token stops working once someone has joined with it.
+    #[serde(default)]
+    pub single_use: bool,
+    /// Keeps the open seat for this player.
+    #[serde(default)]
+    pub username: Option<String>,
+}
+
+impl InviteSettings {
+    fn is_set(&self) -> bool {
+        self.expires_in_secs.is_some() || self.single_use || self.username.is_some()
+    }
+}
+
+impl CreateGameRequest {
+    /// Checks the settings and sets the game up with `white_user` in the white
+    /// seat. Games created directly and accepted challenges both start here.
+    pub fn to_new_game(&self, white_user: Option<Uuid>) -> Result<NewGame, AppError> {
+        check_clock(self.clock)?;
+        let initial_fen = match &self.fen {
+            Some(fen) => check_start_position(fen)?,
+            None => None,
+        };
+        if self.rated && white_user.is_none() {
+            return Err(AppError::Unauthorized(
+                "Sign in to play rated games".to_string(),
+            ));
+        }
+        if self.rated && initial_fen.is_some() {
+            return Err(AppError::rejected(
+                ErrorCode::InvalidRequest,
+                "Rated games must start from the standard position",
+            )
+            .with_details(json!({ "field": "fen" })));
+        }
+        if !self.private && self.invite.is_set() {
+            return Err(AppError::rejected(
+                ErrorCode::InvalidRequest,
+                "Only private games have invites",
+            )
+            .with_details(json!({ "field": "invite" })));
+        }
+        if let Some(secs) = self.invite.expires_in_secs {
+            if secs == 0 || secs > 30 * 24 * 60 * 60 {
+                return Err(AppError::rejected(
+                    ErrorCode::InvalidRequest,
+                    "Invite must last between 1 second and 30 days",
+                )
+                .with_details(json!({ "field": "invite.expires_in_secs" })));
+            }
+        }
+        Ok(NewGame {
+            white_user,
+            variant: self.variant,
+            clock: self.clock,
+            rated: self.rated,
+            initial_fen,
+            private: self.private,
+            invite_expires_at: self
+                .invite
+                .expires_in_secs
+                .map(|secs| Utc::now() + Duration::seconds(secs.into())),
+            invite_single_use: self.invite.single_use,
+            ..NewGame::default()
+        })
+    }
+}
+
+/// Normalizes a custom starting position; `None` if it's the standard one.
+fn check_start_position(fen: &str) -> Result<Option<String>, AppError> {
+    let pos = chess::parse_fen(fen)?;
+    if chess::game_outcome(&pos).is_some() {
+        return Err(AppError::rejected(
+            ErrorCode::InvalidFen,
+            "The game would be over before the first move",
+        )
+        .with_details(json!({ "fen": fen })));
+    }
+    let fen = chess::position_to_fen(&pos);
+    Ok((fen != chess::START_FEN).then_some(fen))
+}
+
+/// Rejects clocks that can't make a playable game.
+pub fn check_clock(clock: Option<Clock>) -> Result<(), AppError> {
+    let Some(clock) = clock else { return Ok(()) };
+    if clock.initial_secs > 3 * 60 * 60
+        || clock.increment_secs > 180
+        || (clock.initial_secs == 0 && clock.increment_secs == 0)
+    {
+        return Err(AppError::rejected(
+            ErrorCode::InvalidRequest,
+            "Clock must start with at most 3 hours and add at most 180 seconds per move",
+        )
+        .with_details(json!({ "field": "clock" })));
+    }
+    Ok(())
+}
+
 pub async fn create_game(
     State(state): State<AppState>,
+    user: Option<CurrentUser>,
+    payload: Result<Option<Json<CreateGameRequest>>, JsonRejection>,
 ) -> Result<Json<GameWithSecret>, AppError> {
-    let game = queries::create_game(&state.db).await?;
-    Ok(Json(game.to_with_secret(game.white_secret, "white")))
+    let req = payload?.map(|Json(req)| req).unwrap_or_default();
+    let mut new_game = req.to_new_game(user.map(|u| u.0.id))?;
+    if let Some(username) = &req.invite.username {
+        new_game.invited_user = Some(invited_user(&state, username, new_game.white_user).await?);
+    }
+    let game = state.repo.create_game(&new_game).await?;
+    let mut response = game.to_with_secret(game.white_secret, "white");
+    response.invite = game.invite();
+    Ok(Json(response))
+}
+
+/// Looks up the player a private game's seat is kept for.
+async fn invited_user(
+    state: &AppState,
+    username: &str,
+    creator: Option<Uuid>,
+) -> Result<Uuid, AppError> {
+    let user = state
+        .repo
+        .get_user_by_name(username)
+        .await?
+        .ok_or_else(|| {
+            AppError::rejected(ErrorCode::UserNotFound, "User not found")
+                .with_details(json!({ "username": username }))
+        })?;
+    if creator == Some(user.id) {
+        return Err(
+            AppError::rejected(ErrorCode::InvalidRequest, "You can't invite yourself")
+                .with_details(json!({ "field": "invite.username" })),
+        );
+    }
+    Ok(user.id)
 }
 
 pub async fn list_games(
     State(state): State<AppState>,
 ) -> Result<Json<Vec<GameResponse>>, AppError> {
-    let games = queries::list_games(&state.db).await?;
+    let games = state.repo.list_games().await?;
     Ok(Json(games.iter().map(|g| g.to_response()).collect()))
 }
 
+async fn load_game(state: &AppState, id: Uuid) -> Result<GameRow, AppError> {
+    // A live game's actor is ahead of the database, so prefer it
+    match state.running_game(id) {
+        Some(handle) => handle.snapshot().await,
+        None => state
+            .repo
+            .get_game(id)
+            .await?
+            .ok_or_else(AppError::game_not_found),
+    }
+}
+
 pub async fn get_game(
     State(state): State<AppState>,
     Path(id): Path<Uuid>,
 ) -> Result<Json<GameResponse>, AppError> {
-    let game = queries::get_game(&state.db, id)
-        .await?
-        .ok_or_else(|| AppError::NotFound("Game not found".to_string()))?;
+    let game = load_game(&state, id).await?;
     Ok(Json(game.to_response()))
 }
 
+#[derive(Deserialize)]
+pub struct JoinQuery {
+    /// The invite token of a private game.
+    pub invite: Option<Uuid>,
+}
+
 pub async fn join_game(
     State(state): State<AppState>,
     Path(id): Path<Uuid>,
+    user: Option<CurrentUser>,
+    query: Result<Query<JoinQuery>, QueryRejection>,
 ) -> Result<Json<GameWithSecret>, AppError> {
-    // Check game exists and is joinable
-    let existing = queries::get_game(&state.db, id)
-        .await?
-        .ok_or_else(|| AppError::NotFound("Game not found".to_string()))?;
-
-    if existing.black_secret.is_some() {
-        return Err(AppError::Conflict("Game already has two players".to_string()));
-    }
+    let Query(query) = query?;
+    let (game, secret) = state
+        .game(id)
+        .join(user.map(|u| u.0.id), query.invite)
+        .await?;
+    Ok(Json(game.to_with_secret(secret, "black")))
+}
 
-    let game = queries::join_game(&state.db, id).await?;
+/// Hands a signed-in player the secret of their seat, e.g. on a new device.
+pub async fn my_seat(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    CurrentUser(user): CurrentUser,
+) -> Result<Json<GameWithSecret>, AppError> {
+    let game = load_game(&state, id).await?;
+    let seat = game.seat_of_user(user.id).ok_or_else(|| {
+        AppError::rejected(ErrorCode::NotAPlayer, "You don't have a seat in this game")
+    })?;
     let secret = game
-        .black_secret
-        .ok_or_else(|| AppError::Internal("Failed to generate black secret".to_string()))?;
-
-    // Broadcast player joined with legal moves
-    let pos = chess::parse_fen(&game.fen)?;
-    let legal = chess::legal_moves_uci(&pos);
-    state.broadcast(
-        id,
-        ServerMessage::PlayerJoined {
-            color: "black".to_string(),
-            fen: game.fen.clone(),
-            status: game.status.to_string(),
-            legal_moves: legal,
-        },
-    );
-
-    Ok(Json(game.to_with_secret(secret, "black")))
+        .secret_of(seat)
+        .ok_or_else(|| AppError::Internal("Seat has a user but no secret".to_string()))?;
+    let mut response = game.to_with_secret(secret, &seat.to_string());
+    // The creator may need the invite again to share it
+    if seat == Color::White {
+        response.invite = game.invite();
+    }
+    Ok(Json(response))
 }
 
 #[derive(Deserialize)]
 pub struct MakeMoveRequest {
     #[serde(rename = "move")]
     pub mv: String,
-    pub secret: Uuid,
+    /// Client-chosen id echoed in the response; retries with the same id are idempotent.
+    #[serde(default)]
+    pub request_id: Option<String>,
+    /// The ply this move is meant to become (1 for White's first move).
+    #[serde(default)]
+    pub ply: Option<u32>,
 }
 
 pub async fn make_move(
     State(state): State<AppState>,
     Path(id): Path<Uuid>,
-    Json(req): Json<MakeMoveRequest>,
-) -> Result<Json<GameResponse>, AppError> {
-    let game = queries::get_game(&state.db, id)
-        .await?
-        .ok_or_else(|| AppError::NotFound("Game not found".to_string()))?;
-
-    if game.status != GameStatus::Active {
-        return Err(AppError::BadRequest("Game is not active".to_string()));
-    }
-
-    // Verify player identity
-    let pos = chess::parse_fen(&game.fen)?;
-    let turn = chess::turn_color(&pos);
-    let is_white = req.secret == game.white_secret;
-    let is_black = game.black_secret.map_or(false, |s| s == req.secret);
-
-    if !is_white && !is_black {
-        return Err(AppError::Unauthorized("Invalid secret".to_string()));
-    }
-
-    let is_white_turn = turn == shakmaty::Color::White;
-    if (is_white_turn && !is_white) || (!is_white_turn && !is_black) {
-        return Err(AppError::BadRequest("Not your turn".to_string()));
-    }
-
-    // Apply move
-    let (new_pos, san) = chess::apply_uci_move(&game.fen, &req.mv)?;
-    let new_fen = chess::position_to_fen(&new_pos);
-
-    let mut new_moves = game.moves.clone();
-    new_moves.push(san.clone());
-
-    let (status, result) = chess::game_outcome(&new_pos)
-        .map(|(s, r)| {
-            let gs = match s {
-                "checkmate" => GameStatus::Checkmate,
-                "stalemate" => GameStatus::Stalemate,
-                _ => GameStatus::Draw,
-            };
-            (gs, r.map(|r| r.to_string()))
+    SeatCredential(credential): SeatCredential,
+    payload: Result<Json<MakeMoveRequest>, JsonRejection>,
+) -> Result<Json<MoveResponse>, AppError> {
+    let Json(req) = payload?;
+    let outcome = state
+        .game(id)
+        .make_move(MoveRequest {
+            uci: req.mv,
+            credential,
+            request_id: req.request_id.clone(),
+            ply: req.ply,
+            conn: None,
         })
-        .unwrap_or((GameStatus::Active, None));
-
-    let updated = queries::update_game_state(
-        &state.db,
-        id,
-        &new_fen,
-        &new_moves,
-        status.clone(),
-        result.as_deref(),
-    )
-    .await?;
-
-    // Broadcast
-    let legal = if status == GameStatus::Active {
-        chess::legal_moves_uci(&new_pos)
-    } else {
-        vec![]
-    };
-
-    state.broadcast(
-        id,
-        ServerMessage::MoveMade {
-            mv: req.mv,
-            san,
-            fen: new_fen.clone(),
-            moves: new_moves.clone(),
-            status: status.to_string(),
-            result: result.clone(),
-            legal_moves: legal,
-        },
-    );
-
-    if status != GameStatus::Active {
-        state.broadcast(
-            id,
-            ServerMessage::GameOver {
-                status: status.to_string(),
-                result,
-            },
-        );
-    }
+        .await?;
 
-    Ok(Json(updated.to_response()))
+    Ok(Json(MoveResponse {
+        game: outcome.game.to_response(),
+        request_id: req.request_id,
+        ply: outcome.receipt.ply,
+        mv: outcome.receipt.mv,
+        san: outcome.receipt.san,
+        duplicate: outcome.duplicate,
+    }))
 }
@@ -0,0 +1,40 @@
+use axum::extract::State;
+use axum::http::header;
+use axum::response::IntoResponse;
+use std::time::Duration;
+
+use crate::error::AppError;
+use crate::state::AppState;
+use crate::telemetry;
+
+/// How long `/readyz` waits for the database before reporting not ready.
+const READY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Liveness: the process is up and serving requests.
+pub async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// Readiness: the storage backend answers.
+pub async fn readyz(State(state): State<AppState>) -> Result<&'static str, AppError> {
+    match tokio::time::timeout(READY_TIMEOUT, state.repo.ping()).await {
+        Ok(Ok(())) => Ok("ready"),
+        Ok(Err(e)) => {
+            tracing::warn!("Readiness check failed: {e}");
+            Err(AppError::Unavailable("Database unreachable".to_string()))
+        }
+        Err(_) => Err(AppError::Unavailable("Database timed out".to_string())),
+    }
+}
+
+/// Prometheus scrape endpoint.
+pub async fn metrics(State(state): State<AppState>) -> Result<impl IntoResponse, AppError> {
+    let Some(handle) = state.metrics.as_ref() else {
+        return Err(AppError::NotFound("Metrics are disabled".to_string()));
+    };
+    telemetry::set_active_games(state.channels.len());
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        handle.render(),
+    ))
+}
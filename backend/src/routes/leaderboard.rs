@@ -0,0 +1,61 @@
+use axum::extract::rejection::QueryRejection;
+use axum::extract::{Query, State};
+use axum::Json;
+use chrono::{Duration, Utc};
+use serde::Deserialize;
+
+use crate::db::models::{Leaderboard, LeaderboardEntry, TimeControl, Variant};
+use crate::error::AppError;
+use crate::rating::PROVISIONAL_DEVIATION;
+use crate::state::AppState;
+
+const DEFAULT_LIMIT: u32 = 50;
+const MAX_LIMIT: u32 = 100;
+
+#[derive(Deserialize)]
+pub struct LeaderboardQuery {
+    /// The time control to rank, e.g. `blitz`.
+    pub category: TimeControl,
+    #[serde(default)]
+    pub variant: Variant,
+    pub limit: Option<u32>,
+}
+
+/// The top players in one category. Provisional ratings and players who
+/// haven't finished a rated game there within the configured window are left out.
+pub async fn leaderboard(
+    State(state): State<AppState>,
+    query: Result<Query<LeaderboardQuery>, QueryRejection>,
+) -> Result<Json<Leaderboard>, AppError> {
+    let Query(query) = query?;
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let active_since =
+        Utc::now() - Duration::days(i64::from(state.ratings.leaderboard_inactive_days));
+
+    let rows = state
+        .repo
+        .leaderboard(
+            query.variant,
+            query.category,
+            PROVISIONAL_DEVIATION,
+            active_since,
+            limit,
+        )
+        .await?;
+    Ok(Json(Leaderboard {
+        variant: query.variant,
+        time_control: query.category,
+        players: rows
+            .into_iter()
+            .zip(1..)
+            .map(|(row, rank)| LeaderboardEntry {
+                rank,
+                username: row.username,
+                rating: row.rating.round() as i32,
+                deviation: row.deviation.round() as i32,
+                games: row.games,
+                last_played_at: row.updated_at,
+            })
+            .collect(),
+    }))
+}
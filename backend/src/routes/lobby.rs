@@ -0,0 +1,307 @@
+use axum::extract::ws::{Message, WebSocket};
+use axum::extract::{State, WebSocketUpgrade};
+use axum::response::IntoResponse;
+use futures_util::stream::SplitSink;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::json;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tracing::{info_span, Instrument};
+use uuid::Uuid;
+
+use crate::auth::CurrentUser;
+use crate::error::{AppError, ErrorCode};
+use crate::lobby::matchmaker::{MatchmakerHandle, Pool};
+use crate::lobby::{ChallengeRequest, LobbyHandle, LobbyUser, SeekRequest};
+use crate::protocol::{extract_request_id, LobbyClientMessage, LobbyServerMessage};
+use crate::routes::games::{check_clock, CreateGameRequest};
+use crate::state::AppState;
+
+pub async fn lobby_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    user: Option<CurrentUser>,
+) -> impl IntoResponse {
+    let user = user.map(|CurrentUser(user)| LobbyUser {
+        id: user.id,
+        username: user.username,
+    });
+    let lobby = state.lobby.clone();
+    let matchmaker = state.matchmaker.clone();
+    let queue_size = state.rooms.outbound_queue_size;
+    ws.on_upgrade(move |socket| handle_socket(socket, lobby, matchmaker, user, queue_size))
+}
+
+async fn send(
+    ws_tx: &mut SplitSink<WebSocket, Message>,
+    msg: &LobbyServerMessage,
+) -> Result<(), axum::Error> {
+    let text = serde_json::to_string(msg).unwrap();
+    ws_tx.send(Message::Text(text.into())).await
+}
+
+async fn handle_socket(
+    socket: WebSocket,
+    lobby: LobbyHandle,
+    matchmaker: MatchmakerHandle,
+    user: Option<LobbyUser>,
+    outbound_queue_size: usize,
+) {
+    let (mut ws_tx, mut ws_rx) = socket.split();
+
+    let conn_id = Uuid::new_v4();
+    let (out_tx, mut out_rx) = mpsc::channel::<LobbyServerMessage>(outbound_queue_size);
+
+    let subscription = match lobby.connect(conn_id, user.clone(), out_tx.clone()).await {
+        Ok(subscription) => subscription,
+        Err(e) => {
+            tracing::error!("Failed to connect lobby socket: {e}");
+            return;
+        }
+    };
+    let mut rx = subscription.rx;
+    let seeks = LobbyServerMessage::Seeks {
+        seeks: subscription.seeks,
+    };
+    if send(&mut ws_tx, &seeks).await.is_err() {
+        lobby.disconnect(conn_id).await;
+        return;
+    }
+    if user.is_some() {
+        let challenges = LobbyServerMessage::Challenges {
+            challenges: subscription.challenges,
+        };
+        if send(&mut ws_tx, &challenges).await.is_err() {
+            lobby.disconnect(conn_id).await;
+            return;
+        }
+    }
+
+    // Task to forward seek changes and addressed messages to WebSocket
+    let send_lobby = lobby.clone();
+    let mut send_task = tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                msg = rx.recv() => match msg {
+                    Ok(msg) => {
+                        if send(&mut ws_tx, &msg).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!("Lobby socket {conn_id} lagged by {skipped} messages, resyncing");
+                        let Ok(seeks) = send_lobby.seeks().await else { break };
+                        if send(&mut ws_tx, &LobbyServerMessage::Seeks { seeks }).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                msg = out_rx.recv() => {
+                    let Some(msg) = msg else { break };
+                    if send(&mut ws_tx, &msg).await.is_err() {
+                        break;
+                    }
+                    if matches!(msg, LobbyServerMessage::ServerShutdown { .. }) {
+                        let _ = ws_tx.send(Message::Close(None)).await;
+                        break;
+                    }
+                }
+            }
+        }
+    });
+
+    // Task to handle incoming WebSocket messages
+    let conn = Connection {
+        id: conn_id,
+        user,
+        lobby: lobby.clone(),
+        matchmaker,
+        out: out_tx,
+    };
+    let mut recv_task = tokio::spawn(async move {
+        while let Some(Ok(msg)) = ws_rx.next().await {
+            if let Message::Text(text) = msg {
+                let span = info_span!("lobby.message", conn_id = %conn_id);
+                handle_client_message(&conn, &text).instrument(span).await;
+            }
+        }
+    });
+
+    tokio::select! {
+        _ = &mut send_task => recv_task.abort(),
+        _ = &mut recv_task => send_task.abort(),
+    }
+
+    lobby.disconnect(conn_id).await;
+}
+
+/// The lobby socket a client message arrived on.
+struct Connection {
+    id: Uuid,
+    user: Option<LobbyUser>,
+    lobby: LobbyHandle,
+    matchmaker: MatchmakerHandle,
+    out: mpsc::Sender<LobbyServerMessage>,
+}
+
+impl Connection {
+    async fn reply(&self, msg: LobbyServerMessage) {
+        let _ = self.out.send(msg).await;
+    }
+}
+
+/// What a request opened, for its ack.
+#[derive(Default)]
+struct Opened {
+    seek_id: Option<Uuid>,
+    challenge_id: Option<Uuid>,
+}
+
+async fn handle_client_message(conn: &Connection, text: &str) {
+    let msg: LobbyClientMessage = match serde_json::from_str(text) {
+        Ok(m) => m,
+        Err(e) => {
+            tracing::warn!("Invalid lobby message: {e}");
+            let err =
+                AppError::rejected(ErrorCode::InvalidRequest, format!("Invalid message: {e}"));
+            conn.reply(LobbyServerMessage::error(&err, extract_request_id(text)))
+                .await;
+            return;
+        }
+    };
+
+    let request_id = msg.request_id().map(str::to_string);
+    let result = match msg {
+        LobbyClientMessage::CreateSeek {
+            variant,
+            clock,
+            rated,
+            color,
+            rating_range,
+            ..
+        } => {
+            let req = SeekRequest {
+                variant,
+                clock,
+                rated,
+                color,
+                rating_range,
+            };
+            match check_seek(&req) {
+                Ok(()) => conn
+                    .lobby
+                    .create_seek(conn.id, req)
+                    .await
+                    .map(|seek_id| Opened {
+                        seek_id: Some(seek_id),
+                        ..Opened::default()
+                    }),
+                Err(e) => Err(e),
+            }
+        }
+        LobbyClientMessage::CancelSeek { seek_id, .. } => conn
+            .lobby
+            .cancel_seek(conn.id, seek_id)
+            .await
+            .map(|_| Opened::default()),
+        LobbyClientMessage::AcceptSeek { seek_id, .. } => conn
+            .lobby
+            .accept_seek(conn.id, seek_id)
+            .await
+            .map(|_| Opened::default()),
+        LobbyClientMessage::JoinQueue {
+            time_control,
+            variant,
+            rated,
+            ..
+        } => {
+            let pool = Pool {
+                variant,
+                time_control,
+                rated,
+            };
+            conn.matchmaker
+                .join(conn.id, conn.user.clone(), pool)
+                .await
+                .map(|_| Opened::default())
+        }
+        LobbyClientMessage::LeaveQueue { .. } => conn
+            .matchmaker
+            .leave(conn.id)
+            .await
+            .map(|_| Opened::default()),
+        LobbyClientMessage::CreateChallenge {
+            username,
+            variant,
+            clock,
+            rated,
+            color,
+            fen,
+            ..
+        } => {
+            let req = ChallengeRequest {
+                username,
+                settings: CreateGameRequest {
+                    variant,
+                    clock,
+                    rated,
+                    fen,
+                    ..CreateGameRequest::default()
+                },
+                color,
+            };
+            conn.lobby
+                .create_challenge(conn.id, req)
+                .await
+                .map(|challenge_id| Opened {
+                    challenge_id: Some(challenge_id),
+                    ..Opened::default()
+                })
+        }
+        LobbyClientMessage::CancelChallenge { challenge_id, .. } => conn
+            .lobby
+            .cancel_challenge(conn.id, challenge_id)
+            .await
+            .map(|_| Opened::default()),
+        LobbyClientMessage::AcceptChallenge { challenge_id, .. } => conn
+            .lobby
+            .accept_challenge(conn.id, challenge_id)
+            .await
+            .map(|_| Opened::default()),
+        LobbyClientMessage::DeclineChallenge {
+            challenge_id,
+            reason,
+            ..
+        } => conn
+            .lobby
+            .decline_challenge(conn.id, challenge_id, reason)
+            .await
+            .map(|_| Opened::default()),
+    };
+
+    let reply = match result {
+        Ok(opened) => LobbyServerMessage::Ack {
+            request_id,
+            seek_id: opened.seek_id,
+            challenge_id: opened.challenge_id,
+        },
+        Err(e) => LobbyServerMessage::error(&e, request_id),
+    };
+    conn.reply(reply).await;
+}
+
+/// Rejects seeks that could never start a playable game.
+fn check_seek(req: &SeekRequest) -> Result<(), AppError> {
+    check_clock(req.clock)?;
+    if let Some(range) = req.rating_range {
+        if range.min > range.max {
+            return Err(AppError::rejected(
+                ErrorCode::InvalidRequest,
+                "Rating range must not end below where it starts",
+            )
+            .with_details(json!({ "field": "rating_range" })));
+        }
+    }
+    Ok(())
+}
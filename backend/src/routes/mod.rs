@@ -1,2 +1,8 @@
+pub mod admin;
+pub mod auth;
 pub mod games;
+pub mod health;
+pub mod leaderboard;
+pub mod lobby;
+pub mod users;
 pub mod ws;
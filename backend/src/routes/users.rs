@@ -0,0 +1,99 @@
+use axum::extract::rejection::QueryRejection;
+use axum::extract::{Path, Query, State};
+use axum::Json;
+use serde::Deserialize;
+use serde_json::json;
+use shakmaty::Color;
+
+use crate::db::models::{
+    GameFilter, GamePage, PlayerResult, RatingHistory, RatingRow, TimeControl, UserProfile,
+    UserRow, Variant,
+};
+use crate::error::{AppError, ErrorCode};
+use crate::state::AppState;
+
+const DEFAULT_PAGE_SIZE: u32 = 20;
+const MAX_PAGE_SIZE: u32 = 100;
+
+async fn find_user(state: &AppState, name: &str) -> Result<UserRow, AppError> {
+    state.repo.get_user_by_name(name).await?.ok_or_else(|| {
+        AppError::rejected(ErrorCode::UserNotFound, "User not found")
+            .with_details(json!({ "username": name }))
+    })
+}
+
+pub async fn get_user(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<UserProfile>, AppError> {
+    let user = find_user(&state, &name).await?;
+    let games = state.repo.count_user_games(user.id).await?;
+    let ratings = state.repo.get_user_ratings(user.id).await?;
+    Ok(Json(UserProfile {
+        username: user.username,
+        created_at: user.created_at,
+        games,
+        ratings: ratings.iter().map(RatingRow::to_response).collect(),
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct UserGamesQuery {
+    pub result: Option<PlayerResult>,
+    /// The color the user played, `white` or `black`.
+    pub color: Option<String>,
+    pub variant: Option<Variant>,
+    pub time_control: Option<TimeControl>,
+    pub limit: Option<u32>,
+    #[serde(default)]
+    pub offset: u32,
+}
+
+pub async fn user_games(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    query: Result<Query<UserGamesQuery>, QueryRejection>,
+) -> Result<Json<GamePage>, AppError> {
+    let Query(query) = query?;
+    let color = query
+        .color
+        .map(|c| c.parse::<Color>())
+        .transpose()
+        .map_err(|_| {
+            AppError::rejected(ErrorCode::InvalidRequest, "Color must be white or black")
+                .with_details(json!({ "field": "color" }))
+        })?;
+    let filter = GameFilter {
+        result: query.result,
+        color,
+        variant: query.variant,
+        time_control: query.time_control,
+    };
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+
+    let user = find_user(&state, &name).await?;
+    // One extra row tells whether there is another page
+    let mut games = state
+        .repo
+        .list_user_games(user.id, &filter, limit + 1, query.offset)
+        .await?;
+    let next_offset = (games.len() > limit as usize).then(|| query.offset + limit);
+    games.truncate(limit as usize);
+
+    Ok(Json(GamePage {
+        games: games.iter().map(|g| g.to_response()).collect(),
+        next_offset,
+    }))
+}
+
+pub async fn rating_history(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<RatingHistory>, AppError> {
+    let user = find_user(&state, &name).await?;
+    let rows = state.repo.get_rating_history(user.id).await?;
+    Ok(Json(RatingHistory::from_rows(user.username, &rows)))
+}
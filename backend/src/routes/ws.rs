@@ -1,81 +1,167 @@
 use axum::extract::ws::{Message, WebSocket};
-use axum::extract::{Path, State, WebSocketUpgrade};
+use axum::extract::{Path, Query, State, WebSocketUpgrade};
 use axum::response::IntoResponse;
+use futures_util::stream::SplitSink;
 use futures_util::{SinkExt, StreamExt};
+use serde::Deserialize;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tracing::{info_span, Instrument};
 use uuid::Uuid;
 
-use crate::chess;
-use crate::db::models::GameStatus;
-use crate::db::queries;
-use crate::error::AppError;
-use crate::protocol::{ClientMessage, ServerMessage};
+use crate::auth::CurrentUser;
+use crate::error::{AppError, ErrorCode};
+use crate::game::{Catchup, Credential, GameHandle, MoveRequest, SeatAction};
+use crate::protocol::{extract_request_id, ClientMessage, GameEvent, ServerMessage};
 use crate::state::AppState;
+use crate::telemetry;
+
+#[derive(Deserialize)]
+pub struct WsParams {
+    /// Last event sequence number the client saw; missed events are replayed.
+    pub since: Option<u64>,
+    /// Seat secret that binds the socket to its seat on connect.
+    pub token: Option<String>,
+}
 
 pub async fn ws_handler(
     ws: WebSocketUpgrade,
     State(state): State<AppState>,
     Path(game_id): Path<Uuid>,
+    Query(params): Query<WsParams>,
+    user: Option<CurrentUser>,
 ) -> Result<impl IntoResponse, AppError> {
-    let game = queries::get_game(&state.db, game_id)
-        .await?
-        .ok_or_else(|| AppError::NotFound("Game not found".to_string()))?;
-
-    // Send initial state upon connection
-    let pos = chess::parse_fen(&game.fen)?;
-    let legal = if game.status == GameStatus::Active {
-        chess::legal_moves_uci(&pos)
-    } else {
-        vec![]
+    // Reject unknown games and bad tokens before upgrading
+    let game = state.game(game_id);
+    let row = game.snapshot().await?;
+    let credential = match params.token {
+        Some(token) => {
+            let secret = token
+                .parse()
+                .ok()
+                .filter(|secret| row.seat_of(*secret).is_some())
+                .ok_or_else(|| AppError::rejected(ErrorCode::InvalidSecret, "Invalid secret"))?;
+            Some(Credential::Secret(secret))
+        }
+        // A signed-in player's socket is bound to their seat without a token
+        None => user
+            .filter(|CurrentUser(user)| row.seat_of_user(user.id).is_some())
+            .map(|CurrentUser(user)| Credential::User(user.id)),
     };
 
-    let initial_state = ServerMessage::GameState {
-        id: game.id,
-        fen: game.fen,
-        moves: game.moves,
-        status: game.status.to_string(),
-        result: game.result,
-        legal_moves: legal,
-        white_connected: true,
-        black_connected: game.black_secret.is_some(),
-    };
+    let queue_size = state.rooms.outbound_queue_size;
+    Ok(ws.on_upgrade(move |socket| {
+        handle_socket(socket, game_id, game, params.since, credential, queue_size)
+    }))
+}
 
-    Ok(ws.on_upgrade(move |socket| handle_socket(socket, state, game_id, initial_state)))
+async fn send_event(
+    ws_tx: &mut SplitSink<WebSocket, Message>,
+    event: &GameEvent,
+) -> Result<(), axum::Error> {
+    let text = serde_json::to_string(event).unwrap();
+    ws_tx.send(Message::Text(text.into())).await
 }
 
 async fn handle_socket(
     socket: WebSocket,
-    state: AppState,
     game_id: Uuid,
-    initial_state: ServerMessage,
+    game: GameHandle,
+    since: Option<u64>,
+    credential: Option<Credential>,
+    outbound_queue_size: usize,
 ) {
     let (mut ws_tx, mut ws_rx) = socket.split();
 
-    // Subscribe to broadcast channel
-    let tx = state.get_or_create_channel(game_id);
-    let mut rx = tx.subscribe();
+    // Per-connection queue for replies and messages addressed to this socket only
+    let conn_id = Uuid::new_v4();
+    let (out_tx, mut out_rx) = mpsc::channel::<ServerMessage>(outbound_queue_size);
 
-    // Send initial state
-    let msg = serde_json::to_string(&initial_state).unwrap();
-    if ws_tx.send(Message::Text(msg.into())).await.is_err() {
-        return;
+    let subscription = match game.subscribe(conn_id, out_tx.clone(), since).await {
+        Ok(subscription) => subscription,
+        Err(e) => {
+            tracing::error!("Failed to subscribe socket: {e}");
+            return;
+        }
+    };
+    let mut rx = subscription.rx;
+    if let Some(credential) = credential {
+        let bound = game
+            .seat_action(SeatAction::Authenticate, credential, Some(conn_id))
+            .await;
+        if let Err(e) = bound {
+            let _ = out_tx.send(ServerMessage::error(&e, None)).await;
+        }
     }
 
-    // Task to forward broadcast messages to WebSocket
+    // Catch the client up, either by replaying missed events or with a snapshot
+    let catchup = match subscription.catchup {
+        Catchup::Replay(events) => events,
+        Catchup::Snapshot(event) => vec![event],
+    };
+    let mut last_seq = since.unwrap_or_default();
+    for event in &catchup {
+        if send_event(&mut ws_tx, event).await.is_err() {
+            game.unsubscribe(conn_id).await;
+            return;
+        }
+        last_seq = event.seq;
+    }
+
+    // Task to forward broadcast and addressed messages to WebSocket
+    let send_game = game.clone();
     let mut send_task = tokio::spawn(async move {
-        while let Ok(msg) = rx.recv().await {
-            let text = serde_json::to_string(&msg).unwrap();
-            if ws_tx.send(Message::Text(text.into())).await.is_err() {
-                break;
+        loop {
+            tokio::select! {
+                event = rx.recv() => match event {
+                    // Already covered by the catch-up replay or snapshot; a
+                    // broadcast snapshot replaces whatever the client had
+                    Ok(event)
+                        if event.seq <= last_seq
+                            && !matches!(event.msg, ServerMessage::GameState { .. }) => {}
+                    Ok(event) => {
+                        last_seq = event.seq;
+                        if send_event(&mut ws_tx, &event).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!("Socket {conn_id} lagged by {skipped} events, resyncing");
+                        telemetry::record_broadcast_lag(skipped);
+                        let Ok(snapshot) = send_game.resync().await else { break };
+                        last_seq = snapshot.seq;
+                        if send_event(&mut ws_tx, &snapshot).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                msg = out_rx.recv() => {
+                    let Some(msg) = msg else { break };
+                    let text = serde_json::to_string(&msg).unwrap();
+                    if ws_tx.send(Message::Text(text.into())).await.is_err() {
+                        break;
+                    }
+                    if matches!(msg, ServerMessage::ServerShutdown { .. }) {
+                        let _ = ws_tx.send(Message::Close(None)).await;
+                        break;
+                    }
+                }
             }
         }
     });
 
     // Task to handle incoming WebSocket messages
-    let state_clone = state.clone();
+    let conn = Connection {
+        id: conn_id,
+        game: game.clone(),
+        out: out_tx,
+    };
     let mut recv_task = tokio::spawn(async move {
         while let Some(Ok(msg)) = ws_rx.next().await {
             if let Message::Text(text) = msg {
-                handle_client_message(&state_clone, game_id, &text).await;
+                let span = info_span!("ws.message", game_id = %game_id, conn_id = %conn_id);
+                handle_client_message(&conn, &text).instrument(span).await;
             }
         }
     });
@@ -85,163 +171,85 @@ async fn handle_socket(
         _ = &mut send_task => recv_task.abort(),
         _ = &mut recv_task => send_task.abort(),
     }
+
+    game.unsubscribe(conn_id).await;
+}
+
+/// The socket a client message arrived on.
+struct Connection {
+    id: Uuid,
+    game: GameHandle,
+    out: mpsc::Sender<ServerMessage>,
+}
+
+impl Connection {
+    async fn reply(&self, msg: ServerMessage) {
+        let _ = self.out.send(msg).await;
+    }
 }
 
-async fn handle_client_message(state: &AppState, game_id: Uuid, text: &str) {
+async fn handle_client_message(conn: &Connection, text: &str) {
     let msg: ClientMessage = match serde_json::from_str(text) {
         Ok(m) => m,
         Err(e) => {
             tracing::warn!("Invalid WS message: {e}");
+            let err =
+                AppError::rejected(ErrorCode::InvalidRequest, format!("Invalid message: {e}"));
+            conn.reply(ServerMessage::error(&err, extract_request_id(text)))
+                .await;
             return;
         }
     };
 
-    match msg {
-        ClientMessage::MakeMove { mv, secret } => {
-            if let Err(e) = handle_make_move(state, game_id, &mv, secret).await {
-                state.broadcast(
-                    game_id,
-                    ServerMessage::Error {
-                        message: e.to_string(),
-                    },
-                );
-            }
+    let request_id = msg.request_id().map(str::to_string);
+
+    // Only `authenticate` carries a secret; everything else acts for the seat
+    // the socket is bound to
+    let socket = Credential::Socket(conn.id);
+    let (action, credential) = match msg {
+        ClientMessage::MakeMove { mv, ply, .. } => {
+            let outcome = conn
+                .game
+                .make_move(MoveRequest {
+                    uci: mv,
+                    credential: socket,
+                    request_id: request_id.clone(),
+                    ply,
+                    conn: Some(conn.id),
+                })
+                .await;
+            let reply = match outcome {
+                Ok(outcome) => ServerMessage::MoveAccepted {
+                    request_id,
+                    ply: outcome.receipt.ply,
+                    mv: outcome.receipt.mv,
+                    san: outcome.receipt.san,
+                    duplicate: outcome.duplicate,
+                },
+                Err(e) => ServerMessage::error(&e, request_id),
+            };
+            conn.reply(reply).await;
+            return;
         }
-        ClientMessage::Resign { secret } => {
-            if let Err(e) = handle_resign(state, game_id, secret).await {
-                state.broadcast(
-                    game_id,
-                    ServerMessage::Error {
-                        message: e.to_string(),
-                    },
-                );
-            }
+        ClientMessage::Authenticate { secret, .. } => {
+            (SeatAction::Authenticate, Credential::Secret(secret))
         }
-    }
-}
-
-async fn handle_make_move(
-    state: &AppState,
-    game_id: Uuid,
-    uci_move: &str,
-    secret: Uuid,
-) -> Result<(), AppError> {
-    let game = queries::get_game(&state.db, game_id)
-        .await?
-        .ok_or_else(|| AppError::NotFound("Game not found".to_string()))?;
-
-    if game.status != GameStatus::Active {
-        return Err(AppError::BadRequest("Game is not active".to_string()));
-    }
-
-    let pos = chess::parse_fen(&game.fen)?;
-    let turn = chess::turn_color(&pos);
-    let is_white = secret == game.white_secret;
-    let is_black = game.black_secret.map_or(false, |s| s == secret);
-
-    if !is_white && !is_black {
-        return Err(AppError::Unauthorized("Invalid secret".to_string()));
-    }
-
-    let is_white_turn = turn == shakmaty::Color::White;
-    if (is_white_turn && !is_white) || (!is_white_turn && !is_black) {
-        return Err(AppError::BadRequest("Not your turn".to_string()));
-    }
-
-    let (new_pos, san) = chess::apply_uci_move(&game.fen, uci_move)?;
-    let new_fen = chess::position_to_fen(&new_pos);
-
-    let mut new_moves = game.moves.clone();
-    new_moves.push(san.clone());
-
-    let (status, result) = chess::game_outcome(&new_pos)
-        .map(|(s, r)| {
-            let gs = match s {
-                "checkmate" => GameStatus::Checkmate,
-                "stalemate" => GameStatus::Stalemate,
-                _ => GameStatus::Draw,
-            };
-            (gs, r.map(|r| r.to_string()))
-        })
-        .unwrap_or((GameStatus::Active, None));
-
-    queries::update_game_state(
-        &state.db,
-        game_id,
-        &new_fen,
-        &new_moves,
-        status.clone(),
-        result.as_deref(),
-    )
-    .await?;
-
-    let legal = if status == GameStatus::Active {
-        chess::legal_moves_uci(&new_pos)
-    } else {
-        vec![]
+        ClientMessage::Resign { .. } => (SeatAction::Resign, socket),
+        ClientMessage::OfferDraw { .. } => (SeatAction::OfferDraw, socket),
+        ClientMessage::AcceptDraw { .. } => (SeatAction::AcceptDraw, socket),
+        ClientMessage::DeclineDraw { .. } => (SeatAction::DeclineDraw, socket),
+        ClientMessage::OfferRematch { .. } => (SeatAction::OfferRematch, socket),
+        ClientMessage::AcceptRematch { .. } => (SeatAction::AcceptRematch, socket),
+        ClientMessage::DeclineRematch { .. } => (SeatAction::DeclineRematch, socket),
     };
 
-    state.broadcast(
-        game_id,
-        ServerMessage::MoveMade {
-            mv: uci_move.to_string(),
-            san,
-            fen: new_fen,
-            moves: new_moves,
-            status: status.to_string(),
-            result: result.clone(),
-            legal_moves: legal,
-        },
-    );
-
-    if status != GameStatus::Active {
-        state.broadcast(
-            game_id,
-            ServerMessage::GameOver {
-                status: status.to_string(),
-                result,
-            },
-        );
-    }
-
-    Ok(())
-}
-
-async fn handle_resign(state: &AppState, game_id: Uuid, secret: Uuid) -> Result<(), AppError> {
-    let game = queries::get_game(&state.db, game_id)
-        .await?
-        .ok_or_else(|| AppError::NotFound("Game not found".to_string()))?;
-
-    if game.status != GameStatus::Active {
-        return Err(AppError::BadRequest("Game is not active".to_string()));
-    }
-
-    let is_white = secret == game.white_secret;
-    let is_black = game.black_secret.map_or(false, |s| s == secret);
-
-    if !is_white && !is_black {
-        return Err(AppError::Unauthorized("Invalid secret".to_string()));
-    }
-
-    let winner = if is_white { "black" } else { "white" };
-
-    queries::update_game_state(
-        &state.db,
-        game_id,
-        &game.fen,
-        &game.moves,
-        GameStatus::Resigned,
-        Some(winner),
-    )
-    .await?;
-
-    state.broadcast(
-        game_id,
-        ServerMessage::GameOver {
-            status: "resigned".to_string(),
-            result: Some(winner.to_string()),
-        },
-    );
-
-    Ok(())
+    let reply = match conn
+        .game
+        .seat_action(action, credential, Some(conn.id))
+        .await
+    {
+        Ok(_) => ServerMessage::Ack { request_id },
+        Err(e) => ServerMessage::error(&e, request_id),
+    };
+    conn.reply(reply).await;
 }
@@ -1,37 +1,95 @@
 use dashmap::DashMap;
-use sqlx::PgPool;
+use futures_util::future::join_all;
+use metrics_exporter_prometheus::PrometheusHandle;
 use std::sync::Arc;
-use tokio::sync::broadcast;
 use uuid::Uuid;
 
-use crate::protocol::ServerMessage;
+use crate::config::{AuthConfig, Config, RatingsConfig, RoomConfig};
+use crate::db::repository::GameRepository;
+use crate::game::GameHandle;
+use crate::lobby::matchmaker::MatchmakerHandle;
+use crate::lobby::LobbyHandle;
+use crate::pubsub::PubSub;
 
-pub type GameChannels = Arc<DashMap<Uuid, broadcast::Sender<ServerMessage>>>;
+/// Actors of the games currently live in this process.
+pub type GameChannels = Arc<DashMap<Uuid, GameHandle>>;
 
 #[derive(Clone)]
 pub struct AppState {
-    pub db: PgPool,
+    pub repo: Arc<dyn GameRepository>,
     pub channels: GameChannels,
+    /// Open seeks and challenges, and the sockets watching them.
+    pub lobby: LobbyHandle,
+    /// Pairs players queued from the lobby.
+    pub matchmaker: MatchmakerHandle,
+    /// Bearer token for the admin API; the API is disabled when unset.
+    pub admin_token: Option<String>,
+    /// Relays game changes between backend instances.
+    pub pubsub: Arc<dyn PubSub>,
+    /// Tells the changes this process announced apart from other instances'.
+    pub instance_id: Uuid,
+    pub rooms: Arc<RoomConfig>,
+    pub auth: Arc<AuthConfig>,
+    pub ratings: Arc<RatingsConfig>,
+    /// Renders `/metrics`; `None` when metrics are disabled.
+    pub metrics: Option<PrometheusHandle>,
 }
 
 impl AppState {
-    pub fn new(db: PgPool) -> Self {
+    pub fn new(
+        repo: Arc<dyn GameRepository>,
+        pubsub: Arc<dyn PubSub>,
+        config: &Config,
+        metrics: Option<PrometheusHandle>,
+    ) -> Self {
+        let rooms = &config.rooms;
+        let (matchmaker, mailbox) = MatchmakerHandle::new(rooms.mailbox_size);
+        let lobby = LobbyHandle::spawn(
+            repo.clone(),
+            rooms,
+            config.challenges.clone(),
+            matchmaker.clone(),
+        );
+        MatchmakerHandle::start(
+            mailbox,
+            repo.clone(),
+            lobby.clone(),
+            config.matchmaking.clone(),
+        );
         Self {
-            db,
+            lobby,
+            matchmaker,
+            repo,
             channels: Arc::new(DashMap::new()),
+            admin_token: config.admin_token(),
+            pubsub,
+            instance_id: Uuid::new_v4(),
+            rooms: Arc::new(rooms.clone()),
+            auth: Arc::new(config.auth.clone()),
+            ratings: Arc::new(config.ratings.clone()),
+            metrics,
         }
     }
 
-    pub fn get_or_create_channel(&self, game_id: Uuid) -> broadcast::Sender<ServerMessage> {
+    /// Returns the actor for a game, starting one if it isn't running yet.
+    pub fn game(&self, game_id: Uuid) -> GameHandle {
         self.channels
             .entry(game_id)
-            .or_insert_with(|| broadcast::channel(64).0)
+            .or_insert_with(|| GameHandle::spawn(self.clone(), game_id))
             .clone()
     }
 
-    pub fn broadcast(&self, game_id: Uuid, msg: ServerMessage) {
-        if let Some(tx) = self.channels.get(&game_id) {
-            let _ = tx.send(msg);
-        }
+    /// Has the lobby and every live game tell their sockets to reconnect later,
+    /// and the games write out pending changes.
+    pub async fn shutdown(&self, reconnect_after_ms: u64) {
+        let _ = self.matchmaker.shutdown().await;
+        let _ = self.lobby.shutdown(reconnect_after_ms).await;
+        let handles: Vec<GameHandle> = self.channels.iter().map(|e| e.value().clone()).collect();
+        join_all(handles.iter().map(|h| h.shutdown(reconnect_after_ms))).await;
+    }
+
+    /// Returns the actor for a game only if one is already running.
+    pub fn running_game(&self, game_id: Uuid) -> Option<GameHandle> {
+        self.channels.get(&game_id).map(|handle| handle.clone())
     }
 }
@@ -0,0 +1,126 @@
+use axum::http::Request;
+use metrics::{counter, gauge, histogram};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use metrics_util::MetricKindMask;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use std::time::Duration;
+use tracing::Span;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+use crate::config::TelemetryConfig;
+use crate::error::AppError;
+
+/// The span of an HTTP request. Only the path is recorded: the query can hold
+/// a player's seat secret, as in `/ws/games/{id}?token=…`, or a private game's
+/// invite, as in `/api/games/{id}/join?invite=…`.
+pub fn request_span<B>(request: &Request<B>) -> Span {
+    tracing::debug_span!(
+        "request",
+        method = %request.method(),
+        path = %request.uri().path(),
+        version = ?request.version(),
+    )
+}
+
+/// Sets up logging and, when an OTLP endpoint is configured, span export.
+/// The returned provider must be shut down on exit to flush buffered spans.
+pub fn init_tracing(config: &TelemetryConfig) -> Option<SdkTracerProvider> {
+    let provider = config.otlp_endpoint.as_ref().map(|endpoint| {
+        let exporter = SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .build()
+            .expect("Failed to create OTLP exporter");
+        SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(
+                Resource::builder()
+                    .with_service_name(config.service_name.clone())
+                    .build(),
+            )
+            .build()
+    });
+    let otel = provider.as_ref().map(|provider| {
+        tracing_opentelemetry::layer().with_tracer(provider.tracer(config.service_name.clone()))
+    });
+
+    tracing_subscriber::registry()
+        .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()))
+        .with(tracing_subscriber::fmt::layer())
+        .with(otel)
+        .init();
+
+    if let Some(endpoint) = &config.otlp_endpoint {
+        tracing::info!("Exporting spans to {endpoint}");
+    }
+    provider
+}
+
+/// Per-game gauges are refreshed by their room at least this often; once a room
+/// retires its series stop being refreshed and are dropped.
+const GAUGE_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+const DB_LATENCY_BUCKETS: [f64; 10] =
+    [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+
+/// Installs the global metrics recorder and returns the handle that renders it.
+pub fn install_metrics() -> PrometheusHandle {
+    let handle = PrometheusBuilder::new()
+        .idle_timeout(MetricKindMask::GAUGE, Some(GAUGE_IDLE_TIMEOUT))
+        .set_buckets_for_metric(
+            Matcher::Full("chess_db_query_duration_seconds".to_string()),
+            &DB_LATENCY_BUCKETS,
+        )
+        .expect("Invalid histogram buckets")
+        .install_recorder()
+        .expect("Failed to install metrics recorder");
+
+    let upkeep = handle.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(5));
+        loop {
+            interval.tick().await;
+            upkeep.run_upkeep();
+        }
+    });
+    handle
+}
+
+pub fn set_active_games(count: usize) {
+    gauge!("chess_active_games").set(count as f64);
+}
+
+/// Game sockets open across all rooms. Counts per game are left to the admin
+/// rooms API: a label per game would never be cleaned up and would publish
+/// the ids of private games.
+pub fn add_game_sockets(delta: isize) {
+    gauge!("chess_game_sockets").increment(delta as f64);
+}
+
+pub fn record_move() {
+    counter!("chess_moves_total").increment(1);
+}
+
+pub fn record_rejected_move(err: &AppError) {
+    counter!("chess_move_rejections_total", "code" => err.code().as_str()).increment(1);
+}
+
+pub fn record_db_query(operation: &'static str, elapsed: Duration, ok: bool) {
+    histogram!(
+        "chess_db_query_duration_seconds",
+        "operation" => operation,
+        "outcome" => if ok { "ok" } else { "error" },
+    )
+    .record(elapsed.as_secs_f64());
+}
+
+/// A socket fell behind the broadcast channel and missed `skipped` events.
+pub fn record_broadcast_lag(skipped: u64) {
+    counter!("chess_broadcast_lag_events_total").increment(1);
+    counter!("chess_broadcast_skipped_events_total").increment(skipped);
+}
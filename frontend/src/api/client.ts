@@ -1,5 +1,25 @@
+import type {
+  ErrorCode,
+  GameSettings,
+  TimeControl,
+  UserGamesQuery,
+  Variant,
+} from "./types";
+
 const BASE = "/api";
 
+export class ApiError extends Error {
+  constructor(
+    message: string,
+    public status: number,
+    public code?: ErrorCode,
+    public details?: Record<string, unknown>,
+  ) {
+    super(message);
+    this.name = "ApiError";
+  }
+}
+
 async function request<T>(path: string, options?: RequestInit): Promise<T> {
   const res = await fetch(`${BASE}${path}`, {
     headers: { "Content-Type": "application/json" },
@@ -8,20 +28,78 @@ async function request<T>(path: string, options?: RequestInit): Promise<T> {
 
   if (!res.ok) {
     const body = await res.json().catch(() => ({ error: res.statusText }));
-    throw new Error(body.error || res.statusText);
+    throw new ApiError(
+      body.error || res.statusText,
+      res.status,
+      body.code,
+      body.details,
+    );
   }
 
+  if (res.status === 204) {
+    return undefined as T;
+  }
   return res.json();
 }
 
 export const api = {
-  createGame: () => request("/games", { method: "POST" }),
+  createGame: (settings: GameSettings = {}) =>
+    request("/games", { method: "POST", body: JSON.stringify(settings) }),
   listGames: () => request("/games"),
   getGame: (id: string) => request(`/games/${id}`),
-  joinGame: (id: string) => request(`/games/${id}/join`, { method: "POST" }),
-  makeMove: (id: string, mv: string, secret: string) =>
+  joinGame: (id: string, invite?: string) =>
+    request(
+      `/games/${id}/join${invite ? `?invite=${encodeURIComponent(invite)}` : ""}`,
+      { method: "POST" },
+    ),
+  mySeat: (id: string) => request(`/games/${id}/seat`),
+  makeMove: (
+    id: string,
+    mv: string,
+    secret: string,
+    requestId?: string,
+    ply?: number,
+  ) =>
     request(`/games/${id}/moves`, {
       method: "POST",
-      body: JSON.stringify({ move: mv, secret }),
+      headers: {
+        "Content-Type": "application/json",
+        Authorization: `Seat ${secret}`,
+      },
+      body: JSON.stringify({ move: mv, request_id: requestId, ply }),
+    }),
+  register: (username: string, password: string) =>
+    request("/auth/register", {
+      method: "POST",
+      body: JSON.stringify({ username, password }),
+    }),
+  login: (username: string, password: string) =>
+    request("/auth/login", {
+      method: "POST",
+      body: JSON.stringify({ username, password }),
     }),
+  logout: () => request("/auth/logout", { method: "POST" }),
+  me: () => request("/auth/me"),
+  getUser: (name: string) => request(`/users/${encodeURIComponent(name)}`),
+  userGames: (name: string, query: UserGamesQuery = {}) => {
+    const params = new URLSearchParams();
+    for (const [key, value] of Object.entries(query)) {
+      if (value !== undefined) params.set(key, String(value));
+    }
+    const search = params.toString();
+    return request(
+      `/users/${encodeURIComponent(name)}/games${search ? `?${search}` : ""}`,
+    );
+  },
+  ratingHistory: (name: string) =>
+    request(`/users/${encodeURIComponent(name)}/rating-history`),
+  leaderboard: (
+    category: TimeControl,
+    variant: Variant = "standard",
+    limit?: number,
+  ) => {
+    const params = new URLSearchParams({ category, variant });
+    if (limit !== undefined) params.set("limit", String(limit));
+    return request(`/leaderboard?${params}`);
+  },
 };
@@ -4,17 +4,178 @@ export interface Game {
   moves: string[];
   status: string;
   result: string | null;
+  version: number;
   created_at: string;
   updated_at: string;
   has_black: boolean;
+  white_user_id: string | null;
+  black_user_id: string | null;
+  variant: Variant;
+  time_control: TimeControl;
+  clock: Clock | null;
+  rated: boolean;
+  /** Set shortly after a rated game ends. */
+  ratings: { white: RatingChange; black: RatingChange } | null;
+  /** Absent when the game started from the standard position. */
+  initial_fen?: string;
+  /** The game this one is a rematch of. */
+  rematch_of: string | null;
+  /** The rematch played after this game. */
+  rematch_id: string | null;
+  /** Left out of listings; joining needs the invite token. */
+  private: boolean;
+  /** The only player who may take the open seat. */
+  invited_user_id: string | null;
+}
+
+export interface RatingChange {
+  before: number;
+  after: number;
+  change: number;
+}
+
+export interface Rating {
+  variant: Variant;
+  time_control: TimeControl;
+  rating: number;
+  deviation: number;
+  games: number;
+  provisional: boolean;
+}
+
+export type Variant = "standard";
+
+export type TimeControl =
+  | "bullet"
+  | "blitz"
+  | "rapid"
+  | "classical"
+  | "correspondence";
+
+export interface Clock {
+  initial_secs: number;
+  increment_secs: number;
+}
+
+export interface GameSettings {
+  variant?: Variant;
+  clock?: Clock | null;
+  /** Needs both players signed in. */
+  rated?: boolean;
+  /** Position to start from; casual games only. */
+  fen?: string | null;
+  private?: boolean;
+  /** Only for private games. */
+  invite?: InviteSettings;
+}
+
+export interface InviteSettings {
+  /** Unset means the invite doesn't expire. */
+  expires_in_secs?: number | null;
+  /** The token stops working once someone has joined with it. */
+  single_use?: boolean;
+  /** Keeps the open seat for this player. */
+  username?: string | null;
+}
+
+export interface Invite {
+  token: string;
+  expires_at: string | null;
+  single_use: boolean;
+}
+
+export interface GamePage {
+  games: Game[];
+  /** Pass as `offset` for the next page; absent on the last one. */
+  next_offset?: number | null;
+}
+
+export interface GameCounts {
+  total: number;
+  wins: number;
+  losses: number;
+  draws: number;
+  ongoing: number;
+}
+
+export interface UserProfile {
+  username: string;
+  created_at: string;
+  games: GameCounts;
+  ratings: Rating[];
+}
+
+export interface RatingPoint {
+  game_id: string;
+  rating: number;
+  deviation: number;
+  recorded_at: string;
+}
+
+export interface RatingHistory {
+  username: string;
+  /** One series per variant and time control, points oldest first. */
+  categories: {
+    variant: Variant;
+    time_control: TimeControl;
+    points: RatingPoint[];
+  }[];
+}
+
+export interface LeaderboardEntry {
+  rank: number;
+  username: string;
+  rating: number;
+  deviation: number;
+  games: number;
+  last_played_at: string;
+}
+
+export interface Leaderboard {
+  variant: Variant;
+  time_control: TimeControl;
+  players: LeaderboardEntry[];
+}
+
+export interface UserGamesQuery {
+  result?: "win" | "loss" | "draw";
+  color?: "white" | "black";
+  variant?: Variant;
+  time_control?: TimeControl;
+  limit?: number;
+  offset?: number;
+}
+
+export interface User {
+  id: string;
+  username: string;
+  created_at: string;
+}
+
+export interface Session {
+  user: User;
+  /** Also set as an HttpOnly cookie; send as `Authorization: Bearer` from non-browser clients. */
+  token: string;
+  expires_at: string;
+}
+
+export interface MoveResult extends Game {
+  request_id?: string;
+  ply: number;
+  move: string;
+  san: string;
+  duplicate: boolean;
 }
 
 export interface GameWithSecret extends Game {
   secret: string;
   color: "white" | "black";
+  /** Handed to the creator of a private game. */
+  invite?: Invite;
 }
 
-export type ServerMessage =
+/** Broadcast game events carry `seq`; replies addressed to one socket do not. */
+export type ServerMessage = { seq?: number } & (
   | {
       type: "game_state";
       id: string;
@@ -48,20 +209,271 @@ export type ServerMessage =
       status: string;
       result: string | null;
     }
+  | {
+      type: "draw_offered";
+      by: "white" | "black";
+    }
+  | {
+      type: "draw_declined";
+      by: "white" | "black";
+    }
+  | {
+      type: "rematch_offered";
+      by: "white" | "black";
+    }
+  | {
+      type: "rematch_declined";
+      by: "white" | "black";
+    }
+  | {
+      type: "rematch_started";
+      game_id: string;
+    }
+  | {
+      /** Sent to each player of the rematch with their new seat. */
+      type: "rematch_seat";
+      game_id: string;
+      color: "white" | "black";
+      secret: string;
+    }
+  | {
+      type: "move_accepted";
+      request_id?: string;
+      ply: number;
+      move: string;
+      san: string;
+      duplicate: boolean;
+    }
+  | {
+      type: "ack";
+      request_id?: string;
+    }
+  | {
+      /** The server is going down; reconnect after the hinted delay. */
+      type: "server_shutdown";
+      reconnect_after_ms: number;
+    }
   | {
       type: "error";
       message: string;
-    };
+      code: ErrorCode;
+      details?: Record<string, unknown>;
+      request_id?: string;
+    }
+);
+
+/** Stable error codes sent with REST and WebSocket errors. */
+export type ErrorCode =
+  | "not_found"
+  | "unauthorized"
+  | "unavailable"
+  | "internal"
+  | "invalid_request"
+  | "game_not_found"
+  | "game_not_active"
+  | "invalid_secret"
+  | "not_your_turn"
+  | "invalid_move"
+  | "illegal_move"
+  | "invalid_fen"
+  | "seat_taken"
+  | "ply_mismatch"
+  | "stale_game"
+  | "draw_already_offered"
+  | "no_draw_offer"
+  | "shutting_down"
+  | "username_taken"
+  | "invalid_credentials"
+  | "invalid_session"
+  | "not_a_player"
+  | "user_not_found"
+  | "seek_not_found"
+  | "rating_out_of_range"
+  | "challenge_not_found"
+  | "rematch_already_offered"
+  | "no_rematch_offer"
+  | "invalid_invite"
+  | "seat_reserved";
+
+export interface ClientAuthenticate {
+  type: "authenticate";
+  secret: string;
+  request_id?: string;
+}
 
 export interface ClientMakeMove {
   type: "make_move";
   move: string;
-  secret: string;
+  request_id?: string;
+  /** The ply this move is meant to become (1 for White's first move). */
+  ply?: number;
 }
 
 export interface ClientResign {
   type: "resign";
-  secret: string;
+  request_id?: string;
+}
+
+export interface ClientDrawAction {
+  type: "offer_draw" | "accept_draw" | "decline_draw";
+  request_id?: string;
+}
+
+export interface ClientRematchAction {
+  type: "offer_rematch" | "accept_rematch" | "decline_rematch";
+  request_id?: string;
+}
+
+export type ClientMessage =
+  | ClientAuthenticate
+  | ClientMakeMove
+  | ClientResign
+  | ClientDrawAction
+  | ClientRematchAction;
+
+export type SeekColor = "white" | "black" | "random";
+
+export interface RatingRange {
+  min: number;
+  max: number;
+}
+
+export interface Seek {
+  id: string;
+  /** Absent for guests. */
+  username: string | null;
+  rating: number | null;
+  variant: Variant;
+  time_control: TimeControl;
+  clock: Clock | null;
+  rated: boolean;
+  color: SeekColor;
+  rating_range?: RatingRange;
+  created_at: string;
 }
 
-export type ClientMessage = ClientMakeMove | ClientResign;
+export interface Challenge {
+  id: string;
+  challenger: string;
+  recipient: string;
+  variant: Variant;
+  time_control: TimeControl;
+  clock: Clock | null;
+  rated: boolean;
+  /** The side the challenger gets. */
+  color: SeekColor;
+  fen?: string;
+  created_at: string;
+  expires_at: string;
+}
+
+export type DeclineReason =
+  | "generic"
+  | "later"
+  | "too_fast"
+  | "too_slow"
+  | "rated"
+  | "casual"
+  | "variant"
+  | "position";
+
+/** Messages on `/ws/lobby`. */
+export type LobbyServerMessage =
+  | {
+      /** Every open seek; sent on connect and after falling behind. */
+      type: "seeks";
+      seeks: Seek[];
+    }
+  | {
+      type: "seek_created";
+      seek: Seek;
+    }
+  | {
+      type: "seek_removed";
+      seek_id: string;
+      reason: "accepted" | "cancelled";
+    }
+  | {
+      /** Open challenges sent or received; on connect, signed-in only. */
+      type: "challenges";
+      challenges: Challenge[];
+    }
+  | {
+      type: "challenge_created";
+      challenge: Challenge;
+    }
+  | {
+      type: "challenge_removed";
+      challenge_id: string;
+      reason: "accepted" | "declined" | "cancelled" | "expired";
+      decline_reason?: DeclineReason;
+    }
+  | {
+      /** Sent only to the two players of a game from a seek, the queue or a challenge. */
+      type: "game_started";
+      game: GameWithSecret;
+    }
+  | {
+      type: "ack";
+      request_id?: string;
+      /** The seek a `create_seek` opened. */
+      seek_id?: string;
+      /** The challenge a `create_challenge` sent. */
+      challenge_id?: string;
+    }
+  | {
+      type: "server_shutdown";
+      reconnect_after_ms: number;
+    }
+  | {
+      type: "error";
+      message: string;
+      code: ErrorCode;
+      details?: Record<string, unknown>;
+      request_id?: string;
+    };
+
+export type LobbyClientMessage =
+  | {
+      type: "create_seek";
+      variant?: Variant;
+      clock?: Clock | null;
+      rated?: boolean;
+      color?: SeekColor;
+      rating_range?: RatingRange | null;
+      request_id?: string;
+    }
+  | {
+      type: "cancel_seek" | "accept_seek";
+      seek_id: string;
+      request_id?: string;
+    }
+  | {
+      /** The game arrives as `game_started` once an opponent is found. */
+      type: "join_queue";
+      time_control: TimeControl;
+      variant?: Variant;
+      rated?: boolean;
+      request_id?: string;
+    }
+  | {
+      type: "leave_queue";
+      request_id?: string;
+    }
+  | ({
+      type: "create_challenge";
+      username: string;
+      color?: SeekColor;
+      request_id?: string;
+    } & GameSettings)
+  | {
+      type: "cancel_challenge" | "accept_challenge";
+      challenge_id: string;
+      request_id?: string;
+    }
+  | {
+      type: "decline_challenge";
+      challenge_id: string;
+      reason?: DeclineReason;
+      request_id?: string;
+    };
@@ -3,14 +3,18 @@ import type { ServerMessage, ClientMessage } from "@/api/types";
 
 interface UseGameWebSocketOptions {
   gameId: string;
+  /** Seat secret; when set, the socket is bound to the seat so it can play and receive private messages. */
+  secret?: string;
   onMessage: (msg: ServerMessage) => void;
 }
 
 export function useGameWebSocket({
   gameId,
+  secret,
   onMessage,
 }: UseGameWebSocketOptions) {
   const wsRef = useRef<WebSocket | null>(null);
+  const lastSeqRef = useRef<number | null>(null);
   const onMessageRef = useRef(onMessage);
   const [connected, setConnected] = useState(false);
   onMessageRef.current = onMessage;
@@ -19,13 +23,25 @@ export function useGameWebSocket({
     let reconnectTimeout: ReturnType<typeof setTimeout>;
     let attempts = 0;
     let disposed = false;
+    // Delay suggested by the server when it shuts down
+    let reconnectHint: number | null = null;
+    lastSeqRef.current = null;
 
     function connect() {
       if (disposed) return;
 
       const protocol = window.location.protocol === "https:" ? "wss:" : "ws:";
+      const params = new URLSearchParams();
+      // Resume from the last seen event so the server replays what we missed
+      if (lastSeqRef.current !== null) {
+        params.set("since", String(lastSeqRef.current));
+      }
+      if (secret) {
+        params.set("token", secret);
+      }
+      const query = params.toString();
       const ws = new WebSocket(
-        `${protocol}//${window.location.host}/ws/games/${gameId}`,
+        `${protocol}//${window.location.host}/ws/games/${gameId}${query ? `?${query}` : ""}`,
       );
       wsRef.current = ws;
 
@@ -37,6 +53,19 @@ export function useGameWebSocket({
       ws.onmessage = (event) => {
         try {
           const msg: ServerMessage = JSON.parse(event.data);
+          if (msg.type === "server_shutdown") {
+            reconnectHint = msg.reconnect_after_ms;
+          }
+          if (msg.seq !== undefined) {
+            if (
+              msg.type !== "game_state" &&
+              lastSeqRef.current !== null &&
+              msg.seq <= lastSeqRef.current
+            ) {
+              return;
+            }
+            lastSeqRef.current = msg.seq;
+          }
           onMessageRef.current(msg);
         } catch {
           // ignore malformed messages
@@ -46,7 +75,9 @@ export function useGameWebSocket({
       ws.onclose = () => {
         setConnected(false);
         if (!disposed) {
-          const delay = Math.min(1000 * 2 ** attempts, 10000);
+          const delay =
+            reconnectHint ?? Math.min(1000 * 2 ** attempts, 10000);
+          reconnectHint = null;
           attempts++;
           reconnectTimeout = setTimeout(connect, delay);
         }
@@ -64,7 +95,7 @@ export function useGameWebSocket({
       clearTimeout(reconnectTimeout);
       wsRef.current?.close();
     };
-  }, [gameId]);
+  }, [gameId, secret]);
 
   const sendMessage = useCallback((msg: ClientMessage) => {
     if (wsRef.current?.readyState === WebSocket.OPEN) {
@@ -91,6 +91,7 @@ function GamePage() {
 
   const { sendMessage, connected } = useGameWebSocket({
     gameId,
+    secret: playerInfo?.secret,
     onMessage,
   });
 
@@ -141,19 +142,17 @@ function GamePage() {
       sendMessage({
         type: "make_move",
         move: uciMove,
-        secret: playerInfo.secret,
+        request_id: crypto.randomUUID(),
+        ply: moves.length + 1,
       });
     },
-    [playerInfo, sendMessage, serverFen],
+    [playerInfo, sendMessage, serverFen, moves.length],
   );
 
   const handleResign = useCallback(() => {
     if (!playerInfo) return;
     if (!window.confirm("Are you sure you want to resign?")) return;
-    sendMessage({
-      type: "resign",
-      secret: playerInfo.secret,
-    });
+    sendMessage({ type: "resign" });
   }, [playerInfo, sendMessage]);
 
   return (